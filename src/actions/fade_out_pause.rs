@@ -0,0 +1,64 @@
+//! Fades the volume out before pausing, then puts it back for when playback resumes.
+
+use std::time::Duration;
+
+use crate::player::Player;
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::handler::Connection;
+use crate::stream_deck::manifest::{ActionManifest, State, TitleAlignment};
+
+use super::{failed, ActionHandler, Key, RoomSettings, KEYPAD, ROOM_INSPECTOR};
+
+pub const FADE_OUT_DURATION: Duration = Duration::from_secs(5);
+
+pub struct FadeOutPause;
+
+impl<P: Player> ActionHandler<P> for FadeOutPause {
+    type Settings = RoomSettings;
+
+    fn manifest(&self) -> ActionManifest {
+        ActionManifest {
+            name: "Fade Out and Pause",
+            uuid: "sh.viora.controller-for-sonos.fade-out-pause",
+            icon: "imgs/actions/fade-out-pause/icon",
+            tooltip: "Fades the volume out, pauses, and restores the volume for next time",
+            property_inspector_path: ROOM_INSPECTOR,
+            controllers: KEYPAD,
+            disable_automatic_states: false,
+            states: &[State {
+                image: "imgs/actions/fade-out-pause/key",
+                title_alignment: TitleAlignment::Middle,
+            }],
+            encoder: None,
+        }
+    }
+
+    fn room(settings: &RoomSettings) -> Option<&str> {
+        settings.room()
+    }
+
+    async fn key_up(
+        &self,
+        key: &Key<'_, P>,
+        settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        let Some(zone) = key.zone(settings.room()) else {
+            return Ok(());
+        };
+        fade_out(key.connection, zone).await
+    }
+}
+
+/// Fades the zone out as a background task, so the volume is put back even if we stop waiting
+/// halfway through, like a handler that timed out. Shutdown cuts the fade short rather than
+/// leave the speaker quiet.
+pub async fn fade_out<P: Player>(connection: &Connection, zone: P) -> Result<(), StreamDeckError> {
+    let closing = connection.clone();
+    connection
+        .run_tracked(async move {
+            zone.fade_out_and_pause(FADE_OUT_DURATION, closing.closed())
+                .await
+        })
+        .await?
+        .map_err(failed)
+}
@@ -0,0 +1,216 @@
+//! Tells taps, double taps, long presses and holds apart from the key down and up events, for
+//! actions that do something different for each. The timing is up to each key's settings.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// A short press. Actions that don't care about gestures only get these, as key ups.
+    Tap,
+    /// Two taps in quick succession. Binding this delays taps until we know it wasn't one.
+    DoubleTap,
+    /// Held down for a while, fires once while the key is still down.
+    LongPress,
+    /// Held down for a while, fires again and again until the key is let go.
+    Hold,
+}
+
+/// How long things take, from the key's settings, in milliseconds.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Timing {
+    long_press_ms: u64,
+    double_tap_ms: u64,
+    repeat_ms: u64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            long_press_ms: 500,
+            double_tap_ms: 300,
+            repeat_ms: 250,
+        }
+    }
+}
+
+impl Timing {
+    pub fn from_settings(settings: &Value) -> Self {
+        let timing: Timing = serde_json::from_value(settings.clone()).unwrap_or_default();
+        // zero would mean a hold that repeats as fast as the speaker can take it
+        Self {
+            repeat_ms: timing.repeat_ms.max(50),
+            ..timing
+        }
+    }
+
+    /// How long a key has to be down to count as a long press or hold.
+    pub fn long_press(&self) -> Duration {
+        Duration::from_millis(self.long_press_ms)
+    }
+
+    /// How long we wait after a tap for another one.
+    pub fn double_tap(&self) -> Duration {
+        Duration::from_millis(self.double_tap_ms)
+    }
+
+    /// How often a hold repeats.
+    pub fn repeat(&self) -> Duration {
+        Duration::from_millis(self.repeat_ms)
+    }
+}
+
+/// What letting go of a key turned out to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Release {
+    Tap,
+    DoubleTap,
+    /// Could still become a double tap. It's a tap if [`Detector::take_tap`] says so after the
+    /// double tap window.
+    MaybeTap(u64),
+    /// The end of a long press or hold, which already fired.
+    Held,
+}
+
+/// Keeps track of presses on each key, by context.
+#[derive(Debug, Default)]
+pub struct Detector {
+    keys: Mutex<HashMap<String, Presses>>,
+}
+
+#[derive(Debug, Default)]
+struct Presses {
+    count: u64,
+    /// The press the key is down for, if it is.
+    down: Option<u64>,
+    /// Whether the press that's down fired as a long press or hold.
+    held: bool,
+    /// A tap that's waiting to see if it becomes a double tap.
+    pending_tap: Option<u64>,
+}
+
+impl Detector {
+    /// Notes the key going down, and returns the press to check on with [`Self::hold`].
+    pub fn key_down(&self, context: &str) -> u64 {
+        let mut keys = self.keys.lock().unwrap();
+        let presses = keys.entry(context.to_string()).or_default();
+        presses.count += 1;
+        presses.down = Some(presses.count);
+        presses.held = false;
+        presses.count
+    }
+
+    /// Whether the press is still down, and marks it as held so letting go isn't a tap.
+    pub fn hold(&self, context: &str, press: u64) -> bool {
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get_mut(context) {
+            Some(presses) if presses.down == Some(press) => {
+                presses.held = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Notes the key going up. Taps wait for a second one if `double_tap` is bound.
+    pub fn key_up(&self, context: &str, double_tap: bool) -> Release {
+        let mut keys = self.keys.lock().unwrap();
+        let presses = keys.entry(context.to_string()).or_default();
+        // a key up without a key down, e.g. one that was dropped while the key was busy, is a tap
+        let press = match presses.down.take() {
+            Some(press) => press,
+            None => {
+                presses.count += 1;
+                presses.count
+            }
+        };
+
+        if std::mem::take(&mut presses.held) {
+            Release::Held
+        } else if !double_tap {
+            Release::Tap
+        } else if presses.pending_tap.take().is_some() {
+            Release::DoubleTap
+        } else {
+            presses.pending_tap = Some(press);
+            Release::MaybeTap(press)
+        }
+    }
+
+    /// Whether the tap is still waiting for a second one, and if so that it's a tap after all.
+    pub fn take_tap(&self, context: &str, press: u64) -> bool {
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get_mut(context) {
+            Some(presses) if presses.pending_tap == Some(press) => {
+                presses.pending_tap = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Forgets about a key that went away.
+    pub fn remove(&self, context: &str) {
+        self.keys.lock().unwrap().remove(context);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn tells_gestures_apart() {
+        let detector = Detector::default();
+
+        detector.key_down("a");
+        assert_eq!(detector.key_up("a", false), Release::Tap);
+
+        // a second tap inside the window makes it a double tap
+        detector.key_down("a");
+        let Release::MaybeTap(first) = detector.key_up("a", true) else {
+            panic!("should wait for a second tap");
+        };
+        detector.key_down("a");
+        assert_eq!(detector.key_up("a", true), Release::DoubleTap);
+        assert!(!detector.take_tap("a", first), "already a double tap");
+
+        // without one it's a tap when the window closes
+        detector.key_down("a");
+        let Release::MaybeTap(only) = detector.key_up("a", true) else {
+            panic!("should wait for a second tap");
+        };
+        assert!(detector.take_tap("a", only));
+
+        // a long press doesn't end in a tap, and the timer of an old press finds nothing
+        let press = detector.key_down("a");
+        assert!(detector.hold("a", press));
+        assert_eq!(detector.key_up("a", true), Release::Held);
+        assert!(!detector.hold("a", press));
+
+        // keys don't get in each other's way
+        let b = detector.key_down("b");
+        assert_eq!(detector.key_up("a", false), Release::Tap);
+        assert!(detector.hold("b", b));
+    }
+
+    #[test]
+    fn timing_comes_from_settings() {
+        let timing = Timing::from_settings(&json!({"room": "Kitchen", "longPressMs": 800}));
+        assert_eq!(timing.long_press(), Duration::from_millis(800));
+        assert_eq!(timing.double_tap(), Duration::from_millis(300));
+
+        let timing = Timing::from_settings(&json!({"repeatMs": 0}));
+        assert_eq!(timing.repeat(), Duration::from_millis(50));
+
+        let timing = Timing::from_settings(&json!({"longPressMs": "soon"}));
+        assert_eq!(timing.long_press(), Duration::from_millis(500));
+    }
+}
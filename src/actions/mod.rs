@@ -0,0 +1,478 @@
+//! Every action the plugin has, each in its own module with its own settings. The [`Registry`]
+//! routes events to them by UUID, and the manifest is generated from it with
+//! `cargo make manifest`, so adding an action means adding a module and listing it there.
+
+use std::future::Future;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::controller::SonosHandler;
+use crate::player::Player;
+use crate::render::Canvas;
+use crate::scheduler::{Poll, Snapshot};
+use crate::sonos::ControllerError;
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::handler::Connection;
+use crate::stream_deck::manifest::{ActionManifest, Controller, Manifest, Os, Software};
+use crate::stream_deck::ReceiveEvent;
+
+use self::gesture::Gesture;
+
+pub mod fade_out_pause;
+pub mod gesture;
+mod next_track;
+pub mod now_playing;
+pub mod play_pause;
+mod previous_track;
+pub mod restart_or_previous;
+mod room_selector;
+pub mod track_progress;
+
+/// The property inspector for keys that can be set to a room of their own.
+const ROOM_INSPECTOR: Option<&str> = Some("pi/room.html");
+const KEYPAD: &[Controller] = &[Controller::Keypad];
+
+/// One of the action's keys, while handling an event for it.
+pub struct Key<'a, P> {
+    pub handler: &'a SonosHandler<P>,
+    pub connection: &'a Connection,
+    pub context: &'a str,
+}
+
+impl<P: Player> Key<'_, P> {
+    /// The zone for the given room, or for the active room if `room` is `None`.
+    pub fn zone(&self, room: Option<&str>) -> Option<P> {
+        let zone = self.handler.zone(room);
+        if zone.is_none() {
+            warn!("no zone detected");
+        }
+        zone
+    }
+
+    /// Draws the key's image, see [`crate::render`].
+    pub async fn show_image(&self, canvas: &Canvas) -> Result<(), StreamDeckError> {
+        self.handler
+            .show_image(self.connection, self.context, canvas)
+            .await
+    }
+
+    /// Goes back to the key's image from the manifest.
+    pub async fn clear_image(&self) -> Result<(), StreamDeckError> {
+        self.handler
+            .clear_image(self.connection, self.context)
+            .await
+    }
+
+    /// Sets the key's title, scrolling it if it doesn't fit.
+    pub async fn show_title(&self, title: &str) -> Result<(), StreamDeckError> {
+        self.handler
+            .show_title(self.connection, self.context, title)
+            .await
+    }
+}
+
+/// What an action does with the events for its keys. Every hook does nothing by default,
+/// except that keys are brought up to date when they appear or their settings change.
+pub trait ActionHandler<P: Player>: Send + Sync + 'static {
+    /// The key's settings from the property inspector. Missing or malformed settings are
+    /// replaced by the default.
+    type Settings: DeserializeOwned + Default + Send + Sync;
+
+    fn manifest(&self) -> ActionManifest;
+
+    /// The room the key controls, or `None` if it follows the active room.
+    fn room(_settings: &Self::Settings) -> Option<&str> {
+        None
+    }
+
+    fn key_down(
+        &self,
+        _key: &Key<'_, P>,
+        _settings: &Self::Settings,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        async { Ok(()) }
+    }
+
+    /// A tap, i.e. the key going up after a short press.
+    fn key_up(
+        &self,
+        _key: &Key<'_, P>,
+        _settings: &Self::Settings,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        async { Ok(()) }
+    }
+
+    /// The gestures other than a tap that the action has hooks for. Taps are held back for a
+    /// moment when double taps are bound, so only list what's implemented.
+    fn gestures(&self) -> &'static [Gesture] {
+        &[]
+    }
+
+    fn double_tap(
+        &self,
+        _key: &Key<'_, P>,
+        _settings: &Self::Settings,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        async { Ok(()) }
+    }
+
+    fn long_press(
+        &self,
+        _key: &Key<'_, P>,
+        _settings: &Self::Settings,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Called over and over while the key is held down.
+    fn hold(
+        &self,
+        _key: &Key<'_, P>,
+        _settings: &Self::Settings,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        async { Ok(()) }
+    }
+
+    fn will_appear(
+        &self,
+        key: &Key<'_, P>,
+        settings: &Self::Settings,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        self.refresh(key, settings)
+    }
+
+    fn will_disappear(
+        &self,
+        _key: &Key<'_, P>,
+        _settings: &Self::Settings,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        async { Ok(()) }
+    }
+
+    /// The dial was turned by `ticks`, positive clockwise, while pushed in if `pressed`.
+    fn dial_rotate(
+        &self,
+        _key: &Key<'_, P>,
+        _settings: &Self::Settings,
+        _ticks: i32,
+        _pressed: bool,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        async { Ok(()) }
+    }
+
+    fn settings_changed(
+        &self,
+        key: &Key<'_, P>,
+        settings: &Self::Settings,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        self.refresh(key, settings)
+    }
+
+    /// A message from the key's property inspector.
+    fn property_inspector_message(
+        &self,
+        _key: &Key<'_, P>,
+        _payload: &Value,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Brings the key up to date, e.g. when zones came and went or the active room changed.
+    fn refresh(
+        &self,
+        _key: &Key<'_, P>,
+        _settings: &Self::Settings,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        async { Ok(()) }
+    }
+
+    /// What the key's zone should be polled for while it's on screen, see [`Self::update`].
+    fn polls(&self) -> &'static [Poll] {
+        &[]
+    }
+
+    /// Shows what polling the key's zone turned up. Only has what was due, which may be less
+    /// than [`Self::polls`] asked for.
+    fn update(
+        &self,
+        _key: &Key<'_, P>,
+        _settings: &Self::Settings,
+        _snapshot: &Snapshot,
+    ) -> impl Future<Output = Result<(), StreamDeckError>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// An [`ActionHandler`] with its settings type erased, so the registry can hold any of them.
+pub trait AnyAction<P>: Send + Sync {
+    fn manifest(&self) -> ActionManifest;
+
+    fn room(&self, settings: &Value) -> Option<String>;
+
+    fn gestures(&self) -> &'static [Gesture];
+
+    fn handle<'a>(
+        &'a self,
+        key: Key<'a, P>,
+        event: &'a ReceiveEvent<String>,
+    ) -> BoxFuture<'a, Result<(), StreamDeckError>>;
+
+    fn gesture<'a>(
+        &'a self,
+        key: Key<'a, P>,
+        settings: &'a Value,
+        gesture: Gesture,
+    ) -> BoxFuture<'a, Result<(), StreamDeckError>>;
+
+    fn refresh<'a>(
+        &'a self,
+        key: Key<'a, P>,
+        settings: &'a Value,
+    ) -> BoxFuture<'a, Result<(), StreamDeckError>>;
+
+    fn polls(&self) -> &'static [Poll];
+
+    fn update<'a>(
+        &'a self,
+        key: Key<'a, P>,
+        settings: &'a Value,
+        snapshot: &'a Snapshot,
+    ) -> BoxFuture<'a, Result<(), StreamDeckError>>;
+}
+
+impl<P: Player, A: ActionHandler<P>> AnyAction<P> for A {
+    fn manifest(&self) -> ActionManifest {
+        ActionHandler::manifest(self)
+    }
+
+    fn room(&self, settings: &Value) -> Option<String> {
+        A::room(&parse_settings(settings)).map(str::to_string)
+    }
+
+    fn gestures(&self) -> &'static [Gesture] {
+        ActionHandler::gestures(self)
+    }
+
+    fn handle<'a>(
+        &'a self,
+        key: Key<'a, P>,
+        event: &'a ReceiveEvent<String>,
+    ) -> BoxFuture<'a, Result<(), StreamDeckError>> {
+        async move {
+            match event {
+                ReceiveEvent::KeyDown { payload, .. } => {
+                    self.key_down(&key, &parse_settings(&payload.settings))
+                        .await
+                }
+                ReceiveEvent::KeyUp { payload, .. } => {
+                    self.key_up(&key, &parse_settings(&payload.settings)).await
+                }
+                ReceiveEvent::WillAppear { payload, .. } => {
+                    self.will_appear(&key, &parse_settings(&payload.settings))
+                        .await
+                }
+                ReceiveEvent::WillDisappear { payload, .. } => {
+                    self.will_disappear(&key, &parse_settings(&payload.settings))
+                        .await
+                }
+                ReceiveEvent::DialRotate { payload, .. } => {
+                    let settings = parse_settings(&payload.settings);
+                    self.dial_rotate(&key, &settings, payload.ticks, payload.pressed)
+                        .await
+                }
+                ReceiveEvent::DidReceiveSettings { payload, .. } => {
+                    self.settings_changed(&key, &parse_settings(&payload["settings"]))
+                        .await
+                }
+                ReceiveEvent::SendToPlugin { payload, .. } => {
+                    self.property_inspector_message(&key, payload).await
+                }
+                _ => Ok(()),
+            }
+        }
+        .boxed()
+    }
+
+    fn gesture<'a>(
+        &'a self,
+        key: Key<'a, P>,
+        settings: &'a Value,
+        gesture: Gesture,
+    ) -> BoxFuture<'a, Result<(), StreamDeckError>> {
+        async move {
+            let settings = parse_settings(settings);
+            match gesture {
+                Gesture::Tap => self.key_up(&key, &settings).await,
+                Gesture::DoubleTap => self.double_tap(&key, &settings).await,
+                Gesture::LongPress => self.long_press(&key, &settings).await,
+                Gesture::Hold => self.hold(&key, &settings).await,
+            }
+        }
+        .boxed()
+    }
+
+    fn refresh<'a>(
+        &'a self,
+        key: Key<'a, P>,
+        settings: &'a Value,
+    ) -> BoxFuture<'a, Result<(), StreamDeckError>> {
+        async move { ActionHandler::refresh(self, &key, &parse_settings(settings)).await }.boxed()
+    }
+
+    fn polls(&self) -> &'static [Poll] {
+        ActionHandler::polls(self)
+    }
+
+    fn update<'a>(
+        &'a self,
+        key: Key<'a, P>,
+        settings: &'a Value,
+        snapshot: &'a Snapshot,
+    ) -> BoxFuture<'a, Result<(), StreamDeckError>> {
+        async move { ActionHandler::update(self, &key, &parse_settings(settings), snapshot).await }
+            .boxed()
+    }
+}
+
+fn parse_settings<S: DeserializeOwned + Default>(settings: &Value) -> S {
+    serde_json::from_value(settings.clone()).unwrap_or_else(|e| {
+        debug!("using default settings instead of {settings}: {e}");
+        S::default()
+    })
+}
+
+/// Keeps a failed speaker command from being mistaken for a problem with the Stream Deck.
+fn failed(e: ControllerError) -> StreamDeckError {
+    StreamDeckError::HandlerFailed(e.to_string())
+}
+
+/// Settings for keys that control a room, the active one unless they're set to their own.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct RoomSettings {
+    #[serde(default)]
+    room: String,
+}
+
+impl RoomSettings {
+    pub fn room(&self) -> Option<&str> {
+        let room = self.room.trim();
+        (!room.is_empty()).then_some(room)
+    }
+}
+
+/// All the actions, by UUID.
+pub struct Registry<P> {
+    actions: Vec<Box<dyn AnyAction<P>>>,
+}
+
+impl<P: Player> Registry<P> {
+    pub fn new() -> Self {
+        Self {
+            // in the order they're listed in the app
+            actions: vec![
+                Box::new(play_pause::PlayPause),
+                Box::new(fade_out_pause::FadeOutPause),
+                Box::new(next_track::NextTrack),
+                Box::new(previous_track::PreviousTrack),
+                Box::new(restart_or_previous::RestartOrPrevious),
+                Box::new(room_selector::RoomSelector),
+                Box::new(track_progress::TrackProgress::default()),
+                Box::new(now_playing::NowPlaying),
+            ],
+        }
+    }
+
+    pub fn get(&self, uuid: &str) -> Option<&dyn AnyAction<P>> {
+        self.actions
+            .iter()
+            .find(|action| action.manifest().uuid == uuid)
+            .map(Box::as_ref)
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        Manifest {
+            name: "Controller for Sonos",
+            version: env!("CARGO_PKG_VERSION"),
+            author: "viora",
+            actions: self
+                .actions
+                .iter()
+                .map(|action| action.manifest())
+                .collect(),
+            category: "Controller for Sonos",
+            category_icon: "imgs/plugin/category-icon",
+            code_path: "bin/sonos-controller",
+            description: "Control your Sonos setup from the Stream Deck",
+            icon: "imgs/plugin/marketplace",
+            sdk_version: 2,
+            software: Software {
+                minimum_version: "6.5",
+            },
+            os: &[Os {
+                platform: "mac",
+                minimum_version: "10.14",
+            }],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use super::*;
+    use crate::player::mock::MockPlayer;
+
+    const PLUGIN_DIR: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/sh.viora.controller-for-sonos.sdPlugin"
+    );
+
+    fn manifest() -> Manifest {
+        Registry::<MockPlayer>::new().manifest()
+    }
+
+    #[test]
+    fn manifest_is_up_to_date() {
+        let path = Path::new(PLUGIN_DIR).join("manifest.json");
+        let checked_in: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let generated = serde_json::to_value(manifest()).unwrap();
+        assert!(
+            checked_in == generated,
+            "manifest.json is out of date, run `cargo make manifest`"
+        );
+    }
+
+    #[test]
+    fn images_exist() {
+        let manifest = manifest();
+        let images = manifest.actions.iter().flat_map(|action| {
+            std::iter::once(action.icon).chain(action.states.iter().map(|state| state.image))
+        });
+        for image in images.chain([manifest.icon, manifest.category_icon]) {
+            for suffix in [".png", "@2x.png"] {
+                let path = Path::new(PLUGIN_DIR).join(format!("{image}{suffix}"));
+                assert!(path.exists(), "missing {}", path.display());
+            }
+        }
+    }
+
+    #[test]
+    fn routes_by_uuid() {
+        let registry = Registry::<MockPlayer>::new();
+        let mut uuids = HashSet::new();
+        for action in manifest().actions {
+            assert!(uuids.insert(action.uuid), "{} is listed twice", action.uuid);
+            let routed = registry.get(action.uuid).unwrap();
+            assert_eq!(routed.manifest().name, action.name);
+        }
+        assert!(registry.get("sh.viora.counter").is_none());
+    }
+}
@@ -0,0 +1,54 @@
+//! Skips to the next track, and keeps skipping while the key is held.
+
+use crate::player::Player;
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::manifest::{ActionManifest, State, TitleAlignment};
+
+use super::gesture::Gesture;
+use super::{failed, ActionHandler, Key, RoomSettings, KEYPAD, ROOM_INSPECTOR};
+
+pub struct NextTrack;
+
+impl<P: Player> ActionHandler<P> for NextTrack {
+    type Settings = RoomSettings;
+
+    fn manifest(&self) -> ActionManifest {
+        ActionManifest {
+            name: "Next Track",
+            uuid: "sh.viora.controller-for-sonos.next-track",
+            icon: "imgs/actions/next-track/icon",
+            tooltip: "Skips to the next track, hold to keep skipping",
+            property_inspector_path: ROOM_INSPECTOR,
+            controllers: KEYPAD,
+            disable_automatic_states: false,
+            states: &[State {
+                image: "imgs/actions/next-track/key",
+                title_alignment: TitleAlignment::Middle,
+            }],
+            encoder: None,
+        }
+    }
+
+    fn room(settings: &RoomSettings) -> Option<&str> {
+        settings.room()
+    }
+
+    async fn key_up(
+        &self,
+        key: &Key<'_, P>,
+        settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        let Some(zone) = key.zone(settings.room()) else {
+            return Ok(());
+        };
+        zone.next().await.map_err(failed)
+    }
+
+    fn gestures(&self) -> &'static [Gesture] {
+        &[Gesture::Hold]
+    }
+
+    async fn hold(&self, key: &Key<'_, P>, settings: &RoomSettings) -> Result<(), StreamDeckError> {
+        ActionHandler::<P>::key_up(self, key, settings).await
+    }
+}
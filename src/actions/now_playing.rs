@@ -0,0 +1,82 @@
+//! Shows the title and artist of what the room plays, scrolling them when they're too long for
+//! the key.
+
+use crate::player::Player;
+use crate::scheduler::{Poll, Snapshot};
+use crate::sonos::PositionInfo;
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::manifest::{ActionManifest, State, TitleAlignment};
+
+use super::{failed, ActionHandler, Key, RoomSettings, KEYPAD, ROOM_INSPECTOR};
+
+pub const UUID: &str = "sh.viora.controller-for-sonos.now-playing";
+
+pub struct NowPlaying;
+
+/// The title on one line and the artist on the next, as far as they're known.
+fn title(position: &PositionInfo) -> String {
+    [&position.title, &position.artist]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl<P: Player> ActionHandler<P> for NowPlaying {
+    type Settings = RoomSettings;
+
+    fn manifest(&self) -> ActionManifest {
+        ActionManifest {
+            name: "Now Playing",
+            uuid: UUID,
+            icon: "imgs/actions/now-playing/icon",
+            tooltip: "Shows the title and artist of what the room plays",
+            property_inspector_path: ROOM_INSPECTOR,
+            controllers: KEYPAD,
+            disable_automatic_states: false,
+            states: &[State {
+                image: "imgs/actions/now-playing/key",
+                title_alignment: TitleAlignment::Bottom,
+            }],
+            encoder: None,
+        }
+    }
+
+    fn room(settings: &RoomSettings) -> Option<&str> {
+        settings.room()
+    }
+
+    // for players that can't tell us the track changed
+    fn polls(&self) -> &'static [Poll] {
+        &[Poll::Position]
+    }
+
+    async fn update(
+        &self,
+        key: &Key<'_, P>,
+        _settings: &RoomSettings,
+        snapshot: &Snapshot,
+    ) -> Result<(), StreamDeckError> {
+        match &snapshot.position {
+            Some(position) => key.show_title(&title(position)).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn refresh(
+        &self,
+        key: &Key<'_, P>,
+        settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        let Some(zone) = key.handler.zone(settings.room()) else {
+            return key.show_title("").await;
+        };
+
+        if zone.capabilities().events {
+            key.handler.watch_transport(key.connection, &zone);
+        }
+        let position = zone.get_position().await.map_err(failed)?;
+        key.show_title(&title(&position)).await
+    }
+}
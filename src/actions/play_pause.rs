@@ -0,0 +1,158 @@
+//! Toggles playback, and shows whether the room is playing, including changes made elsewhere.
+//! A long press pauses every room, e.g. when leaving the house.
+
+use log::warn;
+
+use crate::player::Player;
+use crate::render::{icons, Canvas, Color, Layer};
+use crate::scheduler::{Poll, Snapshot};
+use crate::sonos::AVTransportState;
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::manifest::{ActionManifest, State, TitleAlignment};
+
+use super::gesture::Gesture;
+use super::{failed, ActionHandler, Key, RoomSettings, KEYPAD, ROOM_INSPECTOR};
+
+pub const UUID: &str = "sh.viora.controller-for-sonos.play-pause";
+
+pub struct PlayPause;
+
+impl<P: Player> ActionHandler<P> for PlayPause {
+    type Settings = RoomSettings;
+
+    fn manifest(&self) -> ActionManifest {
+        ActionManifest {
+            name: "Play / Pause",
+            uuid: UUID,
+            icon: "imgs/actions/play-pause/icon",
+            tooltip: "Toggles playback, long press to pause every room",
+            property_inspector_path: ROOM_INSPECTOR,
+            controllers: KEYPAD,
+            // we show the real transport state, see `show_state`
+            disable_automatic_states: true,
+            states: &[
+                State {
+                    image: "imgs/actions/play-pause/play",
+                    title_alignment: TitleAlignment::Middle,
+                },
+                State {
+                    image: "imgs/actions/play-pause/pause",
+                    title_alignment: TitleAlignment::Middle,
+                },
+            ],
+            encoder: None,
+        }
+    }
+
+    fn room(settings: &RoomSettings) -> Option<&str> {
+        settings.room()
+    }
+
+    async fn key_up(
+        &self,
+        key: &Key<'_, P>,
+        settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        let Some(zone) = key.zone(settings.room()) else {
+            return Ok(());
+        };
+        let result = zone.play_pause().await.map_err(failed);
+        // show whatever state it ended up in, even if toggling failed
+        ActionHandler::<P>::refresh(self, key, settings).await?;
+        result
+    }
+
+    fn gestures(&self) -> &'static [Gesture] {
+        &[Gesture::LongPress]
+    }
+
+    async fn long_press(
+        &self,
+        key: &Key<'_, P>,
+        settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        let result = pause_all(&key.handler.zones()).await;
+        ActionHandler::<P>::refresh(self, key, settings).await?;
+        result
+    }
+
+    // players that can't tell us about changes themselves are polled instead
+    fn polls(&self) -> &'static [Poll] {
+        &[Poll::Transport]
+    }
+
+    async fn update(
+        &self,
+        key: &Key<'_, P>,
+        _settings: &RoomSettings,
+        snapshot: &Snapshot,
+    ) -> Result<(), StreamDeckError> {
+        match snapshot.transport {
+            Some(state) => show_state(key, state).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn refresh(
+        &self,
+        key: &Key<'_, P>,
+        settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        let Some(zone) = key.handler.zone(settings.room()) else {
+            return Ok(());
+        };
+
+        if zone.capabilities().events {
+            key.handler.watch_transport(key.connection, &zone);
+        }
+        let state = zone.get_state().await.map_err(failed)?;
+        show_state(key, state).await
+    }
+}
+
+/// Pauses every room that's playing.
+pub async fn pause_all<P: Player>(zones: &[P]) -> Result<(), StreamDeckError> {
+    let mut result = Ok(());
+    for zone in zones {
+        let paused = match zone.get_state().await {
+            Ok(AVTransportState::Playing | AVTransportState::Transitioning) => zone.pause().await,
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        // keep going, one unreachable room shouldn't keep the others playing
+        if let Err(e) = paused {
+            warn!("could not pause {}: {e}", zone.name());
+            result = Err(failed(e));
+        }
+    }
+    result
+}
+
+/// Shows the transport state on a key. Keys only have the play and pause states, so while the
+/// speaker buffers the pause state gets a dimmed icon instead of its own image.
+pub async fn show_state<P: Player>(
+    key: &Key<'_, P>,
+    state: AVTransportState,
+) -> Result<(), StreamDeckError> {
+    let key_state = match state {
+        AVTransportState::Stopped | AVTransportState::Paused => 0,
+        AVTransportState::Playing | AVTransportState::Transitioning => 1,
+    };
+    key.connection.set_state(key.context, key_state).await?;
+    if state == AVTransportState::Transitioning {
+        key.show_image(&buffering()).await
+    } else {
+        key.clear_image().await
+    }
+}
+
+fn buffering() -> Canvas {
+    Canvas::new()
+        .with(Layer::Fill(Color::BACKGROUND))
+        .with(Layer::Icon {
+            svg: icons::PAUSE,
+            size: 48.0,
+            y: 36.0,
+            color: Color::GRAY,
+        })
+}
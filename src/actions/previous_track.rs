@@ -0,0 +1,45 @@
+//! Goes back to the previous track, wherever we are in the current one.
+
+use crate::player::Player;
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::manifest::{ActionManifest, State, TitleAlignment};
+
+use super::{failed, ActionHandler, Key, RoomSettings, KEYPAD, ROOM_INSPECTOR};
+
+pub struct PreviousTrack;
+
+impl<P: Player> ActionHandler<P> for PreviousTrack {
+    type Settings = RoomSettings;
+
+    fn manifest(&self) -> ActionManifest {
+        ActionManifest {
+            name: "Previous Track",
+            uuid: "sh.viora.controller-for-sonos.previous-track",
+            icon: "imgs/actions/previous-track/icon",
+            tooltip: "Goes back to the previous track",
+            property_inspector_path: ROOM_INSPECTOR,
+            controllers: KEYPAD,
+            disable_automatic_states: false,
+            states: &[State {
+                image: "imgs/actions/previous-track/key",
+                title_alignment: TitleAlignment::Middle,
+            }],
+            encoder: None,
+        }
+    }
+
+    fn room(settings: &RoomSettings) -> Option<&str> {
+        settings.room()
+    }
+
+    async fn key_up(
+        &self,
+        key: &Key<'_, P>,
+        settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        let Some(zone) = key.zone(settings.room()) else {
+            return Ok(());
+        };
+        zone.previous().await.map_err(failed)
+    }
+}
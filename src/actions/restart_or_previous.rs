@@ -0,0 +1,69 @@
+//! "Previous" the way CD players do it: back to the start of the track, unless we're already
+//! near the start. A double tap always goes back.
+
+use std::time::Duration;
+
+use crate::player::Player;
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::manifest::{ActionManifest, State, TitleAlignment};
+
+use super::gesture::Gesture;
+use super::{failed, ActionHandler, Key, RoomSettings, KEYPAD, ROOM_INSPECTOR};
+
+/// Past this point, "previous" restarts the current track instead.
+pub const RESTART_THRESHOLD: Duration = Duration::from_secs(3);
+
+pub struct RestartOrPrevious;
+
+impl<P: Player> ActionHandler<P> for RestartOrPrevious {
+    type Settings = RoomSettings;
+
+    fn manifest(&self) -> ActionManifest {
+        ActionManifest {
+            name: "Smart Previous",
+            uuid: "sh.viora.controller-for-sonos.restart-or-previous",
+            icon: "imgs/actions/restart-or-previous/icon",
+            tooltip: "Restarts the current track, or goes back to the previous one near its start or on a double tap",
+            property_inspector_path: ROOM_INSPECTOR,
+            controllers: KEYPAD,
+            disable_automatic_states: false,
+            states: &[State {
+                image: "imgs/actions/restart-or-previous/key",
+                title_alignment: TitleAlignment::Middle,
+            }],
+            encoder: None,
+        }
+    }
+
+    fn room(settings: &RoomSettings) -> Option<&str> {
+        settings.room()
+    }
+
+    async fn key_up(
+        &self,
+        key: &Key<'_, P>,
+        settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        let Some(zone) = key.zone(settings.room()) else {
+            return Ok(());
+        };
+        zone.restart_or_previous(RESTART_THRESHOLD)
+            .await
+            .map_err(failed)
+    }
+
+    fn gestures(&self) -> &'static [Gesture] {
+        &[Gesture::DoubleTap]
+    }
+
+    async fn double_tap(
+        &self,
+        key: &Key<'_, P>,
+        settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        let Some(zone) = key.zone(settings.room()) else {
+            return Ok(());
+        };
+        zone.previous().await.map_err(failed)
+    }
+}
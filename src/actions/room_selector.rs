@@ -0,0 +1,57 @@
+//! Cycles through the rooms to pick the active room, and shows which one it is.
+
+use serde::Deserialize;
+
+use crate::player::Player;
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::manifest::{ActionManifest, State, TitleAlignment};
+
+use super::{ActionHandler, Key, KEYPAD};
+
+pub struct RoomSelector;
+
+/// Nothing to set: the selector always works on the active room.
+#[derive(Debug, Default, Deserialize)]
+pub struct NoSettings {}
+
+impl<P: Player> ActionHandler<P> for RoomSelector {
+    type Settings = NoSettings;
+
+    fn manifest(&self) -> ActionManifest {
+        ActionManifest {
+            name: "Room Selector",
+            uuid: "sh.viora.controller-for-sonos.room-selector",
+            icon: "imgs/actions/room-selector/icon",
+            tooltip: "Switches the active room, used by every key without a room of its own",
+            property_inspector_path: None,
+            controllers: KEYPAD,
+            disable_automatic_states: false,
+            states: &[State {
+                image: "imgs/actions/room-selector/key",
+                // leaves room for the room name
+                title_alignment: TitleAlignment::Bottom,
+            }],
+            encoder: None,
+        }
+    }
+
+    async fn key_up(
+        &self,
+        key: &Key<'_, P>,
+        _settings: &NoSettings,
+    ) -> Result<(), StreamDeckError> {
+        key.handler.select_next_room(key.connection).await
+    }
+
+    async fn refresh(
+        &self,
+        key: &Key<'_, P>,
+        _settings: &NoSettings,
+    ) -> Result<(), StreamDeckError> {
+        let title = match key.handler.zone(None) {
+            Some(zone) => zone.name().to_string(),
+            None => "No rooms".to_string(),
+        };
+        key.show_title(&title).await
+    }
+}
@@ -0,0 +1,297 @@
+//! Shows how far into the track the room is, as a ring around the elapsed or remaining time.
+//! The position is only polled now and then and counted up locally in between, so the key
+//! moves every second without asking the speaker every second. A tap switches between elapsed
+//! and remaining time.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::error;
+use tokio::time::Instant;
+
+use crate::player::Player;
+use crate::render::{Canvas, Color, Layer};
+use crate::scheduler::{Poll, Snapshot};
+use crate::sonos::{AVTransportState, PositionInfo};
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::manifest::{ActionManifest, State, TitleAlignment};
+
+use super::{failed, ActionHandler, Key, RoomSettings, KEYPAD, ROOM_INSPECTOR};
+
+pub const UUID: &str = "sh.viora.controller-for-sonos.track-progress";
+
+/// How often keys are redrawn while the room plays.
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub struct TrackProgress {
+    /// What each key last heard about its room, by context. Shared with the keys' tickers.
+    keys: Arc<Mutex<HashMap<String, Progress>>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Progress {
+    /// The last position we were told, and when.
+    position: Option<(PositionInfo, Instant)>,
+    playing: bool,
+    /// Whether the key shows the time left instead of the time played.
+    remaining: bool,
+}
+
+impl Progress {
+    /// How far into the track the room is by now, counting up from the last position while
+    /// it plays.
+    fn elapsed(&self, now: Instant) -> Option<Duration> {
+        let (position, at) = self.position.as_ref()?;
+        let mut elapsed = position.elapsed?;
+        if self.playing {
+            elapsed += now.saturating_duration_since(*at);
+        }
+        Some(match position.duration {
+            Some(duration) => elapsed.min(duration),
+            None => elapsed,
+        })
+    }
+
+    fn canvas(&self, now: Instant) -> Canvas {
+        let elapsed = self.elapsed(now);
+        let duration = self
+            .position
+            .as_ref()
+            .and_then(|(position, _)| position.duration);
+        let fraction = match (elapsed, duration) {
+            (Some(elapsed), Some(duration)) if !duration.is_zero() => {
+                elapsed.as_secs_f32() / duration.as_secs_f32()
+            }
+            _ => 0.0,
+        };
+        // streams have no duration, so there's nothing left to count down
+        let time = match (elapsed, duration) {
+            (Some(elapsed), Some(duration)) if self.remaining => {
+                format!("-{}", format_time(duration.saturating_sub(elapsed)))
+            }
+            (Some(elapsed), _) => format_time(elapsed),
+            (None, _) => "--:--".to_string(),
+        };
+
+        Canvas::new()
+            .with(Layer::Fill(Color::BLACK))
+            .with(Layer::Ring {
+                value: fraction,
+                color: if self.playing {
+                    Color::ACCENT
+                } else {
+                    Color::WHITE
+                },
+            })
+            .with(Layer::Text {
+                text: time,
+                size: 15.0,
+                y: 41.0,
+                color: Color::WHITE,
+            })
+    }
+}
+
+/// `m:ss`, or `h:mm:ss` for long tracks.
+fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{hours}:{:02}:{:02}", (seconds / 60) % 60, seconds % 60),
+    }
+}
+
+fn is_playing(state: AVTransportState) -> bool {
+    matches!(
+        state,
+        AVTransportState::Playing | AVTransportState::Transitioning
+    )
+}
+
+impl TrackProgress {
+    /// Changes what the key knows and draws it. Starts the key's ticker the first time.
+    async fn show<P: Player>(
+        &self,
+        key: &Key<'_, P>,
+        change: impl FnOnce(&mut Progress),
+    ) -> Result<(), StreamDeckError> {
+        let (canvas, new) = {
+            let mut keys = self.keys.lock().unwrap();
+            let new = !keys.contains_key(key.context);
+            let progress = keys.entry(key.context.to_string()).or_default();
+            change(progress);
+            (progress.canvas(Instant::now()), new)
+        };
+        if new {
+            self.tick(key);
+        }
+        key.show_image(&canvas).await
+    }
+
+    /// Redraws the key every [`TICK`] until it goes away. Images that didn't change, like
+    /// while the room is paused, aren't sent again.
+    fn tick<P: Player>(&self, key: &Key<'_, P>) {
+        let keys = self.keys.clone();
+        let handler = key.handler.clone();
+        let connection = key.connection.clone();
+        let context = key.context.to_string();
+        key.connection.spawn(async move {
+            let mut ticks = tokio::time::interval(TICK);
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {}
+                    _ = connection.closed() => break,
+                }
+                let Some(progress) = keys.lock().unwrap().get(&context).cloned() else {
+                    break;
+                };
+                let canvas = progress.canvas(Instant::now());
+                if let Err(e) = handler.show_image(&connection, &context, &canvas).await {
+                    error!("could not draw track progress: {e:?}");
+                }
+            }
+        });
+    }
+}
+
+impl<P: Player> ActionHandler<P> for TrackProgress {
+    type Settings = RoomSettings;
+
+    fn manifest(&self) -> ActionManifest {
+        ActionManifest {
+            name: "Track Progress",
+            uuid: UUID,
+            icon: "imgs/actions/track-progress/icon",
+            tooltip: "Shows how far into the track the room is, tap to show the time left",
+            property_inspector_path: ROOM_INSPECTOR,
+            controllers: KEYPAD,
+            // the key draws itself
+            disable_automatic_states: true,
+            states: &[State {
+                image: "imgs/actions/track-progress/key",
+                title_alignment: TitleAlignment::Bottom,
+            }],
+            encoder: None,
+        }
+    }
+
+    fn room(settings: &RoomSettings) -> Option<&str> {
+        settings.room()
+    }
+
+    async fn key_up(
+        &self,
+        key: &Key<'_, P>,
+        _settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        self.show(key, |progress| progress.remaining = !progress.remaining)
+            .await
+    }
+
+    async fn will_disappear(
+        &self,
+        key: &Key<'_, P>,
+        _settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        // which also stops the key's ticker
+        self.keys.lock().unwrap().remove(key.context);
+        Ok(())
+    }
+
+    // counted up locally in between, see `Progress::elapsed`
+    fn polls(&self) -> &'static [Poll] {
+        &[Poll::Position, Poll::Transport]
+    }
+
+    async fn update(
+        &self,
+        key: &Key<'_, P>,
+        _settings: &RoomSettings,
+        snapshot: &Snapshot,
+    ) -> Result<(), StreamDeckError> {
+        let now = Instant::now();
+        self.show(key, |progress| {
+            if let Some(position) = &snapshot.position {
+                progress.position = Some((position.clone(), now));
+            }
+            if let Some(state) = snapshot.transport {
+                progress.playing = is_playing(state);
+            }
+        })
+        .await
+    }
+
+    /// Also called for transport events, since the track or position may have changed too.
+    async fn refresh(
+        &self,
+        key: &Key<'_, P>,
+        settings: &RoomSettings,
+    ) -> Result<(), StreamDeckError> {
+        let Some(zone) = key.handler.zone(settings.room()) else {
+            return self
+                .show(key, |progress| {
+                    *progress = Progress {
+                        remaining: progress.remaining,
+                        ..Default::default()
+                    }
+                })
+                .await;
+        };
+
+        if zone.capabilities().events {
+            key.handler.watch_transport(key.connection, &zone);
+        }
+        let state = zone.get_state().await.map_err(failed)?;
+        let position = zone.get_position().await.map_err(failed)?;
+        let now = Instant::now();
+        self.show(key, |progress| {
+            progress.position = Some((position, now));
+            progress.playing = is_playing(state);
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_up_while_playing() {
+        let start = Instant::now();
+        let mut progress = Progress {
+            position: Some((
+                PositionInfo {
+                    track: 1,
+                    duration: Some(Duration::from_secs(200)),
+                    elapsed: Some(Duration::from_secs(60)),
+                    title: None,
+                    artist: None,
+                },
+                start,
+            )),
+            playing: true,
+            remaining: false,
+        };
+
+        let later = start + Duration::from_secs(30);
+        assert_eq!(progress.elapsed(later), Some(Duration::from_secs(90)));
+        assert_eq!(
+            progress.elapsed(start + Duration::from_secs(500)),
+            Some(Duration::from_secs(200)),
+            "never past the end"
+        );
+
+        progress.playing = false;
+        assert_eq!(progress.elapsed(later), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn formats_time() {
+        assert_eq!(format_time(Duration::from_secs(7)), "0:07");
+        assert_eq!(format_time(Duration::from_secs(754)), "12:34");
+        assert_eq!(format_time(Duration::from_secs(3723)), "1:02:03");
+    }
+}
@@ -0,0 +1,1372 @@
+use crate::actions::gesture::{self, Gesture, Release, Timing};
+use crate::actions::{
+    self, fade_out_pause, now_playing, play_pause, restart_or_previous, track_progress, AnyAction,
+    Registry,
+};
+use crate::deep_link::{Command, DeepLink};
+use crate::player::Player;
+use crate::render::{self, Canvas, Images, Titles};
+use crate::scheduler::{self, Poll, Scheduler, Snapshot};
+use crate::sonos::{local_address, ControllerError, DeviceCache, Household, Zone};
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::handler::{Connection, Handler};
+use crate::stream_deck::ReceiveEvent;
+use futures::future::join_all;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Look for new or lost zones this often, even if nothing else tells us to.
+const REDISCOVERY_INTERVAL: Duration = Duration::from_secs(300);
+/// How often we check whether we've moved to a different network.
+const NETWORK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Relative to the plugin directory, which is where the Stream Deck starts us.
+const DEVICE_CACHE_PATH: &str = "cache/speakers.json";
+
+/// Handles the plugin's events for zones of any kind of [`Player`], Sonos by default.
+pub struct SonosHandler<P = Zone> {
+    state: Arc<State<P>>,
+}
+
+// derived Clone would require `P: Clone` even though only the Arc is cloned
+impl<P> Clone for SonosHandler<P> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+struct State<P> {
+    actions: Registry<P>,
+    household: Household<P>,
+    cache: DeviceCache,
+    discovering: AtomicBool,
+    polling: AtomicBool,
+    /// Wakes up discovery early, e.g. when the computer wakes up from sleep.
+    rediscover: Notify,
+    settings: Mutex<GlobalSettings>,
+    /// The keys currently on screen, by context.
+    keys: Mutex<HashMap<String, OnScreen>>,
+    /// Tells the poller that what's on screen changed.
+    keys_changed: Notify,
+    /// Decks that were unplugged, whose keys are left alone until they're back.
+    disconnected: Mutex<HashSet<String>>,
+    gestures: gesture::Detector,
+    images: Images,
+    titles: Titles,
+    /// Rooms whose transport events we're subscribed to.
+    watched_rooms: Mutex<HashSet<String>>,
+}
+
+#[derive(Debug, Clone)]
+struct OnScreen {
+    action: String,
+    device: String,
+    settings: Value,
+}
+
+/// Plugin-wide settings, stored by the Stream Deck.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GlobalSettings {
+    /// The room used by every key that isn't set to a room of its own.
+    active_room: Option<String>,
+    /// Speaker addresses entered by hand, separated by commas or spaces.
+    #[serde(default)]
+    speakers: String,
+    /// Whatever else is in there, kept so writing the settings doesn't drop it.
+    #[serde(flatten)]
+    other: serde_json::Map<String, Value>,
+}
+
+impl GlobalSettings {
+    fn speaker_locations<P: Player>(&self) -> impl Iterator<Item = String> + '_ {
+        self.speakers
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|address| !address.is_empty())
+            .map(P::location_for_address)
+    }
+}
+
+impl<P: Player> Handler<String> for SonosHandler<P> {
+    async fn handle(
+        &self,
+        connection: &Connection,
+        event: &ReceiveEvent<String>,
+    ) -> Result<(), StreamDeckError> {
+        self.discover_zones(connection);
+        self.poll_zones(connection);
+
+        match event {
+            ReceiveEvent::DidReceiveGlobalSettings { payload } => {
+                let settings: GlobalSettings =
+                    serde_json::from_value(payload["settings"].clone()).unwrap_or_default();
+                let previous =
+                    std::mem::replace(&mut *self.state.settings.lock().unwrap(), settings.clone());
+                if previous.speakers != settings.speakers {
+                    self.state.rediscover.notify_one();
+                }
+                self.show_active_room(connection).await
+            }
+            ReceiveEvent::DidReceiveDeepLink { payload } => {
+                let url = payload["url"].as_str().unwrap_or_default();
+                info!("deep link {url}");
+                self.follow(connection, DeepLink::parse(url)?).await
+            }
+            ReceiveEvent::SystemDidWakeUp => {
+                self.state.rediscover.notify_one();
+                Ok(())
+            }
+            ReceiveEvent::DeviceDidConnect { device, .. } => {
+                self.state.disconnected.lock().unwrap().remove(device);
+                self.state.keys_changed.notify_one();
+                Ok(())
+            }
+            ReceiveEvent::DeviceDidDisconnect { device } => {
+                self.state
+                    .disconnected
+                    .lock()
+                    .unwrap()
+                    .insert(device.clone());
+                self.state.keys_changed.notify_one();
+                Ok(())
+            }
+            _ => self.handle_key(connection, event).await,
+        }
+    }
+}
+
+impl<P: Player> SonosHandler<P> {
+    pub fn new() -> Self {
+        Self::with_state(DeviceCache::new(DEVICE_CACHE_PATH), false)
+    }
+
+    /// A handler for the given players that doesn't look for any others, for tests.
+    #[cfg(test)]
+    pub(crate) fn with_players(players: Vec<P>) -> Self {
+        let handler = Self::with_state(DeviceCache::new(DEVICE_CACHE_PATH), true);
+        for player in players {
+            handler.state.household.insert(player);
+        }
+        handler
+    }
+
+    fn with_state(cache: DeviceCache, discovering: bool) -> Self {
+        Self {
+            state: Arc::new(State {
+                household: Default::default(),
+                cache,
+                discovering: AtomicBool::new(discovering),
+                polling: AtomicBool::new(false),
+                rediscover: Notify::new(),
+                settings: Default::default(),
+                actions: Registry::new(),
+                keys: Default::default(),
+                keys_changed: Notify::new(),
+                disconnected: Default::default(),
+                gestures: Default::default(),
+                images: Default::default(),
+                titles: Default::default(),
+                watched_rooms: Default::default(),
+            }),
+        }
+    }
+
+    /// Starts looking for zones in the background, unless we already are. Runs again every
+    /// now and then, when the computer wakes up and when the network changes, and updates the
+    /// keys on screen whenever zones come and go, until the connection closes.
+    fn discover_zones(&self, connection: &Connection) {
+        if self.state.discovering.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let handler = self.clone();
+        let background = connection.clone();
+        connection.spawn(async move {
+            let connection = background;
+            let mut address = local_address();
+            loop {
+                if handler.rediscover().await {
+                    if let Err(e) = handler.show_all_keys(&connection).await {
+                        error!("could not update keys after discovery: {e:?}");
+                    }
+                }
+                if handler.state.household.zones().is_empty() {
+                    warn!("no sonos zones found");
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep(REDISCOVERY_INTERVAL) => {}
+                    _ = handler.state.rediscover.notified() => info!("rediscovering zones"),
+                    _ = network_change(&mut address) => info!("network changed, rediscovering zones"),
+                    _ = connection.closed() => break,
+                }
+            }
+        });
+    }
+
+    /// Starts polling zones for the keys on screen that need it, unless we already are. Goes
+    /// quiet while no keys need anything, and stops when the connection closes.
+    fn poll_zones(&self, connection: &Connection) {
+        if self.state.polling.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let handler = self.clone();
+        let background = connection.clone();
+        connection.spawn(async move {
+            let connection = background;
+            let mut scheduler = Scheduler::default();
+            loop {
+                let (wanted, zones) = handler.wanted_polls();
+                let due = scheduler.due(&wanted, Instant::now());
+                let polled = join_all(due.iter().map(|(name, polls)| {
+                    let zone = &zones[name];
+                    async move { scheduler::poll(zone, polls).await }
+                }));
+                let polled = tokio::select! {
+                    polled = polled => polled,
+                    _ = connection.closed() => break,
+                };
+
+                let now = Instant::now();
+                for ((name, polls), result) in due.iter().zip(polled) {
+                    scheduler.polled(name, polls, result.is_ok(), now);
+                    match result {
+                        Ok(snapshot) => handler.show_polled(&connection, name, &snapshot).await,
+                        Err(e) => debug!("could not poll {name}: {e}"),
+                    }
+                }
+
+                // with nothing to poll, wait for keys to change
+                let next = scheduler.next_due(&wanted);
+                tokio::select! {
+                    _ = tokio::time::sleep_until(next.unwrap_or(now)), if next.is_some() => {}
+                    _ = handler.state.keys_changed.notified() => {}
+                    _ = connection.closed() => break,
+                }
+            }
+        });
+    }
+
+    /// What the keys on screen want polled, by zone name, and the zones themselves.
+    fn wanted_polls(&self) -> (HashMap<String, HashSet<Poll>>, HashMap<String, P>) {
+        let mut wanted: HashMap<String, HashSet<Poll>> = HashMap::new();
+        let mut zones = HashMap::new();
+        for key in self.visible_keys().into_values() {
+            let Some(action) = self.state.actions.get(&key.action) else {
+                continue;
+            };
+            if action.polls().is_empty() {
+                continue;
+            }
+            let Some(zone) = self.state.zone(self.state.room(&key).as_deref()) else {
+                continue;
+            };
+            let events = zone.capabilities().events;
+            let polls = action
+                .polls()
+                .iter()
+                .filter(|poll| !(events && **poll == Poll::Transport));
+            let name = zone.name().to_string();
+            wanted.entry(name.clone()).or_default().extend(polls);
+            zones.insert(name, zone);
+        }
+        wanted.retain(|_, polls| !polls.is_empty());
+        (wanted, zones)
+    }
+
+    /// The keys on screen, minus those on decks that aren't plugged in.
+    fn visible_keys(&self) -> HashMap<String, OnScreen> {
+        let disconnected = self.state.disconnected.lock().unwrap().clone();
+        let mut keys = self.state.keys.lock().unwrap().clone();
+        keys.retain(|_, key| !disconnected.contains(&key.device));
+        keys
+    }
+
+    /// Hands what polling a zone turned up to the keys that asked for it.
+    async fn show_polled(&self, connection: &Connection, zone: &str, snapshot: &Snapshot) {
+        for (context, key) in self.visible_keys() {
+            let Some(action) = self.state.actions.get(&key.action) else {
+                continue;
+            };
+            if !action.polls().iter().any(|poll| snapshot.has(*poll)) {
+                continue;
+            }
+            let key_zone = self.state.zone(self.state.room(&key).as_deref());
+            if key_zone.as_ref().map(P::name) != Some(zone) {
+                continue;
+            }
+            let on_screen = actions::Key {
+                handler: self,
+                connection,
+                context: &context,
+            };
+            if let Err(e) = action.update(on_screen, &key.settings, snapshot).await {
+                error!("could not update {} key: {e:?}", key.action);
+            }
+        }
+    }
+
+    /// Looks for zones on the network and at the addresses we already know about, and
+    /// remembers what we found for next time. Returns whether anything changed.
+    async fn rediscover(&self) -> bool {
+        let mut locations: Vec<String> = self
+            .state
+            .cache
+            .load()
+            .into_iter()
+            .map(|speaker| speaker.location)
+            .collect();
+        locations.extend(self.state.settings.lock().unwrap().speaker_locations::<P>());
+        locations.sort_unstable();
+        locations.dedup();
+
+        let changed = self
+            .state
+            .household
+            .discover(DISCOVERY_TIMEOUT, &locations)
+            .await;
+        if changed {
+            if let Err(e) = self.state.cache.save(&self.state.household.zones()) {
+                warn!("could not cache speakers: {e}");
+            }
+        }
+        changed
+    }
+
+    /// Keeps track of the keys on screen, and hands their events to their action.
+    async fn handle_key(
+        &self,
+        connection: &Connection,
+        event: &ReceiveEvent<String>,
+    ) -> Result<(), StreamDeckError> {
+        let (Some(uuid), Some(context)) = (event.action(), event.context()) else {
+            return Ok(());
+        };
+        let Some(action) = self.state.actions.get(uuid) else {
+            return Err(StreamDeckError::UnknownAction);
+        };
+
+        match event {
+            ReceiveEvent::WillAppear {
+                device, payload, ..
+            } => {
+                if let (Some(on), Some(size)) =
+                    (connection.device(device), connection.key_size(device))
+                {
+                    debug!("{uuid} key on {} ({:?}, {size}px keys)", on.name, on.kind);
+                }
+                let key = OnScreen {
+                    action: uuid.clone(),
+                    device: device.clone(),
+                    settings: payload.settings.clone(),
+                };
+                self.state
+                    .keys
+                    .lock()
+                    .unwrap()
+                    .insert(context.to_string(), key);
+                self.state.keys_changed.notify_one();
+            }
+            ReceiveEvent::DidReceiveSettings { payload, .. } => {
+                if let Some(key) = self.state.keys.lock().unwrap().get_mut(context) {
+                    key.settings = payload["settings"].clone();
+                }
+                self.state.keys_changed.notify_one();
+            }
+            ReceiveEvent::TitleParametersDidChange { payload, .. } => {
+                let parameters = payload.title_parameters.clone();
+                self.state
+                    .titles
+                    .parameters_changed(connection, context, parameters)
+                    .await?;
+            }
+            ReceiveEvent::WillDisappear { .. } => {
+                self.state.keys.lock().unwrap().remove(context);
+                self.state.gestures.remove(context);
+                self.state.images.forget(context);
+                self.state.titles.forget(context);
+                self.state.keys_changed.notify_one();
+            }
+            // a multi-action presses and lets go right away, which can only be a tap, and one
+            // that's over once the event is handled
+            _ if event.is_in_multi_action() => {}
+            ReceiveEvent::KeyDown { payload, .. } if !action.gestures().is_empty() => {
+                let press = self.state.gestures.key_down(context);
+                self.watch_hold(connection, action, uuid, context, press, &payload.settings);
+            }
+            ReceiveEvent::KeyUp { payload, .. } if !action.gestures().is_empty() => {
+                return self
+                    .release(connection, action, uuid, context, &payload.settings)
+                    .await;
+            }
+            _ => {}
+        }
+
+        let key = actions::Key {
+            handler: self,
+            connection,
+            context,
+        };
+        action.handle(key, event).await
+    }
+
+    /// Fires long presses and holds for a press of a key that binds them, for as long as it
+    /// stays down.
+    fn watch_hold(
+        &self,
+        connection: &Connection,
+        action: &dyn AnyAction<P>,
+        uuid: &str,
+        context: &str,
+        press: u64,
+        settings: &Value,
+    ) {
+        let gestures = action.gestures();
+        let long_press = gestures.contains(&Gesture::LongPress);
+        let hold = gestures.contains(&Gesture::Hold);
+        if !long_press && !hold {
+            return;
+        }
+
+        let timing = Timing::from_settings(settings);
+        let handler = self.clone();
+        let background = connection.clone();
+        let (uuid, context, settings) = (uuid.to_string(), context.to_string(), settings.clone());
+        connection.spawn(async move {
+            let connection = background;
+            let still_down = |wait| handler.still_down(&connection, &context, press, wait);
+
+            if !still_down(timing.long_press()).await {
+                return;
+            }
+            if long_press {
+                handler
+                    .fire(&connection, &uuid, &context, &settings, Gesture::LongPress)
+                    .await;
+            }
+            if hold {
+                loop {
+                    handler
+                        .fire(&connection, &uuid, &context, &settings, Gesture::Hold)
+                        .await;
+                    if !still_down(timing.repeat()).await {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Waits a bit, and then tells whether the key is still down for the same press.
+    async fn still_down(
+        &self,
+        connection: &Connection,
+        context: &str,
+        press: u64,
+        wait: Duration,
+    ) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => self.state.gestures.hold(context, press),
+            _ = connection.closed() => false,
+        }
+    }
+
+    /// Works out what letting go of a key that binds gestures means, and does it.
+    async fn release(
+        &self,
+        connection: &Connection,
+        action: &dyn AnyAction<P>,
+        uuid: &str,
+        context: &str,
+        settings: &Value,
+    ) -> Result<(), StreamDeckError> {
+        let double_tap = action.gestures().contains(&Gesture::DoubleTap);
+        let gesture = match self.state.gestures.key_up(context, double_tap) {
+            Release::Held => return Ok(()),
+            Release::Tap => Gesture::Tap,
+            Release::DoubleTap => Gesture::DoubleTap,
+            Release::MaybeTap(press) => {
+                let timing = Timing::from_settings(settings);
+                let handler = self.clone();
+                let background = connection.clone();
+                let (uuid, context, settings) =
+                    (uuid.to_string(), context.to_string(), settings.clone());
+                connection.spawn(async move {
+                    let connection = background;
+                    tokio::select! {
+                        _ = tokio::time::sleep(timing.double_tap()) => {}
+                        _ = connection.closed() => return,
+                    }
+                    if handler.state.gestures.take_tap(&context, press) {
+                        handler
+                            .fire(&connection, &uuid, &context, &settings, Gesture::Tap)
+                            .await;
+                    }
+                });
+                return Ok(());
+            }
+        };
+
+        let key = actions::Key {
+            handler: self,
+            connection,
+            context,
+        };
+        action.gesture(key, settings, gesture).await
+    }
+
+    /// Runs a gesture that was recognized in the background, after the key's event was handled,
+    /// so failures are shown here rather than by the middleware.
+    async fn fire(
+        &self,
+        connection: &Connection,
+        uuid: &str,
+        context: &str,
+        settings: &Value,
+        gesture: Gesture,
+    ) {
+        let Some(action) = self.state.actions.get(uuid) else {
+            return;
+        };
+        debug!("{gesture:?} on {uuid}");
+        let key = actions::Key {
+            handler: self,
+            connection,
+            context,
+        };
+        if let Err(e) = action.gesture(key, settings, gesture).await {
+            error!("{gesture:?} on {uuid} failed: {e:?}");
+            if let Err(e) = connection.show_alert(context).await {
+                error!("could not show alert: {e:?}");
+            }
+        }
+    }
+
+    /// Draws an image on a key, at the size its deck wants, unless it already shows it.
+    pub(crate) async fn show_image(
+        &self,
+        connection: &Connection,
+        context: &str,
+        canvas: &Canvas,
+    ) -> Result<(), StreamDeckError> {
+        let device = match self.state.keys.lock().unwrap().get(context) {
+            Some(key) => key.device.clone(),
+            None => return Ok(()),
+        };
+        let pixels = render::pixels_for(connection.key_size(&device));
+        self.state
+            .images
+            .show(connection, context, canvas, pixels)
+            .await
+    }
+
+    /// Goes back to the key's image from the manifest, after [`Self::show_image`].
+    pub(crate) async fn clear_image(
+        &self,
+        connection: &Connection,
+        context: &str,
+    ) -> Result<(), StreamDeckError> {
+        self.state.images.clear(connection, context).await
+    }
+
+    /// Sets a key's title, scrolling it if it's too long.
+    pub(crate) async fn show_title(
+        &self,
+        connection: &Connection,
+        context: &str,
+        title: &str,
+    ) -> Result<(), StreamDeckError> {
+        // it'd keep scrolling after the key went away
+        if !self.state.keys.lock().unwrap().contains_key(context) {
+            return Ok(());
+        }
+        self.state.titles.show(connection, context, title).await
+    }
+
+    /// Every zone we know about.
+    pub(crate) fn zones(&self) -> Arc<Vec<P>> {
+        self.state.household.zones()
+    }
+
+    /// The zone for the given room, or for the active room if `room` is `None`.
+    pub(crate) fn zone(&self, room: Option<&str>) -> Option<P> {
+        self.state.zone(room)
+    }
+
+    /// Does what a deep link asks for, the way the key of the same name would.
+    async fn follow(&self, connection: &Connection, link: DeepLink) -> Result<(), StreamDeckError> {
+        let room = link.room.as_deref();
+        if let Some(room) = room {
+            if self.state.household.find(room).is_none() {
+                return Err(StreamDeckError::InvalidArguments(format!(
+                    "no room called {room:?}"
+                )));
+            }
+        }
+        let failed = |e: ControllerError| StreamDeckError::HandlerFailed(e.to_string());
+        let zone = || {
+            self.zone(room)
+                .ok_or_else(|| StreamDeckError::HandlerFailed("no zone detected".to_string()))
+        };
+        match link.command {
+            Command::Play => match &link.favorite {
+                Some(favorite) => zone()?.play_favorite(favorite).await.map_err(failed)?,
+                None => zone()?.play().await.map_err(failed)?,
+            },
+            Command::Pause => zone()?.pause().await.map_err(failed)?,
+            Command::PlayPause => zone()?.play_pause().await.map_err(failed)?,
+            Command::Next => zone()?.next().await.map_err(failed)?,
+            Command::Previous => zone()?.previous().await.map_err(failed)?,
+            Command::RestartOrPrevious => zone()?
+                .restart_or_previous(restart_or_previous::RESTART_THRESHOLD)
+                .await
+                .map_err(failed)?,
+            Command::FadeOut => fade_out_pause::fade_out(connection, zone()?).await?,
+            Command::PauseAll => play_pause::pause_all(&self.zones()).await?,
+            Command::SelectRoom => {
+                let room = zone()?.name().to_string();
+                return self.select_room(connection, room).await;
+            }
+        }
+        // keys don't hear about changes from players without events
+        self.show_all_keys(connection).await
+    }
+
+    /// Makes the room after the active one (in alphabetical order) the active room.
+    pub(crate) async fn select_next_room(
+        &self,
+        connection: &Connection,
+    ) -> Result<(), StreamDeckError> {
+        let zones = self.state.household.zones();
+        let mut names: Vec<&str> = zones.iter().map(P::name).collect();
+        names.sort_unstable();
+        let Some(first) = names.first() else {
+            warn!("no zone detected");
+            return Ok(());
+        };
+
+        let current = self.state.zone(None);
+        let current = current.as_ref().map(P::name);
+        let next = names
+            .iter()
+            .skip_while(|name| Some(**name) != current)
+            .nth(1)
+            .unwrap_or(first)
+            .to_string();
+        self.select_room(connection, next).await
+    }
+
+    /// Makes the room the active one, and remembers it.
+    async fn select_room(
+        &self,
+        connection: &Connection,
+        room: String,
+    ) -> Result<(), StreamDeckError> {
+        info!("active room is now {room}");
+        let settings = {
+            let mut settings = self.state.settings.lock().unwrap();
+            settings.active_room = Some(room);
+            settings.clone()
+        };
+        connection
+            .set_global_settings(serde_json::to_value(settings)?)
+            .await?;
+        self.show_active_room(connection).await
+    }
+
+    async fn show_all_keys(&self, connection: &Connection) -> Result<(), StreamDeckError> {
+        let contexts: Vec<String> = self.state.keys.lock().unwrap().keys().cloned().collect();
+        for context in contexts {
+            self.show_key(connection, &context).await?;
+        }
+        Ok(())
+    }
+
+    /// Updates every key that depends on the active room.
+    async fn show_active_room(&self, connection: &Connection) -> Result<(), StreamDeckError> {
+        self.state.keys_changed.notify_one();
+        let contexts: Vec<String> = self
+            .state
+            .keys
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, key)| self.state.room(key).is_none())
+            .map(|(context, _)| context.clone())
+            .collect();
+        for context in contexts {
+            self.show_key(connection, &context).await?;
+        }
+        Ok(())
+    }
+
+    /// Brings a key on screen up to date with the room it controls.
+    async fn show_key(
+        &self,
+        connection: &Connection,
+        context: &str,
+    ) -> Result<(), StreamDeckError> {
+        let Some(key) = self.state.keys.lock().unwrap().get(context).cloned() else {
+            return Ok(());
+        };
+        let Some(action) = self.state.actions.get(&key.action) else {
+            return Ok(());
+        };
+        let on_screen = actions::Key {
+            handler: self,
+            connection,
+            context,
+        };
+        action.refresh(on_screen, &key.settings).await
+    }
+
+    /// Keeps the play/pause keys for the zone in sync with changes made outside of the Stream
+    /// Deck, and has keys about the track catch up with what the room plays now. Does nothing
+    /// if we're already watching, and tries again on the next call if watching fails.
+    pub(crate) fn watch_transport(&self, connection: &Connection, zone: &P) {
+        let room = zone.name().to_string();
+        if !self
+            .state
+            .watched_rooms
+            .lock()
+            .unwrap()
+            .insert(room.clone())
+        {
+            return;
+        }
+
+        let background = connection.clone();
+        let handler = self.clone();
+        let zone = zone.clone();
+        connection.spawn(async move {
+            let connection = background;
+            let state = &handler.state;
+            let (states_tx, states_rx) = mpsc::channel(8);
+            // dropping the receiver when the connection closes ends the subscription
+            let forward = async {
+                let mut states_rx = states_rx;
+                loop {
+                    let transport_state = tokio::select! {
+                        transport_state = states_rx.recv() => match transport_state {
+                            Some(transport_state) => transport_state,
+                            None => break,
+                        },
+                        _ = connection.closed() => break,
+                    };
+                    for context in state.keys_in_room(&room, play_pause::UUID) {
+                        let key = actions::Key {
+                            handler: &handler,
+                            connection: &connection,
+                            context: &context,
+                        };
+                        if let Err(e) = play_pause::show_state(&key, transport_state).await {
+                            error!("could not update play/pause key: {e:?}");
+                        }
+                    }
+                    // the track may have changed too
+                    for action in [track_progress::UUID, now_playing::UUID] {
+                        for context in state.keys_in_room(&room, action) {
+                            if let Err(e) = handler.show_key(&connection, &context).await {
+                                error!("could not update {action} key: {e:?}");
+                            }
+                        }
+                    }
+                }
+            };
+
+            let (watched, _) = tokio::join!(zone.watch_transport_state(states_tx), forward);
+            if let Err(e) = watched {
+                warn!("stopped watching transport state for {room}: {e}");
+            }
+            state.watched_rooms.lock().unwrap().remove(&room);
+        });
+    }
+}
+
+impl<P: Player> State<P> {
+    /// The zone for the given room, or for the active room if `room` is `None`. Falls back to
+    /// the first zone we know about if there's no active room yet.
+    fn zone(&self, room: Option<&str>) -> Option<P> {
+        let settings = self.settings.lock().unwrap();
+        match room.or(settings.active_room.as_deref()) {
+            Some(room) => {
+                let zone = self.household.find(room);
+                if zone.is_none() {
+                    warn!("no zone found for room {room}");
+                }
+                zone
+            }
+            None => self.household.first(),
+        }
+    }
+
+    /// The room the key is set to, or `None` if it follows the active room.
+    fn room(&self, key: &OnScreen) -> Option<String> {
+        let action = self.actions.get(&key.action)?;
+        action.room(&key.settings)
+    }
+
+    /// Contexts of the keys for `action` on screen that control the given room.
+    fn keys_in_room(&self, room: &str, action: &str) -> Vec<String> {
+        let keys = self.keys.lock().unwrap().clone();
+        keys.into_iter()
+            .filter(|(_, key)| key.action == action)
+            .filter(|(_, key)| {
+                let zone = self.zone(self.room(key).as_deref());
+                zone.as_ref().map(P::name) == Some(room)
+            })
+            .map(|(context, _)| context)
+            .collect()
+    }
+}
+
+/// Resolves once the local address differs from `address`, and updates it.
+async fn network_change(address: &mut Option<IpAddr>) {
+    let mut checks = tokio::time::interval(NETWORK_CHECK_INTERVAL);
+    loop {
+        checks.tick().await;
+        let current = local_address();
+        if current != *address {
+            *address = current;
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::mock::MockPlayer;
+    use crate::sonos::{AVTransportState, ControllerError};
+    use crate::stream_deck::middleware::Feedback;
+    use crate::stream_deck::SendEvent;
+    use serde_json::json;
+
+    const PLUGIN: &str = "plugin-uuid";
+
+    fn setup(rooms: &[&str]) -> (SonosHandler<MockPlayer>, Vec<MockPlayer>) {
+        let players: Vec<MockPlayer> = rooms.iter().map(|room| MockPlayer::new(room)).collect();
+        (SonosHandler::with_players(players.clone()), players)
+    }
+
+    fn connection() -> (Connection, mpsc::Receiver<SendEvent>) {
+        let (tx, rx) = mpsc::channel(32);
+        (Connection::new(tx, PLUGIN, Default::default()), rx)
+    }
+
+    fn event(event: &str, action: &str, context: &str, settings: Value) -> ReceiveEvent<String> {
+        serde_json::from_value(json!({
+            "event": event,
+            "action": format!("sh.viora.controller-for-sonos.{action}"),
+            "context": context,
+            "device": "device",
+            "payload": {
+                "settings": settings,
+                "coordinates": { "column": 0, "row": 0 },
+                "controller": "Keypad",
+                "isInMultiAction": false,
+            },
+        }))
+        .unwrap()
+    }
+
+    fn multi_action(mut event: ReceiveEvent<String>) -> ReceiveEvent<String> {
+        match &mut event {
+            ReceiveEvent::KeyDown { payload, .. } | ReceiveEvent::KeyUp { payload, .. } => {
+                payload.is_in_multi_action = true;
+            }
+            _ => {}
+        }
+        event
+    }
+
+    fn sent(events: &mut mpsc::Receiver<SendEvent>) -> Vec<SendEvent> {
+        let mut sent = vec![];
+        while let Ok(event) = events.try_recv() {
+            sent.push(event);
+        }
+        sent
+    }
+
+    #[tokio::test]
+    async fn play_pause_toggles_and_shows_state() {
+        let (handler, players) = setup(&["Kitchen"]);
+        let (connection, mut events) = connection();
+
+        let appear = event("willAppear", "play-pause", "key", json!({}));
+        handler.handle(&connection, &appear).await.unwrap();
+        sent(&mut events);
+        let key_up = event("keyUp", "play-pause", "key", json!({}));
+        handler.handle(&connection, &key_up).await.unwrap();
+
+        assert_eq!(players[0].state().transport, AVTransportState::Playing);
+        assert!(sent(&mut events).iter().any(|event| matches!(
+            event,
+            SendEvent::SetState { context, payload } if context == "key" && payload.state == 1
+        )));
+    }
+
+    #[tokio::test]
+    async fn keys_control_their_own_room() {
+        let (handler, players) = setup(&["Kitchen", "Office"]);
+        let (connection, _events) = connection();
+
+        let key_up = event("keyUp", "next-track", "key", json!({ "room": "Office" }));
+        handler.handle(&connection, &key_up).await.unwrap();
+
+        assert_eq!(players[0].state().track, 1);
+        assert_eq!(players[1].state().track, 2);
+    }
+
+    #[tokio::test]
+    async fn room_selector_cycles_rooms() {
+        let (handler, _) = setup(&["Office", "Kitchen", "Bedroom"]);
+        let (connection, mut events) = connection();
+        let global = serde_json::from_value(json!({
+            "event": "didReceiveGlobalSettings",
+            "payload": { "settings": { "speakers": "10.0.0.2", "added": "later" } },
+        }))
+        .unwrap();
+        handler.handle(&connection, &global).await.unwrap();
+
+        let appear = event("willAppear", "room-selector", "selector", json!({}));
+        handler.handle(&connection, &appear).await.unwrap();
+        let key_up = event("keyUp", "room-selector", "selector", json!({}));
+        handler.handle(&connection, &key_up).await.unwrap();
+
+        let sent = sent(&mut events);
+        let titles: Vec<&str> = sent
+            .iter()
+            .filter_map(|event| match event {
+                SendEvent::SetTitle { payload, .. } => Some(&*payload.title),
+                _ => None,
+            })
+            .collect();
+        // settings are written whole, so the room goes in with everything else
+        assert!(sent.iter().any(|event| matches!(
+            event,
+            SendEvent::SetGlobalSettings { payload, .. } if *payload == json!({
+                "activeRoom": "Bedroom",
+                "speakers": "10.0.0.2",
+                "added": "later",
+            })
+        )));
+        // falls back to the first room found, then moves on alphabetically
+        assert_eq!(titles, ["Office", "Bedroom"]);
+        assert_eq!(
+            handler
+                .state
+                .settings
+                .lock()
+                .unwrap()
+                .active_room
+                .as_deref(),
+            Some("Bedroom")
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_actions_show_alert() {
+        let (handler, players) = setup(&["Kitchen"]);
+        let (connection, mut events) = connection();
+        players[0].state().fail = Some(ControllerError::MalformedResponse);
+
+        let key_up = event("keyUp", "next-track", "key", json!({}));
+        let handler = handler.with(Feedback);
+        assert!(handler.handle(&connection, &key_up).await.is_err());
+        assert!(sent(&mut events)
+            .iter()
+            .any(|event| matches!(event, SendEvent::ShowAlert { context } if context == "key")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restart_skips_when_seeking_is_unsupported() {
+        let (handler, players) = setup(&["Kitchen"]);
+        let (connection, _events) = connection();
+        {
+            let mut state = players[0].state();
+            state.track = 2;
+            state.elapsed = Duration::from_secs(60);
+            state.capabilities.seek = false;
+        }
+
+        let key_up = event("keyUp", "restart-or-previous", "key", json!({}));
+        handler.handle(&connection, &key_up).await.unwrap();
+        // taps wait for a possible double tap first
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(players[0].state().track, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fade_out_restores_volume() {
+        let (handler, players) = setup(&["Kitchen"]);
+        let (connection, _events) = connection();
+        players[0].state().transport = AVTransportState::Playing;
+
+        let key_up = event("keyUp", "fade-out-pause", "key", json!({}));
+        handler.handle(&connection, &key_up).await.unwrap();
+
+        let state = players[0].state();
+        assert_eq!(state.transport, AVTransportState::Paused);
+        assert_eq!(state.volume, 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gestures_do_different_things() {
+        let (handler, players) = setup(&["Kitchen", "Office"]);
+        let (connection, _events) = connection();
+        for player in &players {
+            let mut state = player.state();
+            state.transport = AVTransportState::Playing;
+            state.tracks = 10;
+        }
+        let press = |action: &str, context: &str, settings: Value| {
+            let down = event("keyDown", action, context, settings.clone());
+            let up = event("keyUp", action, context, settings);
+            (down, up)
+        };
+
+        // a long press on play/pause pauses every room, and letting go doesn't toggle again
+        let (down, up) = press("play-pause", "play", json!({ "room": "Kitchen" }));
+        handler.handle(&connection, &down).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        handler.handle(&connection, &up).await.unwrap();
+        for player in &players {
+            assert_eq!(player.state().transport, AVTransportState::Paused);
+        }
+
+        // holding next keeps skipping, at the key's own pace
+        let settings = json!({ "longPressMs": 1000, "repeatMs": 100 });
+        let (down, up) = press("next-track", "next", settings);
+        handler.handle(&connection, &down).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1250)).await;
+        handler.handle(&connection, &up).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(players[0].state().track, 4);
+
+        // a double tap goes back no matter how far into the track we are
+        players[0].state().elapsed = Duration::from_secs(60);
+        let (down, up) = press("restart-or-previous", "previous", json!({}));
+        for _ in 0..2 {
+            handler.handle(&connection, &down).await.unwrap();
+            handler.handle(&connection, &up).await.unwrap();
+        }
+        assert_eq!(players[0].state().track, 3);
+
+        // while a single tap waits to be sure it wasn't a double tap, then restarts
+        players[0].state().elapsed = Duration::from_secs(60);
+        handler.handle(&connection, &down).await.unwrap();
+        handler.handle(&connection, &up).await.unwrap();
+        assert_eq!(players[0].state().elapsed, Duration::from_secs(60));
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let state = players[0].state();
+        assert_eq!((state.track, state.elapsed), (3, Duration::ZERO));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn multi_actions_tap_right_away() {
+        let (handler, players) = setup(&["Kitchen"]);
+        let (connection, mut events) = connection();
+        players[0].state().elapsed = Duration::from_secs(60);
+        let handler = handler.with(Feedback);
+
+        for kind in ["keyDown", "keyUp"] {
+            let step = multi_action(event(kind, "restart-or-previous", "key", json!({})));
+            handler.handle(&connection, &step).await.unwrap();
+        }
+
+        // no waiting for a double tap, and one check mark once it's done
+        assert_eq!(players[0].state().elapsed, Duration::ZERO);
+        let oks = sent(&mut events)
+            .into_iter()
+            .filter(|event| matches!(event, SendEvent::ShowOk { .. }))
+            .count();
+        assert_eq!(oks, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_players_without_events() {
+        let (handler, players) = setup(&["Kitchen"]);
+        let (connection, mut events) = connection();
+        players[0].state().capabilities.events = false;
+
+        let appear = event("willAppear", "play-pause", "key", json!({}));
+        handler.handle(&connection, &appear).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        sent(&mut events);
+
+        // someone else starts playing, which this player can't tell us about
+        players[0].state().transport = AVTransportState::Playing;
+        tokio::time::sleep(Poll::Transport.interval()).await;
+        assert!(sent(&mut events).iter().any(|event| matches!(
+            event,
+            SendEvent::SetState { context, payload } if context == "key" && payload.state == 1
+        )));
+
+        // nothing is polled for a deck that's unplugged
+        let unplugged: ReceiveEvent<String> =
+            serde_json::from_value(json!({ "event": "deviceDidDisconnect", "device": "device" }))
+                .unwrap();
+        handler.handle(&connection, &unplugged).await.unwrap();
+        tokio::task::yield_now().await;
+        let calls = players[0].state().calls.len();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(players[0].state().calls.len(), calls);
+        connection.close();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fades_put_the_volume_back() {
+        let (handler, players) = setup(&["Kitchen"]);
+        let (connection, _events) = connection();
+        let fade = event("keyUp", "fade-out-pause", "key", json!({}));
+
+        // nobody waits for the fade to end, like a handler that timed out
+        players[0].state().transport = AVTransportState::Playing;
+        let handled = handler.handle(&connection, &fade);
+        assert!(tokio::time::timeout(Duration::from_secs(1), handled)
+            .await
+            .is_err());
+        tokio::time::sleep(fade_out_pause::FADE_OUT_DURATION).await;
+        assert_eq!(players[0].state().transport, AVTransportState::Paused);
+        assert_eq!(players[0].state().volume, 20);
+
+        // and shutdown doesn't wait for the whole fade, but doesn't leave it quiet either
+        players[0].state().transport = AVTransportState::Playing;
+        let handled = handler.handle(&connection, &fade);
+        assert!(tokio::time::timeout(Duration::from_secs(1), handled)
+            .await
+            .is_err());
+        let start = Instant::now();
+        connection.close();
+        connection.finish_tasks(Duration::from_secs(3)).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(players[0].state().transport, AVTransportState::Paused);
+        assert_eq!(players[0].state().volume, 20);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unplugged_speakers_do_not_hold_up_polling() {
+        let (handler, players) = setup(&["Kitchen", "Office"]);
+        let (connection, mut events) = connection();
+        for player in &players {
+            player.state().capabilities.events = false;
+        }
+
+        for (context, room) in [("kitchen", "Kitchen"), ("office", "Office")] {
+            let appear = event("willAppear", "play-pause", context, json!({ "room": room }));
+            handler.handle(&connection, &appear).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        sent(&mut events);
+
+        // the kitchen speaker goes away without a word, and the office starts playing
+        players[0].state().stalled = true;
+        players[1].state().transport = AVTransportState::Playing;
+        tokio::time::sleep(Poll::Transport.interval() + scheduler::POLL_TIMEOUT).await;
+        assert!(sent(&mut events).iter().any(|event| matches!(
+            event,
+            SendEvent::SetState { context, payload } if context == "office" && payload.state == 1
+        )));
+        connection.close();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn track_progress_counts_up_between_polls() {
+        let (handler, players) = setup(&["Kitchen"]);
+        let (connection, mut events) = connection();
+        players[0].state().transport = AVTransportState::Playing;
+        let positions = || {
+            let state = players[0].state();
+            state
+                .calls
+                .iter()
+                .filter(|call| **call == "get_position")
+                .count()
+        };
+        let images = |events: &mut mpsc::Receiver<SendEvent>| {
+            sent(events)
+                .into_iter()
+                .filter(|event| matches!(event, SendEvent::SetImage { .. }))
+                .count()
+        };
+
+        let appear = event("willAppear", "track-progress", "key", json!({}));
+        handler.handle(&connection, &appear).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(images(&mut events) > 0);
+        let polled = positions();
+
+        // the key moves every second without asking the player
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(images(&mut events), 3);
+        assert_eq!(positions(), polled);
+
+        // but catches up as soon as something happens, then stays put while paused
+        players[0].change_transport(AVTransportState::Paused);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(positions(), polled + 1);
+        images(&mut events);
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(images(&mut events), 0);
+        connection.close();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn now_playing_scrolls_long_titles() {
+        let (handler, players) = setup(&["Kitchen"]);
+        let (connection, mut events) = connection();
+        players[0].state().title = "A title much too long for any key".to_string();
+        let titles = |events: &mut mpsc::Receiver<SendEvent>| -> Vec<String> {
+            sent(events)
+                .into_iter()
+                .filter_map(|event| match event {
+                    SendEvent::SetTitle { payload, .. } => Some(payload.title),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let appear = event("willAppear", "now-playing", "key", json!({}));
+        handler.handle(&connection, &appear).await.unwrap();
+        let parameters: ReceiveEvent<String> = serde_json::from_value(json!({
+            "event": "titleParametersDidChange",
+            "action": "sh.viora.controller-for-sonos.now-playing",
+            "context": "key",
+            "device": "device",
+            "payload": {
+                "settings": {},
+                "coordinates": { "column": 0, "row": 0 },
+                "state": 0,
+                "title": "",
+                "titleParameters": {
+                    "fontFamily": "",
+                    "fontSize": 6,
+                    "fontStyle": "",
+                    "fontUnderline": false,
+                    "showTitle": true,
+                    "titleAlignment": "bottom",
+                    "titleColor": "#ffffff",
+                },
+            },
+        }))
+        .unwrap();
+        handler.handle(&connection, &parameters).await.unwrap();
+
+        // smaller text fits more
+        let shown = titles(&mut events);
+        assert_eq!(shown.len(), 2);
+        assert!(shown[1].len() > shown[0].len());
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(!titles(&mut events).is_empty());
+
+        let disappear = event("willDisappear", "now-playing", "key", json!({}));
+        handler.handle(&connection, &disappear).await.unwrap();
+        titles(&mut events);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(titles(&mut events).is_empty());
+        connection.close();
+    }
+
+    #[tokio::test]
+    async fn deep_links_drive_rooms() {
+        let (handler, players) = setup(&["Kitchen", "Office"]);
+        let (connection, _events) = connection();
+        let follow = |url: &str| {
+            let link: ReceiveEvent<String> = serde_json::from_value(json!({
+                "event": "didReceiveDeepLink",
+                "payload": { "url": url },
+            }))
+            .unwrap();
+            let handler = handler.clone();
+            let connection = connection.clone();
+            async move { handler.handle(&connection, &link).await }
+        };
+
+        follow("/next?room=Office").await.unwrap();
+        assert_eq!(players[1].state().track, 2);
+        follow("/select-room?room=Office").await.unwrap();
+        follow("/play").await.unwrap();
+        assert_eq!(players[1].state().transport, AVTransportState::Playing);
+        assert_eq!(players[0].state().transport, AVTransportState::Paused);
+
+        players[0].state().transport = AVTransportState::Playing;
+        follow("/pause-all").await.unwrap();
+        assert!(players
+            .iter()
+            .all(|player| player.state().transport == AVTransportState::Paused));
+
+        // nothing happens for links that don't make sense
+        assert!(follow("/play?room=Attic").await.is_err());
+        assert!(follow("/play?room=Kitchen&favorite=Polka").await.is_err());
+        assert!(follow("/pause?room=Kitchen&favorite=Jazz").await.is_err());
+        assert_eq!(players[0].state().transport, AVTransportState::Paused);
+
+        follow("/play?room=Kitchen&favorite=jazz#now")
+            .await
+            .unwrap();
+        assert_eq!(players[0].state().favorite.as_deref(), Some("Jazz"));
+        assert_eq!(players[0].state().transport, AVTransportState::Playing);
+    }
+
+    #[tokio::test]
+    async fn play_pause_keys_follow_outside_changes() {
+        let (handler, players) = setup(&["Kitchen"]);
+        let (connection, mut events) = connection();
+
+        let appear = event("willAppear", "play-pause", "key", json!({}));
+        handler.handle(&connection, &appear).await.unwrap();
+        sent(&mut events);
+
+        // let the watcher subscribe before anything changes
+        tokio::task::yield_now().await;
+        players[0].change_transport(AVTransportState::Playing);
+        let update = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap();
+        assert!(matches!(
+            update,
+            Some(SendEvent::SetState { payload, .. }) if payload.state == 1
+        ));
+
+        // buffering looks like the pause state with an image of its own, there's no third state
+        players[0].change_transport(AVTransportState::Transitioning);
+        let mut updates = vec![];
+        for _ in 0..2 {
+            let update = tokio::time::timeout(Duration::from_secs(1), events.recv()).await;
+            updates.push(update.unwrap().unwrap());
+        }
+        assert!(matches!(
+            &updates[0],
+            SendEvent::SetState { payload, .. } if payload.state == 1
+        ));
+        assert!(matches!(
+            &updates[1],
+            SendEvent::SetImage { payload, .. } if payload.image.starts_with("data:image/png")
+        ));
+
+        // and back to the manifest's once it plays
+        players[0].change_transport(AVTransportState::Playing);
+        let mut updates = vec![];
+        for _ in 0..2 {
+            let update = tokio::time::timeout(Duration::from_secs(1), events.recv()).await;
+            updates.push(update.unwrap().unwrap());
+        }
+        assert!(matches!(
+            &updates[1],
+            SendEvent::SetImage { payload, .. } if payload.image.is_empty()
+        ));
+    }
+}
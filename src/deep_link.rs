@@ -0,0 +1,183 @@
+//! Commands from deep links, so scripts and other apps can drive rooms through the plugin:
+//!
+//! ```text
+//! streamdeck://plugins/message/sh.viora.controller-for-sonos/<command>[?room=<room>][&favorite=<favorite>]
+//! ```
+//!
+//! The app hands us everything after the plugin's UUID. Commands do what the key of the same
+//! name does, in the given room or else the active one, e.g. `next?room=Living%20Room`. `play`
+//! can also start one of the favorites saved in the Sonos app, e.g.
+//! `play?room=Kitchen&favorite=Jazz`.
+
+use std::fmt;
+
+use crate::stream_deck::error::StreamDeckError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Previous,
+    RestartOrPrevious,
+    FadeOut,
+    /// Pauses every room, like a long press on play/pause.
+    PauseAll,
+    /// Makes the room the active one, which needs a room.
+    SelectRoom,
+}
+
+impl Command {
+    const ALL: [Command; 9] = [
+        Command::Play,
+        Command::Pause,
+        Command::PlayPause,
+        Command::Next,
+        Command::Previous,
+        Command::RestartOrPrevious,
+        Command::FadeOut,
+        Command::PauseAll,
+        Command::SelectRoom,
+    ];
+
+    /// What the command is called in links.
+    pub fn name(self) -> &'static str {
+        match self {
+            Command::Play => "play",
+            Command::Pause => "pause",
+            Command::PlayPause => "play-pause",
+            Command::Next => "next",
+            Command::Previous => "previous",
+            Command::RestartOrPrevious => "restart-or-previous",
+            Command::FadeOut => "fade-out",
+            Command::PauseAll => "pause-all",
+            Command::SelectRoom => "select-room",
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeepLink {
+    pub command: Command,
+    /// `None` for the active room.
+    pub room: Option<String>,
+    /// What to play instead of whatever was playing, only for [`Command::Play`].
+    pub favorite: Option<String>,
+}
+
+impl DeepLink {
+    /// Reads the part of a link after the plugin's UUID, like `/play?room=Kitchen`. Anything
+    /// we don't understand is an error rather than ignored, so a typo doesn't quietly do
+    /// something else, like play in the wrong room. Fragments are only for whoever made the
+    /// link, and are ignored.
+    pub fn parse(url: &str) -> Result<Self, StreamDeckError> {
+        let invalid = |reason: String| StreamDeckError::InvalidArguments(reason);
+        let url = url.split_once('#').map_or(url, |(url, _)| url);
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let name = path.trim_matches('/');
+        let command = Command::ALL
+            .into_iter()
+            .find(|command| command.name() == name)
+            .ok_or_else(|| invalid(format!("no command called {name:?}")))?;
+
+        let (mut room, mut favorite) = (None, None);
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            let value = Some(value.trim().to_string()).filter(|value| !value.is_empty());
+            match &*key {
+                "room" => room = value,
+                "favorite" if command == Command::Play => favorite = value,
+                _ => return Err(invalid(format!("{command} doesn't take {key:?}"))),
+            }
+        }
+        match (command, &room) {
+            (Command::SelectRoom, None) => Err(invalid(format!("{command} needs a room"))),
+            (Command::PauseAll, Some(_)) => Err(invalid(format!("{command} is for every room"))),
+            _ => Ok(Self {
+                command,
+                room,
+                favorite,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(url: &str) -> Option<DeepLink> {
+        DeepLink::parse(url).ok()
+    }
+
+    #[test]
+    fn reads_commands_and_rooms() {
+        assert_eq!(
+            parse("/play-pause"),
+            Some(DeepLink {
+                command: Command::PlayPause,
+                room: None,
+                favorite: None,
+            })
+        );
+        assert_eq!(
+            parse("next/?room=Living%20Room"),
+            Some(DeepLink {
+                command: Command::Next,
+                room: Some("Living Room".to_string()),
+                favorite: None,
+            })
+        );
+        assert_eq!(
+            parse("/select-room?room=Kids+Room").and_then(|link| link.room),
+            Some("Kids Room".to_string())
+        );
+        for command in Command::ALL {
+            let room = if command == Command::PauseAll {
+                ""
+            } else {
+                "?room=Office"
+            };
+            assert_eq!(
+                parse(&format!("/{command}{room}")).map(|link| link.command),
+                Some(command)
+            );
+        }
+    }
+
+    #[test]
+    fn reads_favorites() {
+        assert_eq!(
+            parse("/play?room=Kitchen&favorite=Jazz"),
+            Some(DeepLink {
+                command: Command::Play,
+                room: Some("Kitchen".to_string()),
+                favorite: Some("Jazz".to_string()),
+            })
+        );
+        assert_eq!(
+            parse("/play?favorite=Road%20Trip#from-my-script").and_then(|link| link.favorite),
+            Some("Road Trip".to_string())
+        );
+        assert_eq!(
+            parse("/next#favorite=Jazz").map(|link| link.command),
+            Some(Command::Next)
+        );
+    }
+
+    #[test]
+    fn refuses_what_it_does_not_understand() {
+        assert_eq!(parse("/"), None);
+        assert_eq!(parse("/stop"), None);
+        assert_eq!(parse("/play?room=Kitchen&volume=10"), None);
+        assert_eq!(parse("/next?favorite=Jazz"), None);
+        assert_eq!(parse("/select-room"), None);
+        assert_eq!(parse("/pause-all?room=Kitchen"), None);
+    }
+}
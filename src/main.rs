@@ -1,93 +1,162 @@
-use crate::stream_deck::error::StreamDeckError;
-use crate::stream_deck::ReceiveEvent;
-use log::{debug, info, warn};
+use controller::SonosHandler;
+use log::{debug, error, info, warn, LevelFilter};
+use log4rs::append::file::FileAppender;
+use log4rs::config::{Appender, Config, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use player::audit::Audited;
 use sonos::Zone;
-use std::{env, time::Duration};
-use stream_deck::handler::{Connection, Handler};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use std::{env, fs, thread};
+use stream_deck::handler::Handler;
+use stream_deck::launch::LaunchArgs;
+use stream_deck::middleware::{Feedback, Latency};
 
+mod actions;
+mod controller;
+mod deep_link;
+mod player;
+mod render;
+mod scheduler;
 pub mod sonos;
-#[macro_use]
 pub(crate) mod stream_deck;
 
-struct SonosHandler {
-    zone: Option<Zone>,
-}
-
-// TODO: Extract
-#[derive(Debug, Clone, Copy)]
-pub enum Action {
-    PlayPause,
-}
+/// Where logging is configured, relative to the *.sdPlugin directory.
+const LOG_CONFIG_PATH: &str = "log4rs.yml";
+/// Where we log when `log4rs.yml` is missing or broken, same as its default.
+const FALLBACK_LOG_PATH: &str = "logs/sonos-controller.log";
 
-action_names!(Action => {
-    "sh.viora.controller-for-sonos.play-pause" => Action::PlayPause
-});
+/// What also goes to the Stream Deck's log; everything else only to ours.
+const STREAM_DECK_LOG_LEVEL: LevelFilter = LevelFilter::Warn;
 
-impl Handler<Action> for SonosHandler {
-    async fn handle(
-        &self,
-        _connection: &Connection,
-        event: &ReceiveEvent<Action>,
-    ) -> Result<(), StreamDeckError> {
-        match event {
-            ReceiveEvent::KeyUp { action, .. } => self.action(action).await,
-            _ => Ok(()),
-        }
-    }
-}
-
-impl SonosHandler {
-    async fn new() -> Self {
-        let zones = Zone::get_zones(Duration::from_secs(5)).await;
-        if let Ok(mut zones) = zones {
-            if let Some(found_zone) = zones.pop() {
-                info!("found zone: {}", found_zone.name());
-                return Self {
-                    zone: Some(found_zone),
-                };
+#[tokio::main(flavor = "current_thread")] // no need for multithreading, keep it simple
+async fn main() {
+    // `cargo make manifest` writes the plugin's manifest.json with this
+    if env::args().nth(1).as_deref() == Some("--manifest") {
+        match serde_json::to_string_pretty(&actions::Registry::<Zone>::new().manifest()) {
+            Ok(manifest) => println!("{manifest}"),
+            Err(e) => {
+                eprintln!("could not write the manifest: {e}");
+                std::process::exit(1);
             }
         }
-        warn!("no sonos zones found");
-        return Self { zone: None };
+        return;
     }
 
-    async fn action(&self, action: &Action) -> Result<(), StreamDeckError> {
-        match action {
-            Action::PlayPause => self.play_pause().await,
+    init_logging();
+    log_panics::init();
+
+    info!("starting plugin");
+    debug!("pid: {}", std::process::id()); // useful to `kill` the process so the SD app restarts it
+
+    let args = match LaunchArgs::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(2);
         }
+    };
+    debug!(
+        "port: {}, uuid: {}, registerEvent: {}",
+        args.port, args.plugin_uuid, args.register_event
+    );
+    let application = &args.info.application;
+    if !application.version.is_empty() {
+        info!(
+            "Stream Deck {} on {:?} {} ({}), {} device(s)",
+            application.version,
+            application.platform,
+            application.platform_version,
+            application.language,
+            args.info.devices.len()
+        );
     }
 
-    async fn play_pause(&self) -> Result<(), StreamDeckError> {
-        if let Some(zone) = &self.zone {
-            zone.play_pause()
-                .await
-                .map_err(|e| StreamDeckError::HandlerFailed(e.to_string()))
-        } else {
-            warn!("no zone detected");
-            Ok(())
-        }
+    let handler = SonosHandler::<Audited<Zone>>::new()
+        .with(Feedback)
+        .with(Latency::default());
+    if let Err(e) = stream_deck::plumbing::run(args, handler).await {
+        error!("plugin failed: {e}");
+        std::process::exit(1);
     }
-}
 
-#[tokio::main(flavor = "current_thread")] // no need for multithreading, keep it simple
-async fn main() {
-    // Log directly to a file as we can't read stdout/stderr from the Stream Deck app
-    // log4rs.yml should be in the *.sdPlugin directory
-    log4rs::init_file("log4rs.yml", Default::default()).unwrap();
-    log_panics::init();
+    info!("plugin shutting down -- goodbye!");
+}
 
-    info!("starting plugin");
-    debug!("pid: {}", std::process::id()); // useful to `kill` the process so the SD app restarts it
+/// Log directly to a file as we can't read stdout/stderr from the Stream Deck app, and warnings
+/// and errors to the Stream Deck's log as well.
+/// log4rs.yml should be in the *.sdPlugin directory; without it we still log to a file.
+/// Changes to it are picked up every `refresh_rate`, if it has one.
+fn init_logging() {
+    let (config, problem) =
+        match log4rs::config::load_config_file(LOG_CONFIG_PATH, Default::default()) {
+            Ok(config) => (config, None),
+            Err(e) => match fallback_config() {
+                Ok(config) => (config, Some(e)),
+                Err(fallback_error) => {
+                    eprintln!("could not set up logging: {e}, {fallback_error}");
+                    return;
+                }
+            },
+        };
+    if let Err(e) = stream_deck::logger::init(config, STREAM_DECK_LOG_LEVEL) {
+        eprintln!("could not set up logging: {e}");
+        return;
+    }
+    match problem {
+        Some(e) => warn!("could not load log4rs.yml, logging to {FALLBACK_LOG_PATH}: {e}"),
+        None => watch_log_config(Path::new(LOG_CONFIG_PATH)),
+    }
+}
 
-    // really quick and dirty argument parsing - should probably use `clap` later
-    let args: Vec<String> = env::args().collect();
-    let port: u16 = args[2].parse().unwrap();
-    let uuid = &args[4];
-    let register_event = &args[6];
-    debug!("port: {port}, uuid: {uuid}, registerEvent: {register_event}");
+/// Reloads the logging configuration whenever the file changes, like log4rs does for loggers
+/// it installs itself. Stops once the file no longer has a `refresh_rate`.
+fn watch_log_config(path: &'static Path) {
+    let Some(mut rate) = refresh_rate(path) else {
+        return;
+    };
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last: Option<SystemTime> = modified(path);
+    let watching = thread::Builder::new()
+        .name("log config".to_string())
+        .spawn(move || loop {
+            thread::sleep(rate);
+            let now = modified(path);
+            if now == last {
+                continue;
+            }
+            last = now;
+            match log4rs::config::load_config_file(path, Default::default()) {
+                Ok(config) => {
+                    stream_deck::logger::reload(config);
+                    info!("reloaded {}", path.display());
+                }
+                Err(e) => warn!("could not reload {}: {e}", path.display()),
+            }
+            match refresh_rate(path) {
+                Some(new) => rate = new,
+                None => break,
+            }
+        });
+    if let Err(e) = watching {
+        warn!("not watching {} for changes: {e}", path.display());
+    }
+}
 
-    let handler = SonosHandler::new().await;
-    stream_deck::plumbing::run(port, uuid, handler).await;
+fn refresh_rate(path: &Path) -> Option<Duration> {
+    let source = fs::read_to_string(path).ok()?;
+    serde_yaml::from_str::<log4rs::config::RawConfig>(&source)
+        .ok()?
+        .refresh_rate()
+}
 
-    info!("plugin shutting down -- goodbye!");
+fn fallback_config() -> Result<Config, String> {
+    let file = FileAppender::builder()
+        .encoder(Box::new(PatternEncoder::new("{d} - {m}{n}")))
+        .build(FALLBACK_LOG_PATH)
+        .map_err(|e| e.to_string())?;
+    Config::builder()
+        .appender(Appender::builder().build("logfile", Box::new(file)))
+        .build(Root::builder().appender("logfile").build(LevelFilter::Info))
+        .map_err(|e| e.to_string())
 }
@@ -0,0 +1,205 @@
+//! Writes a line of JSON for every command sent to a player, with what it was told to do,
+//! whether that worked and how long it took. Keys, gestures and deep links all end up here, so
+//! the audit log has them all, and only what actually reached a speaker.
+
+use futures::{Stream, TryStreamExt};
+use log::info;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use super::{Capabilities, Player};
+use crate::sonos::{AVTransportState, ControllerError, PositionInfo, Volume};
+
+/// Where audit lines go, so log4rs.yml can send them to a file of their own.
+pub const AUDIT_TARGET: &str = "audit";
+
+/// A player that audits the commands it's given. Asking it things, like its state, isn't
+/// audited.
+#[derive(Debug, Clone)]
+pub struct Audited<P>(P);
+
+impl<P: Player> Audited<P> {
+    /// Runs one command on the player, and writes its audit line once it's done.
+    async fn audit<T>(
+        &self,
+        command: &str,
+        args: Value,
+        run: impl Future<Output = Result<T, ControllerError>>,
+    ) -> Result<T, ControllerError> {
+        let start = Instant::now();
+        let result = run.await;
+        let error = result.as_ref().err();
+        let line = audit_line(self.0.name(), command, args, error, start.elapsed());
+        info!(target: AUDIT_TARGET, "{line}");
+        result
+    }
+}
+
+fn audit_line(
+    room: &str,
+    command: &str,
+    args: Value,
+    error: Option<&ControllerError>,
+    elapsed: Duration,
+) -> Value {
+    json!({
+        "room": room,
+        "command": command,
+        "args": args,
+        "ok": error.is_none(),
+        "error": error.map(ToString::to_string),
+        "ms": elapsed.as_millis() as u64,
+    })
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+impl<P: Player> Player for Audited<P> {
+    async fn discover(
+        timeout: Duration,
+    ) -> Result<impl Stream<Item = Result<Self, ControllerError>> + Send, ControllerError> {
+        Ok(P::discover(timeout).await?.map_ok(Audited))
+    }
+
+    async fn from_location(location: &str) -> Result<Option<Self>, ControllerError> {
+        Ok(P::from_location(location).await?.map(Audited))
+    }
+
+    fn location_for_address(address: &str) -> String {
+        P::location_for_address(address)
+    }
+
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn uuid(&self) -> &str {
+        self.0.uuid()
+    }
+
+    fn location(&self) -> String {
+        self.0.location()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.0.capabilities()
+    }
+
+    async fn play(&self) -> Result<(), ControllerError> {
+        self.audit("play", json!({}), self.0.play()).await
+    }
+
+    async fn pause(&self) -> Result<(), ControllerError> {
+        self.audit("pause", json!({}), self.0.pause()).await
+    }
+
+    async fn next(&self) -> Result<(), ControllerError> {
+        self.audit("next", json!({}), self.0.next()).await
+    }
+
+    async fn previous(&self) -> Result<(), ControllerError> {
+        self.audit("previous", json!({}), self.0.previous()).await
+    }
+
+    async fn seek(&self, position: Duration) -> Result<(), ControllerError> {
+        let args = json!({ "position_ms": millis(position) });
+        self.audit("seek", args, self.0.seek(position)).await
+    }
+
+    async fn get_state(&self) -> Result<AVTransportState, ControllerError> {
+        self.0.get_state().await
+    }
+
+    async fn get_position(&self) -> Result<PositionInfo, ControllerError> {
+        self.0.get_position().await
+    }
+
+    async fn get_volume(&self) -> Result<Volume, ControllerError> {
+        self.0.get_volume().await
+    }
+
+    async fn get_sleep_timer(&self) -> Result<Option<Duration>, ControllerError> {
+        self.0.get_sleep_timer().await
+    }
+
+    async fn set_volume(&self, volume: &Volume) -> Result<(), ControllerError> {
+        let args = json!({ "volume": volume.value() });
+        self.audit("set_volume", args, self.0.set_volume(volume))
+            .await
+    }
+
+    async fn play_favorite(&self, name: &str) -> Result<(), ControllerError> {
+        let args = json!({ "favorite": name });
+        self.audit("play_favorite", args, self.0.play_favorite(name))
+            .await
+    }
+
+    async fn watch_transport_state(
+        &self,
+        states: mpsc::Sender<AVTransportState>,
+    ) -> Result<(), ControllerError> {
+        self.0.watch_transport_state(states).await
+    }
+
+    // the rest run as one command on the player itself, rather than as the commands they're
+    // made of, so each gets one line and the player's own way of doing them
+
+    async fn ramp_volume(
+        &self,
+        target: &Volume,
+        duration: Duration,
+    ) -> Result<(), ControllerError> {
+        let args = json!({ "volume": target.value(), "duration_ms": millis(duration) });
+        self.audit("ramp_volume", args, self.0.ramp_volume(target, duration))
+            .await
+    }
+
+    async fn play_pause(&self) -> Result<(), ControllerError> {
+        self.audit("play_pause", json!({}), self.0.play_pause())
+            .await
+    }
+
+    async fn restart_or_previous(&self, threshold: Duration) -> Result<(), ControllerError> {
+        let args = json!({ "threshold_ms": millis(threshold) });
+        let run = self.0.restart_or_previous(threshold);
+        self.audit("restart_or_previous", args, run).await
+    }
+
+    async fn fade_out_and_pause(
+        &self,
+        duration: Duration,
+        stop: impl Future<Output = ()> + Send,
+    ) -> Result<(), ControllerError> {
+        let args = json!({ "duration_ms": millis(duration) });
+        let run = self.0.fade_out_and_pause(duration, stop);
+        self.audit("fade_out_and_pause", args, run).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_lines_are_structured() {
+        let error = ControllerError::TransitionNotAvailable("Next".to_string());
+        let line = audit_line(
+            "Kitchen",
+            "next",
+            json!({}),
+            Some(&error),
+            Duration::from_millis(12),
+        );
+        assert_eq!(
+            line,
+            json!({
+                "room": "Kitchen", "command": "next", "args": {}, "ok": false,
+                "error": error.to_string(), "ms": 12,
+            })
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! An in-memory player for handler tests.
+
+use futures::{stream, Stream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::{Capabilities, Player};
+use crate::sonos::{AVTransportState, ControllerError, PositionInfo, Volume};
+
+#[derive(Debug, Clone)]
+pub struct MockPlayer {
+    name: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+/// What the mock plays, and what was done to it. Tests can change it directly.
+#[derive(Debug)]
+pub struct MockState {
+    pub transport: AVTransportState,
+    pub volume: u8,
+    /// 1-based, like UPnP counts tracks.
+    pub track: u32,
+    pub tracks: u32,
+    pub elapsed: Duration,
+    /// What every track is called.
+    pub title: String,
+    /// The favorites there are to play, and the one played last.
+    pub favorites: Vec<String>,
+    pub favorite: Option<String>,
+    pub capabilities: Capabilities,
+    pub sleep_timer: Option<Duration>,
+    /// Makes questions go unanswered, like a speaker that was unplugged.
+    pub stalled: bool,
+    /// Makes the next call fail with this error.
+    pub fail: Option<ControllerError>,
+    /// Every call so far, in order.
+    pub calls: Vec<&'static str>,
+    watchers: Vec<mpsc::Sender<AVTransportState>>,
+}
+
+impl MockPlayer {
+    /// A paused player at the start of the first of three tracks.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            state: Arc::new(Mutex::new(MockState {
+                transport: AVTransportState::Paused,
+                volume: 20,
+                track: 1,
+                tracks: 3,
+                elapsed: Duration::ZERO,
+                title: "A Song".to_string(),
+                favorites: vec!["Jazz".to_string()],
+                favorite: None,
+                capabilities: Capabilities::ALL,
+                sleep_timer: None,
+                stalled: false,
+                fail: None,
+                calls: vec![],
+                watchers: vec![],
+            })),
+        }
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Changes the transport state as if someone used another app, and tells whoever is
+    /// watching.
+    pub fn change_transport(&self, transport: AVTransportState) {
+        let mut state = self.state();
+        state.transport = transport;
+        state
+            .watchers
+            .retain(|watcher| watcher.try_send(transport).is_ok());
+    }
+
+    /// Never returns if the player is stalled.
+    async fn answer(&self) {
+        if self.state().stalled {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Records the call and takes the error it should fail with, if any.
+    fn call(&self, name: &'static str) -> Result<MutexGuard<'_, MockState>, ControllerError> {
+        let mut state = self.state();
+        state.calls.push(name);
+        match state.fail.take() {
+            Some(e) => Err(e),
+            None => Ok(state),
+        }
+    }
+}
+
+impl Player for MockPlayer {
+    async fn discover(
+        _timeout: Duration,
+    ) -> Result<impl Stream<Item = Result<Self, ControllerError>> + Send, ControllerError> {
+        Ok(stream::empty())
+    }
+
+    async fn from_location(_location: &str) -> Result<Option<Self>, ControllerError> {
+        Ok(None)
+    }
+
+    fn location_for_address(address: &str) -> String {
+        format!("mock://{address}")
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn uuid(&self) -> &str {
+        &self.name
+    }
+
+    fn location(&self) -> String {
+        Self::location_for_address(&self.name)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.state().capabilities
+    }
+
+    async fn play(&self) -> Result<(), ControllerError> {
+        self.call("play")?.transport = AVTransportState::Playing;
+        Ok(())
+    }
+
+    async fn pause(&self) -> Result<(), ControllerError> {
+        self.call("pause")?.transport = AVTransportState::Paused;
+        Ok(())
+    }
+
+    async fn next(&self) -> Result<(), ControllerError> {
+        let mut state = self.call("next")?;
+        if !state.capabilities.skip || state.track >= state.tracks {
+            return Err(ControllerError::TransitionNotAvailable("Next".to_string()));
+        }
+        state.track += 1;
+        state.elapsed = Duration::ZERO;
+        Ok(())
+    }
+
+    async fn previous(&self) -> Result<(), ControllerError> {
+        let mut state = self.call("previous")?;
+        if !state.capabilities.skip || state.track <= 1 {
+            return Err(ControllerError::TransitionNotAvailable(
+                "Previous".to_string(),
+            ));
+        }
+        state.track -= 1;
+        state.elapsed = Duration::ZERO;
+        Ok(())
+    }
+
+    async fn seek(&self, position: Duration) -> Result<(), ControllerError> {
+        let mut state = self.call("seek")?;
+        if !state.capabilities.seek {
+            return Err(ControllerError::Unsupported("Seek".to_string()));
+        }
+        state.elapsed = position;
+        Ok(())
+    }
+
+    async fn get_state(&self) -> Result<AVTransportState, ControllerError> {
+        self.answer().await;
+        Ok(self.call("get_state")?.transport)
+    }
+
+    async fn get_position(&self) -> Result<PositionInfo, ControllerError> {
+        self.answer().await;
+        let state = self.call("get_position")?;
+        Ok(PositionInfo {
+            track: state.track,
+            duration: None,
+            elapsed: Some(state.elapsed),
+            title: Some(state.title.clone()),
+            artist: None,
+        })
+    }
+
+    async fn get_volume(&self) -> Result<Volume, ControllerError> {
+        self.answer().await;
+        Ok(Volume::new(self.call("get_volume")?.volume))
+    }
+
+    async fn get_sleep_timer(&self) -> Result<Option<Duration>, ControllerError> {
+        self.answer().await;
+        Ok(self.call("get_sleep_timer")?.sleep_timer)
+    }
+
+    async fn set_volume(&self, volume: &Volume) -> Result<(), ControllerError> {
+        self.call("set_volume")?.volume = volume.value();
+        Ok(())
+    }
+
+    async fn play_favorite(&self, name: &str) -> Result<(), ControllerError> {
+        let mut state = self.call("play_favorite")?;
+        let Some(favorite) = state
+            .favorites
+            .iter()
+            .find(|favorite| favorite.eq_ignore_ascii_case(name))
+            .cloned()
+        else {
+            return Err(ControllerError::NoFavorite(name.to_string()));
+        };
+        state.favorite = Some(favorite);
+        state.transport = AVTransportState::Playing;
+        Ok(())
+    }
+
+    async fn watch_transport_state(
+        &self,
+        states: mpsc::Sender<AVTransportState>,
+    ) -> Result<(), ControllerError> {
+        self.state().watchers.push(states.clone());
+        states.closed().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+use futures::Stream;
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::sonos::{AVTransportState, ControllerError, PositionInfo, Volume};
+
+pub mod audit;
+#[cfg(test)]
+pub mod mock;
+
+/// Don't flood the player with requests when stepping the volume by hand.
+const MIN_VOLUME_STEP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a player can do beyond play and pause, so keys can do the next best thing instead
+/// of failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Skipping to the next or previous track.
+    pub skip: bool,
+    pub seek: bool,
+    pub volume: bool,
+    /// Telling us when the transport state changes.
+    pub events: bool,
+}
+
+impl Capabilities {
+    pub const ALL: Self = Self {
+        skip: true,
+        seek: true,
+        volume: true,
+        events: true,
+    };
+}
+
+/// Something that plays music in a room and that keys can control, like a Sonos zone.
+///
+/// The methods return `Send` futures so the handler can drive players from spawned tasks.
+pub trait Player: Clone + Debug + Send + Sync + 'static {
+    /// Searches the network for players, yielding each one as soon as it answers.
+    fn discover(
+        timeout: Duration,
+    ) -> impl Future<
+        Output = Result<impl Stream<Item = Result<Self, ControllerError>> + Send, ControllerError>,
+    > + Send;
+
+    /// Builds a player from the URL of its device description. Returns `None` if the device
+    /// is there but isn't a player we can use.
+    fn from_location(
+        location: &str,
+    ) -> impl Future<Output = Result<Option<Self>, ControllerError>> + Send;
+
+    /// The device description URL for an address entered by hand.
+    fn location_for_address(address: &str) -> String;
+
+    /// The room the player is in, which is how keys refer to it.
+    fn name(&self) -> &str;
+
+    /// Stays the same when the player's address changes.
+    fn uuid(&self) -> &str;
+
+    /// Where the device description lives, which identifies the player on the network.
+    fn location(&self) -> String;
+
+    fn capabilities(&self) -> Capabilities;
+
+    fn play(&self) -> impl Future<Output = Result<(), ControllerError>> + Send;
+
+    fn pause(&self) -> impl Future<Output = Result<(), ControllerError>> + Send;
+
+    fn next(&self) -> impl Future<Output = Result<(), ControllerError>> + Send;
+
+    fn previous(&self) -> impl Future<Output = Result<(), ControllerError>> + Send;
+
+    fn seek(&self, position: Duration) -> impl Future<Output = Result<(), ControllerError>> + Send;
+
+    fn get_state(&self) -> impl Future<Output = Result<AVTransportState, ControllerError>> + Send;
+
+    fn get_position(&self) -> impl Future<Output = Result<PositionInfo, ControllerError>> + Send;
+
+    fn get_volume(&self) -> impl Future<Output = Result<Volume, ControllerError>> + Send;
+
+    fn set_volume(
+        &self,
+        volume: &Volume,
+    ) -> impl Future<Output = Result<(), ControllerError>> + Send;
+
+    /// How long until the sleep timer pauses the player, or `None` if it isn't set.
+    fn get_sleep_timer(
+        &self,
+    ) -> impl Future<Output = Result<Option<Duration>, ControllerError>> + Send;
+
+    /// Plays one of the favorites saved in the Sonos app, found by name regardless of case.
+    fn play_favorite(&self, name: &str)
+        -> impl Future<Output = Result<(), ControllerError>> + Send;
+
+    /// Forwards every transport state change to `states`, until the receiving end is dropped
+    /// or watching breaks down.
+    fn watch_transport_state(
+        &self,
+        states: mpsc::Sender<AVTransportState>,
+    ) -> impl Future<Output = Result<(), ControllerError>> + Send;
+
+    /// Moves the volume to `target` over about `duration` and returns once the ramp is done.
+    /// Steps the volume by hand unless the player knows better.
+    fn ramp_volume(
+        &self,
+        target: &Volume,
+        duration: Duration,
+    ) -> impl Future<Output = Result<(), ControllerError>> + Send {
+        async move {
+            let current = self.get_volume().await?;
+            step_volume(self, &current, target, duration).await
+        }
+    }
+
+    fn play_pause(&self) -> impl Future<Output = Result<(), ControllerError>> + Send {
+        async move {
+            match self.get_state().await? {
+                AVTransportState::Paused | AVTransportState::Stopped => self.play().await,
+                AVTransportState::Playing | AVTransportState::Transitioning => self.pause().await,
+            }
+        }
+    }
+
+    /// Restarts the current track, or skips to the previous one if we're still within
+    /// `threshold` of its start, like the previous button on most players. Players that can't
+    /// seek always skip.
+    fn restart_or_previous(
+        &self,
+        threshold: Duration,
+    ) -> impl Future<Output = Result<(), ControllerError>> + Send {
+        async move {
+            if !self.capabilities().seek {
+                return self.previous().await;
+            }
+            match self.get_position().await?.elapsed {
+                Some(elapsed) if elapsed > threshold => self.seek(Duration::ZERO).await,
+                _ => self.previous().await,
+            }
+        }
+    }
+
+    /// Fades the volume out, pauses, and then puts the original volume back so the next
+    /// play doesn't start silent. Players without volume control just pause. Once `stop`
+    /// resolves, e.g. on shutdown, the fade is cut short and goes straight to pausing.
+    ///
+    /// Dropping the future halfway leaves the volume down, so callers that may stop waiting
+    /// should run it as a task of its own, see [`Connection::run_tracked`].
+    ///
+    /// [`Connection::run_tracked`]: crate::stream_deck::handler::Connection::run_tracked
+    fn fade_out_and_pause(
+        &self,
+        duration: Duration,
+        stop: impl Future<Output = ()> + Send,
+    ) -> impl Future<Output = Result<(), ControllerError>> + Send {
+        async move {
+            if !matches!(
+                self.get_state().await?,
+                AVTransportState::Playing | AVTransportState::Transitioning
+            ) {
+                return Ok(());
+            }
+            if !self.capabilities().volume {
+                return self.pause().await;
+            }
+
+            let original = self.get_volume().await?;
+            let silent = Volume::new(0);
+            let faded = async {
+                tokio::select! {
+                    ramped = self.ramp_volume(&silent, duration) => ramped?,
+                    _ = stop => {}
+                }
+                self.pause().await
+            }
+            .await;
+
+            // restore the volume even if the fade failed halfway through
+            let restored = self.set_volume(&original).await;
+            faded.and(restored)
+        }
+    }
+}
+
+/// Steps the volume from `from` to `to` over about `duration`, for players that can't ramp
+/// by themselves.
+pub async fn step_volume<P: Player>(
+    player: &P,
+    from: &Volume,
+    to: &Volume,
+    duration: Duration,
+) -> Result<(), ControllerError> {
+    let distance = to.value() as i32 - from.value() as i32;
+    let max_steps = (duration.as_millis() / MIN_VOLUME_STEP_INTERVAL.as_millis()) as i32;
+    let steps = distance.abs().min(max_steps).max(1);
+
+    for step in 1..=steps {
+        let volume = from.value() as i32 + distance * step / steps;
+        player.set_volume(&Volume::new(volume as u8)).await?;
+        if step < steps {
+            tokio::time::sleep(duration / steps as u32).await;
+        }
+    }
+    Ok(())
+}
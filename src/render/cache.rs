@@ -0,0 +1,122 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+
+use super::Canvas;
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::handler::Connection;
+
+/// Rendered images to keep around for other keys showing the same thing.
+const MAX_RENDERED: usize = 64;
+
+/// Sends key images, skipping those a key already shows and only drawing each image once.
+/// Images are told apart by a hash of their content.
+#[derive(Debug, Default)]
+pub struct Images {
+    /// What each key shows, by context.
+    shown: Mutex<HashMap<String, u64>>,
+    rendered: Mutex<HashMap<u64, Arc<str>>>,
+}
+
+impl Images {
+    pub async fn show(
+        &self,
+        connection: &Connection,
+        context: &str,
+        canvas: &Canvas,
+        pixels: u32,
+    ) -> Result<(), StreamDeckError> {
+        let svg = canvas.to_svg();
+        let hash = content_hash(&svg, pixels);
+        if self.shown.lock().unwrap().get(context) == Some(&hash) {
+            return Ok(());
+        }
+
+        let image = self.rendered.lock().unwrap().get(&hash).cloned();
+        let image = match image {
+            Some(image) => image,
+            None => {
+                let image: Arc<str> = canvas.to_data_url(pixels)?.into();
+                debug!(
+                    "drew a {pixels}px image for {context}, {} bytes",
+                    image.len()
+                );
+                let mut rendered = self.rendered.lock().unwrap();
+                if rendered.len() >= MAX_RENDERED {
+                    rendered.clear();
+                }
+                rendered.insert(hash, image.clone());
+                image
+            }
+        };
+
+        connection.set_image(context, &image).await?;
+        self.shown.lock().unwrap().insert(context.to_string(), hash);
+        Ok(())
+    }
+
+    /// Puts the image from the manifest back on a key we drew on. Does nothing for keys that
+    /// already show it.
+    pub async fn clear(
+        &self,
+        connection: &Connection,
+        context: &str,
+    ) -> Result<(), StreamDeckError> {
+        if !self.shown.lock().unwrap().contains_key(context) {
+            return Ok(());
+        }
+        connection.set_image(context, "").await?;
+        self.forget(context);
+        Ok(())
+    }
+
+    /// Forgets what a key shows, e.g. when it went away and the app shows the manifest's
+    /// image again once it's back.
+    pub fn forget(&self, context: &str) {
+        self.shown.lock().unwrap().remove(context);
+    }
+}
+
+fn content_hash(svg: &str, pixels: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    svg.hash(&mut hasher);
+    pixels.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::render::{Color, Layer};
+    use crate::stream_deck::SendEvent;
+
+    #[tokio::test]
+    async fn sends_only_what_changed() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let connection = Connection::new(tx, "plugin", Default::default());
+        let images = Images::default();
+        let red = Canvas::new().with(Layer::Fill(Color(255, 0, 0)));
+        let blue = Canvas::new().with(Layer::Fill(Color(0, 0, 255)));
+
+        for (context, canvas) in [("a", &red), ("a", &red), ("b", &red), ("a", &blue)] {
+            images.show(&connection, context, canvas, 72).await.unwrap();
+        }
+        images.forget("b");
+        images.show(&connection, "b", &red, 72).await.unwrap();
+        drop(connection);
+
+        let mut sent = vec![];
+        while let Some(SendEvent::SetImage { context, payload }) = rx.recv().await {
+            sent.push((context, payload.image));
+        }
+        let contexts: Vec<&str> = sent.iter().map(|(context, _)| context.as_str()).collect();
+        assert_eq!(contexts, ["a", "b", "a", "b"]);
+        assert_eq!(sent[0].1, sent[1].1, "drawn once, sent to both");
+        assert!(sent[0].1.starts_with("data:image/png;base64,"));
+    }
+}
@@ -0,0 +1,294 @@
+//! Scrolls titles that don't fit on a key, a character at a time, pausing at either end so
+//! they can be read. The app draws titles itself in the user's font, size and alignment, so
+//! all we send is which part of each line to show.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::error;
+
+use crate::stream_deck::error::StreamDeckError;
+use crate::stream_deck::handler::Connection;
+use crate::stream_deck::payload::TitleParameters;
+
+/// How long each step of the scroll is shown.
+const STEP: Duration = Duration::from_millis(300);
+/// How many steps a line rests at either end.
+const PAUSE_STEPS: usize = 6;
+/// Roughly how much of the key's width titles get, and how wide the app's font is for its
+/// size. Erring on the narrow side only means scrolling a little more than needed.
+const TITLE_WIDTH: f32 = 64.0;
+const CHAR_WIDTH: f32 = 0.6;
+
+/// How many characters of a line fit on a key at the given font size.
+pub fn fits(font_size: f32) -> usize {
+    ((TITLE_WIDTH / (font_size.max(1.0) * CHAR_WIDTH)) as usize).max(1)
+}
+
+/// A title, each line of which scrolls back and forth on its own if it's too long.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marquee {
+    lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Line {
+    chars: Vec<char>,
+    fits: usize,
+}
+
+impl Line {
+    /// How many characters don't fit.
+    fn overflow(&self) -> usize {
+        self.chars.len().saturating_sub(self.fits)
+    }
+
+    /// Rests at the start, scrolls to the end, rests there and scrolls back, over and over.
+    fn at(&self, step: usize) -> String {
+        let overflow = self.overflow();
+        if overflow == 0 {
+            return self.chars.iter().collect();
+        }
+        let step = step % (2 * (PAUSE_STEPS + overflow));
+        let offset = if step < PAUSE_STEPS {
+            0
+        } else if step < PAUSE_STEPS + overflow {
+            step - PAUSE_STEPS + 1
+        } else if step < 2 * PAUSE_STEPS + overflow {
+            overflow
+        } else {
+            2 * (PAUSE_STEPS + overflow) - step - 1
+        };
+        self.chars[offset..offset + self.fits].iter().collect()
+    }
+}
+
+impl Marquee {
+    pub fn new(title: &str, fits: usize) -> Self {
+        Self {
+            lines: title
+                .lines()
+                .map(|line| Line {
+                    chars: line.chars().collect(),
+                    fits,
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether anything needs to move at all.
+    pub fn scrolls(&self) -> bool {
+        self.lines.iter().any(|line| line.overflow() > 0)
+    }
+
+    /// The title to show at the given step.
+    pub fn frame(&self, step: usize) -> String {
+        let lines: Vec<String> = self.lines.iter().map(|line| line.at(step)).collect();
+        lines.join("\n")
+    }
+}
+
+/// Sets key titles, scrolling those that are too long until the title changes or the key
+/// goes away.
+#[derive(Debug, Default)]
+pub struct Titles {
+    /// By context. Shared with the animations, which stop once their key's entry changes.
+    keys: Arc<Mutex<HashMap<String, Title>>>,
+    /// Never goes back, so a key that's forgotten and shown again can't pick up where an old
+    /// animation left off.
+    generations: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Title {
+    text: Option<String>,
+    parameters: TitleParameters,
+    /// Changes whenever the text or parameters do, which stops the running animation.
+    generation: u64,
+}
+
+impl Titles {
+    /// Shows a title on a key. Does nothing if it already shows it, so an animation isn't
+    /// started over every time the title is looked up again.
+    pub async fn show(
+        &self,
+        connection: &Connection,
+        context: &str,
+        text: &str,
+    ) -> Result<(), StreamDeckError> {
+        {
+            let mut keys = self.keys.lock().unwrap();
+            let title = keys.entry(context.to_string()).or_default();
+            if title.text.as_deref() == Some(text) {
+                return Ok(());
+            }
+            title.text = Some(text.to_string());
+        }
+        self.start(connection, context).await
+    }
+
+    /// Remembers how the user wants the key's title to look, and starts over with it.
+    pub async fn parameters_changed(
+        &self,
+        connection: &Connection,
+        context: &str,
+        parameters: TitleParameters,
+    ) -> Result<(), StreamDeckError> {
+        {
+            let mut keys = self.keys.lock().unwrap();
+            let title = keys.entry(context.to_string()).or_default();
+            if title.parameters == parameters {
+                return Ok(());
+            }
+            title.parameters = parameters;
+        }
+        self.start(connection, context).await
+    }
+
+    /// Stops animating a key that went away.
+    pub fn forget(&self, context: &str) {
+        self.keys.lock().unwrap().remove(context);
+    }
+
+    /// Sends the first frame, and keeps scrolling in the background if there's more.
+    async fn start(&self, connection: &Connection, context: &str) -> Result<(), StreamDeckError> {
+        let (marquee, generation, animate) = {
+            let mut keys = self.keys.lock().unwrap();
+            let Some(title) = keys.get_mut(context) else {
+                return Ok(());
+            };
+            let Some(text) = &title.text else {
+                return Ok(());
+            };
+            title.generation = self.generations.fetch_add(1, Ordering::Relaxed) + 1;
+            let marquee = Marquee::new(text, fits(title.parameters.font_size));
+            // nobody sees hidden titles move
+            let animate = marquee.scrolls() && title.parameters.show_title;
+            (marquee, title.generation, animate)
+        };
+        let first = marquee.frame(0);
+        connection.set_title(context, &first).await?;
+        if !animate {
+            return Ok(());
+        }
+
+        let keys = self.keys.clone();
+        let background = connection.clone();
+        let context = context.to_string();
+        connection.spawn(async move {
+            let connection = background;
+            let mut shown = first;
+            for step in 1.. {
+                tokio::select! {
+                    _ = tokio::time::sleep(STEP) => {}
+                    _ = connection.closed() => break,
+                }
+                let current = keys.lock().unwrap().get(&context).map(|t| t.generation);
+                if current != Some(generation) {
+                    break;
+                }
+                let frame = marquee.frame(step);
+                if frame == shown {
+                    continue;
+                }
+                if let Err(e) = connection.set_title(&context, &frame).await {
+                    error!("could not scroll title: {e:?}");
+                }
+                shown = frame;
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::stream_deck::SendEvent;
+
+    #[test]
+    fn rests_at_either_end() {
+        let marquee = Marquee::new("abcdef\nxy", 4);
+        let frames: Vec<String> = (0..2 * (PAUSE_STEPS + 2))
+            .map(|step| marquee.frame(step))
+            .collect();
+
+        let at =
+            |first: &str, count| frames.iter().filter(|f| f.starts_with(first)).count() == count;
+        assert!(at("abcd\n", PAUSE_STEPS + 1));
+        assert!(at("bcde\n", 2));
+        assert!(at("cdef\n", PAUSE_STEPS + 1));
+        assert!(frames.iter().all(|frame| frame.ends_with("\nxy")));
+        assert_eq!(marquee.frame(2 * (PAUSE_STEPS + 2)), frames[0]);
+        assert!(!Marquee::new("short", 5).scrolls());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scrolls_until_the_key_goes_away() {
+        let (tx, mut rx) = mpsc::channel(64);
+        let connection = Connection::new(tx, "plugin", Default::default());
+        let titles = Titles::default();
+        let mut sent = || {
+            let mut titles = vec![];
+            while let Ok(SendEvent::SetTitle { payload, .. }) = rx.try_recv() {
+                titles.push(payload.title);
+            }
+            titles
+        };
+
+        let long = "A title much too long for any key";
+        titles.show(&connection, "key", long).await.unwrap();
+        titles.show(&connection, "key", long).await.unwrap();
+        tokio::time::sleep(STEP * (PAUSE_STEPS as u32 + 2) + STEP / 2).await;
+        let frames = sent();
+        assert_eq!(frames.len(), 4, "rests, then scrolls: {frames:?}");
+        assert!(long.starts_with(&frames[0]));
+
+        // bigger text fits less, and hidden titles stay put
+        let parameters = TitleParameters {
+            font_size: 18.0,
+            show_title: false,
+            ..Default::default()
+        };
+        titles
+            .parameters_changed(&connection, "key", parameters)
+            .await
+            .unwrap();
+        tokio::time::sleep(STEP * 20).await;
+        assert_eq!(sent(), [&long[..fits(18.0)]]);
+
+        titles.show(&connection, "key", &long[1..]).await.unwrap();
+        titles
+            .parameters_changed(&connection, "key", Default::default())
+            .await
+            .unwrap();
+        titles.forget("key");
+        sent();
+        tokio::time::sleep(STEP * 20).await;
+        assert!(sent().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keys_shown_again_scroll_once() {
+        let (tx, mut rx) = mpsc::channel(64);
+        let connection = Connection::new(tx, "plugin", Default::default());
+        let titles = Titles::default();
+
+        let long = "A title much too long for any key";
+        titles.show(&connection, "key", long).await.unwrap();
+        titles.forget("key");
+        titles.show(&connection, "key", long).await.unwrap();
+        tokio::time::sleep(STEP * (PAUSE_STEPS as u32 + 2) + STEP / 2).await;
+
+        let mut frames = 0;
+        while rx.try_recv().is_ok() {
+            frames += 1;
+        }
+        // the first frame twice, then only the second animation's
+        assert_eq!(frames, 5);
+    }
+}
@@ -0,0 +1,244 @@
+//! Draws key images from layers like text, icons, bars and album art, for keys that show more
+//! than a fixed image can. Layers are put together as SVG and rendered to PNG on the CPU, with
+//! a bundled font so keys look the same on every computer. Titles that are too long for a key
+//! scroll, see [`Titles`].
+
+use std::fmt::Write;
+use std::sync::{Arc, OnceLock};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::{self, fontdb};
+
+use crate::stream_deck::error::StreamDeckError;
+
+mod cache;
+#[allow(dead_code)] // only play/pause keys show one so far
+pub mod icons;
+mod marquee;
+#[cfg(test)]
+mod tests;
+
+pub use cache::Images;
+pub use marquee::Titles;
+
+/// Key images are drawn at one of these sizes, the larger one for keys bigger than a classic
+/// Stream Deck's and for high DPI screens. The app scales them to fit.
+pub const STANDARD: u32 = 72;
+pub const HIGH_DPI: u32 = 144;
+/// Layers are laid out on a key this many units wide and high, whatever the pixels.
+const UNITS: f32 = 72.0;
+
+const FONT: &[u8] = include_bytes!("../../assets/fonts/DejaVuSansCondensed-Bold.ttf");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Color(pub u8, pub u8, pub u8);
+
+impl Color {
+    pub const BLACK: Self = Self(0, 0, 0);
+    pub const WHITE: Self = Self(255, 255, 255);
+    pub const GRAY: Self = Self(96, 96, 96);
+    /// Behind the plugin's key images.
+    pub const BACKGROUND: Self = Self(31, 31, 31);
+    /// The orange of the plugin's icons.
+    pub const ACCENT: Self = Self(255, 153, 0);
+}
+
+impl std::fmt::Display for Color {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+}
+
+/// One thing drawn on a key, on top of what's below it. Positions and sizes are in units of
+/// a 72 unit key, `y` is where a layer's vertical center or text baseline goes.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(dead_code)] // no album art or volume keys yet
+pub enum Layer {
+    Fill(Color),
+    /// A PNG or JPEG, cropped to fill the key.
+    Art(Arc<[u8]>),
+    /// An SVG document, centered horizontally.
+    Icon {
+        svg: &'static str,
+        size: f32,
+        y: f32,
+        color: Color,
+    },
+    /// A line of text, centered horizontally.
+    Text {
+        text: String,
+        size: f32,
+        y: f32,
+        color: Color,
+    },
+    /// A horizontal bar, filled to `value` between 0 and 1.
+    Bar {
+        value: f32,
+        y: f32,
+        color: Color,
+    },
+    /// A ring around the key's edge, filled clockwise to `value` between 0 and 1.
+    Ring {
+        value: f32,
+        color: Color,
+    },
+}
+
+/// A key image, from the bottom layer up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Canvas {
+    layers: Vec<Layer>,
+}
+
+impl Canvas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, layer: Layer) -> Self {
+        self.layers.push(layer);
+        self
+    }
+
+    /// The image as an SVG document, which is also what tells images apart for caching.
+    pub fn to_svg(&self) -> String {
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{UNITS}" height="{UNITS}" viewBox="0 0 {UNITS} {UNITS}">"#
+        );
+        for layer in &self.layers {
+            // writing to a String can't fail
+            let _ = write_layer(&mut svg, layer);
+        }
+        svg.push_str("</svg>");
+        svg
+    }
+
+    /// Renders the image as a PNG, `pixels` wide and high.
+    pub fn render(&self, pixels: u32) -> Result<Vec<u8>, StreamDeckError> {
+        let failed = |e: String| StreamDeckError::ImageFailed(e);
+        let tree =
+            usvg::Tree::from_str(&self.to_svg(), options()).map_err(|e| failed(e.to_string()))?;
+        let mut pixmap =
+            Pixmap::new(pixels, pixels).ok_or_else(|| failed(format!("no {pixels}px images")))?;
+        let scale = pixels as f32 / UNITS;
+        resvg::render(
+            &tree,
+            Transform::from_scale(scale, scale),
+            &mut pixmap.as_mut(),
+        );
+        pixmap.encode_png().map_err(|e| failed(e.to_string()))
+    }
+
+    /// The image as a data URL for `setImage`.
+    pub fn to_data_url(&self, pixels: u32) -> Result<String, StreamDeckError> {
+        Ok(format!(
+            "data:image/png;base64,{}",
+            BASE64.encode(self.render(pixels)?)
+        ))
+    }
+}
+
+/// The size to draw images for a key that's `key_size` pixels on the deck.
+pub fn pixels_for(key_size: Option<u32>) -> u32 {
+    match key_size {
+        Some(size) if size <= STANDARD => STANDARD,
+        _ => HIGH_DPI,
+    }
+}
+
+fn write_layer(svg: &mut String, layer: &Layer) -> std::fmt::Result {
+    let center = UNITS / 2.0;
+    match layer {
+        Layer::Fill(color) => write!(
+            svg,
+            r#"<rect width="{UNITS}" height="{UNITS}" fill="{color}"/>"#
+        ),
+        Layer::Art(data) => {
+            // JPEG otherwise, which is what speakers mostly serve
+            let mime = if data.starts_with(b"\x89PNG") {
+                "image/png"
+            } else {
+                "image/jpeg"
+            };
+            write!(
+                svg,
+                r#"<image width="{UNITS}" height="{UNITS}" preserveAspectRatio="xMidYMid slice" href="data:{mime};base64,{}"/>"#,
+                BASE64.encode(data)
+            )
+        }
+        Layer::Icon {
+            svg: icon,
+            size,
+            y,
+            color,
+        } => {
+            // icons draw in `currentColor`, so one icon works in any color
+            let icon = icon.replace("currentColor", &color.to_string());
+            write!(
+                svg,
+                r#"<image x="{}" y="{}" width="{size}" height="{size}" href="data:image/svg+xml;base64,{}"/>"#,
+                center - size / 2.0,
+                y - size / 2.0,
+                BASE64.encode(icon)
+            )
+        }
+        Layer::Text {
+            text,
+            size,
+            y,
+            color,
+        } => write!(
+            svg,
+            r#"<text x="{center}" y="{y}" font-size="{size}" text-anchor="middle" fill="{color}">{}</text>"#,
+            escape(text)
+        ),
+        Layer::Bar { value, y, color } => {
+            let (x, width, height) = (8.0, UNITS - 16.0, 6.0);
+            let top = y - height / 2.0;
+            let filled = width * value.clamp(0.0, 1.0);
+            write!(
+                svg,
+                r#"<rect x="{x}" y="{top}" width="{width}" height="{height}" rx="3" fill="{}"/><rect x="{x}" y="{top}" width="{filled}" height="{height}" rx="3" fill="{color}"/>"#,
+                Color::GRAY
+            )
+        }
+        Layer::Ring { value, color } => {
+            let radius = center - 4.0;
+            let circumference = 2.0 * std::f32::consts::PI * radius;
+            let filled = circumference * value.clamp(0.0, 1.0);
+            write!(
+                svg,
+                r#"<circle cx="{center}" cy="{center}" r="{radius}" fill="none" stroke="{}" stroke-width="4"/><circle cx="{center}" cy="{center}" r="{radius}" fill="none" stroke="{color}" stroke-width="4" stroke-dasharray="{filled} {circumference}" transform="rotate(-90 {center} {center})"/>"#,
+                Color::GRAY
+            )
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Parsing options with only the bundled font, loaded once.
+fn options() -> &'static usvg::Options<'static> {
+    static OPTIONS: OnceLock<usvg::Options<'static>> = OnceLock::new();
+    OPTIONS.get_or_init(|| {
+        let mut fonts = fontdb::Database::new();
+        fonts.load_font_data(FONT.to_vec());
+        let family = fonts
+            .faces()
+            .next()
+            .and_then(|face| face.families.first())
+            .map(|(family, _)| family.clone())
+            .unwrap_or_default();
+        usvg::Options {
+            font_family: family,
+            fontdb: Arc::new(fonts),
+            ..Default::default()
+        }
+    })
+}
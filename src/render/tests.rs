@@ -0,0 +1,139 @@
+//! Golden image tests: each case is rendered and compared to a checked-in PNG in `golden/`.
+//! After changing how things look on purpose, run the tests with `UPDATE_GOLDEN=1` and check
+//! the new images before committing them. Mismatches are written to `target/golden/`.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use resvg::tiny_skia::{Color as SkiaColor, Pixmap};
+
+use super::*;
+
+const GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/render/golden");
+
+/// How far apart pixels may be before they count as different, for rounding in the rasterizer.
+const TOLERANCE: u8 = 8;
+
+fn album_art() -> Arc<[u8]> {
+    let mut art = Pixmap::new(60, 40).unwrap();
+    art.fill(SkiaColor::from_rgba8(40, 90, 160, 255));
+    for (i, pixel) in art.pixels_mut().iter_mut().enumerate() {
+        let (x, y) = (i % 60, i / 60);
+        if (x / 10 + y / 10) % 2 == 0 {
+            *pixel = SkiaColor::from_rgba8(230, 200, 60, 255)
+                .premultiply()
+                .to_color_u8();
+        }
+    }
+    art.encode_png().unwrap().into()
+}
+
+fn cases() -> Vec<(&'static str, Canvas)> {
+    vec![
+        (
+            "volume",
+            Canvas::new()
+                .with(Layer::Fill(Color::BLACK))
+                .with(Layer::Icon {
+                    svg: icons::SPEAKER,
+                    size: 28.0,
+                    y: 22.0,
+                    color: Color::WHITE,
+                })
+                .with(Layer::Text {
+                    text: "42".to_string(),
+                    size: 16.0,
+                    y: 52.0,
+                    color: Color::WHITE,
+                })
+                .with(Layer::Bar {
+                    value: 0.42,
+                    y: 63.0,
+                    color: Color::ACCENT,
+                }),
+        ),
+        (
+            "progress",
+            Canvas::new()
+                .with(Layer::Fill(Color::BLACK))
+                .with(Layer::Ring {
+                    value: 0.3,
+                    color: Color::ACCENT,
+                })
+                .with(Layer::Icon {
+                    svg: icons::PAUSE,
+                    size: 30.0,
+                    y: 36.0,
+                    color: Color::WHITE,
+                }),
+        ),
+        (
+            "room",
+            Canvas::new()
+                .with(Layer::Fill(Color(30, 30, 30)))
+                .with(Layer::Text {
+                    text: "Bed & <Bath>".to_string(),
+                    size: 10.0,
+                    y: 40.0,
+                    color: Color::ACCENT,
+                }),
+        ),
+        (
+            "art",
+            Canvas::new()
+                .with(Layer::Art(album_art()))
+                .with(Layer::Icon {
+                    svg: icons::PLAY,
+                    size: 24.0,
+                    y: 36.0,
+                    color: Color::WHITE,
+                }),
+        ),
+    ]
+}
+
+fn check(name: &str, png: &[u8]) {
+    let path = Path::new(GOLDEN_DIR).join(format!("{name}.png"));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, png).unwrap();
+        return;
+    }
+
+    let actual = Pixmap::decode_png(png).unwrap();
+    let expected = Pixmap::decode_png(
+        &std::fs::read(&path)
+            .unwrap_or_else(|e| panic!("{}: {e}, run with UPDATE_GOLDEN=1", path.display())),
+    )
+    .unwrap();
+    let same = actual.width() == expected.width()
+        && actual.height() == expected.height()
+        && actual
+            .data()
+            .iter()
+            .zip(expected.data())
+            .all(|(a, b)| a.abs_diff(*b) <= TOLERANCE);
+    if !same {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/golden");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(format!("{name}.png")), png).unwrap();
+        panic!("{name} doesn't match {}, see target/golden", path.display());
+    }
+}
+
+#[test]
+fn matches_golden_images() {
+    for (name, canvas) in cases() {
+        for pixels in [STANDARD, HIGH_DPI] {
+            let png = canvas.render(pixels).unwrap();
+            check(&format!("{name}@{pixels}"), &png);
+        }
+    }
+}
+
+#[test]
+fn draws_for_the_key_size() {
+    assert_eq!(pixels_for(Some(72)), STANDARD);
+    assert_eq!(pixels_for(Some(144)), HIGH_DPI);
+    assert_eq!(pixels_for(Some(96)), HIGH_DPI, "the app scales down");
+    assert_eq!(pixels_for(None), HIGH_DPI);
+}
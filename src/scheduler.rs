@@ -0,0 +1,261 @@
+//! Polls zones for what keys show but players don't tell us about by themselves, like the
+//! volume or the sleep timer. Only zones behind keys that are on screen are polled, each kind of
+//! data at its own pace, and speakers that don't answer are left alone for a while.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::player::Player;
+use crate::sonos::{AVTransportState, ControllerError, PositionInfo, Volume};
+
+/// Don't wait longer than this before trying an unreachable speaker again.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A speaker that takes longer than this to answer counts as unreachable, so one that went
+/// away without closing its connections doesn't hold up the others.
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What a key can ask to be kept up to date about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Poll {
+    /// Only polled for players that can't tell us about changes themselves.
+    Transport,
+    #[allow(dead_code)] // no volume keys yet
+    Volume,
+    /// Track progress keys count up in between, so this can be slow.
+    Position,
+    /// Sleep timers run for minutes, so this can be slower still.
+    #[allow(dead_code)] // no sleep timer keys yet
+    SleepTimer,
+}
+
+impl Poll {
+    pub fn interval(self) -> Duration {
+        match self {
+            Poll::Transport => Duration::from_secs(5),
+            Poll::Volume => Duration::from_secs(2),
+            Poll::Position => Duration::from_secs(10),
+            Poll::SleepTimer => Duration::from_secs(30),
+        }
+    }
+}
+
+/// What one round of polling a zone turned up. Only what was asked for is filled in.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub transport: Option<AVTransportState>,
+    pub volume: Option<Volume>,
+    pub position: Option<PositionInfo>,
+    /// `Some(None)` if no sleep timer is set.
+    pub sleep_timer: Option<Option<Duration>>,
+}
+
+impl Snapshot {
+    pub fn has(&self, poll: Poll) -> bool {
+        match poll {
+            Poll::Transport => self.transport.is_some(),
+            Poll::Volume => self.volume.is_some(),
+            Poll::Position => self.position.is_some(),
+            Poll::SleepTimer => self.sleep_timer.is_some(),
+        }
+    }
+}
+
+/// Asks a zone for everything that's due in one go, so each speaker only sees one burst of
+/// requests at a time. Gives up after [`POLL_TIMEOUT`].
+pub async fn poll<P: Player>(zone: &P, polls: &[Poll]) -> Result<Snapshot, ControllerError> {
+    let all = async {
+        let mut snapshot = Snapshot::default();
+        for poll in polls {
+            match poll {
+                Poll::Transport => snapshot.transport = Some(zone.get_state().await?),
+                Poll::Volume => snapshot.volume = Some(zone.get_volume().await?),
+                Poll::Position => snapshot.position = Some(zone.get_position().await?),
+                Poll::SleepTimer => snapshot.sleep_timer = Some(sleep_timer(zone).await?),
+            }
+        }
+        Ok(snapshot)
+    };
+    tokio::time::timeout(POLL_TIMEOUT, all)
+        .await
+        .unwrap_or(Err(ControllerError::Timeout))
+}
+
+/// Players without a sleep timer never have one running.
+async fn sleep_timer<P: Player>(zone: &P) -> Result<Option<Duration>, ControllerError> {
+    match zone.get_sleep_timer().await {
+        Err(ControllerError::Unsupported(_)) => Ok(None),
+        timer => timer,
+    }
+}
+
+/// Keeps track of when each zone was last polled for what, by zone name.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    zones: HashMap<String, Polled>,
+}
+
+#[derive(Debug, Default)]
+struct Polled {
+    last: HashMap<Poll, Instant>,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Polled {
+    fn due_at(&self, poll: Poll) -> Option<Instant> {
+        let due = self.last.get(&poll).map(|last| *last + poll.interval());
+        due.max(self.retry_at)
+    }
+}
+
+impl Scheduler {
+    /// What's due for each zone, given what the keys on screen want. Zones nobody wants
+    /// anything from anymore are forgotten.
+    pub fn due(
+        &mut self,
+        wanted: &HashMap<String, HashSet<Poll>>,
+        now: Instant,
+    ) -> Vec<(String, Vec<Poll>)> {
+        self.zones.retain(|zone, _| wanted.contains_key(zone));
+        let mut due = vec![];
+        for (zone, polls) in wanted {
+            let polled = self.zones.entry(zone.clone()).or_default();
+            let mut polls: Vec<Poll> = polls
+                .iter()
+                .copied()
+                .filter(|poll| polled.due_at(*poll).is_none_or(|at| at <= now))
+                .collect();
+            if !polls.is_empty() {
+                polls.sort_unstable_by_key(|poll| poll.interval());
+                due.push((zone.clone(), polls));
+            }
+        }
+        due
+    }
+
+    /// When anything is due next, or `None` if nothing's wanted.
+    pub fn next_due(&self, wanted: &HashMap<String, HashSet<Poll>>) -> Option<Instant> {
+        wanted
+            .iter()
+            .flat_map(|(zone, polls)| {
+                let polled = self.zones.get(zone);
+                polls
+                    .iter()
+                    .map(move |poll| polled.and_then(|polled| polled.due_at(*poll)))
+            })
+            // never polled means right away
+            .map(|at| at.unwrap_or_else(Instant::now))
+            .min()
+    }
+
+    /// Notes how polling a zone went. Failures push its next poll back further each time.
+    pub fn polled(&mut self, zone: &str, polls: &[Poll], ok: bool, now: Instant) {
+        let polled = self.zones.entry(zone.to_string()).or_default();
+        for poll in polls {
+            polled.last.insert(*poll, now);
+        }
+        if ok {
+            polled.failures = 0;
+            polled.retry_at = None;
+        } else {
+            polled.failures += 1;
+            let backoff = Duration::from_secs(1)
+                .saturating_mul(1 << polled.failures.min(6))
+                .min(MAX_BACKOFF);
+            polled.retry_at = Some(now + backoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::mock::MockPlayer;
+
+    fn wanted(zones: &[(&str, &[Poll])]) -> HashMap<String, HashSet<Poll>> {
+        zones
+            .iter()
+            .map(|(zone, polls)| (zone.to_string(), polls.iter().copied().collect()))
+            .collect()
+    }
+
+    #[test]
+    fn polls_each_kind_at_its_own_pace() {
+        let mut scheduler = Scheduler::default();
+        let wanted = wanted(&[("Kitchen", &[Poll::Volume, Poll::Position])]);
+        let start = Instant::now();
+
+        // everything's due at first, batched per zone
+        let due = scheduler.due(&wanted, start);
+        assert_eq!(
+            due,
+            [("Kitchen".to_string(), vec![Poll::Volume, Poll::Position])]
+        );
+        scheduler.polled("Kitchen", &due[0].1, true, start);
+
+        let later = start + Duration::from_secs(2);
+        assert_eq!(scheduler.next_due(&wanted), Some(later));
+        assert_eq!(
+            scheduler.due(&wanted, later),
+            [("Kitchen".to_string(), vec![Poll::Volume])]
+        );
+        assert!(scheduler.due(&HashMap::new(), later).is_empty());
+        assert_eq!(scheduler.next_due(&HashMap::new()), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_what_is_due() {
+        let player = MockPlayer::new("Kitchen");
+        player.state().sleep_timer = Some(Duration::from_secs(600));
+        let snapshot = poll(&player, &[Poll::Volume, Poll::SleepTimer])
+            .await
+            .unwrap();
+        assert_eq!(snapshot.volume, Some(Volume::new(20)));
+        assert_eq!(snapshot.sleep_timer, Some(Some(Duration::from_secs(600))));
+        assert!(!snapshot.has(Poll::Position));
+
+        player.state().fail = Some(ControllerError::Unsupported("Sleep".to_string()));
+        let snapshot = poll(&player, &[Poll::SleepTimer]).await.unwrap();
+        assert_eq!(snapshot.sleep_timer, Some(None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_speakers_that_do_not_answer() {
+        let player = MockPlayer::new("Kitchen");
+        player.state().stalled = true;
+        let start = Instant::now();
+        assert!(matches!(
+            poll(&player, &[Poll::Transport]).await,
+            Err(ControllerError::Timeout)
+        ));
+        assert_eq!(start.elapsed(), POLL_TIMEOUT);
+    }
+
+    #[test]
+    fn backs_off_from_unreachable_speakers() {
+        let mut scheduler = Scheduler::default();
+        let wanted = wanted(&[("Office", &[Poll::Volume])]);
+        let mut now = Instant::now();
+
+        let mut waits = vec![];
+        for _ in 0..8 {
+            scheduler.polled("Office", &[Poll::Volume], false, now);
+            let next = scheduler.next_due(&wanted).unwrap();
+            waits.push((next - now).as_secs());
+            assert!(scheduler
+                .due(&wanted, next - Duration::from_millis(1))
+                .is_empty());
+            now = next;
+        }
+        assert_eq!(waits, [2, 4, 8, 16, 32, 60, 60, 60]);
+
+        // and it's back to normal as soon as it answers
+        scheduler.polled("Office", &[Poll::Volume], true, now);
+        assert_eq!(
+            scheduler.next_due(&wanted),
+            Some(now + Poll::Volume.interval())
+        );
+    }
+}
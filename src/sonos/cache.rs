@@ -0,0 +1,66 @@
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::player::Player;
+
+/// A speaker we've seen before, with enough to find it again without multicast.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedSpeaker {
+    pub uuid: String,
+    pub name: String,
+    pub location: String,
+}
+
+/// Remembers the speakers we've found on disk, for networks where SSDP discovery doesn't
+/// make it through.
+#[derive(Debug, Clone)]
+pub struct DeviceCache {
+    path: PathBuf,
+}
+
+impl DeviceCache {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The cached speakers. A missing or unreadable cache is the same as an empty one.
+    pub fn load(&self) -> Vec<CachedSpeaker> {
+        let json = match fs::read_to_string(&self.path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return vec![],
+            Err(e) => {
+                warn!("could not read {}: {e}", self.path.display());
+                return vec![];
+            }
+        };
+        serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("ignoring malformed {}: {e}", self.path.display());
+            vec![]
+        })
+    }
+
+    pub fn save(&self, zones: &[impl Player]) -> io::Result<()> {
+        let speakers: Vec<CachedSpeaker> = zones
+            .iter()
+            .map(|zone| CachedSpeaker {
+                uuid: zone.uuid().to_string(),
+                name: zone.name().to_string(),
+                location: zone.location(),
+            })
+            .collect();
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&speakers)?)?;
+        debug!(
+            "cached {} speakers in {}",
+            speakers.len(),
+            self.path.display()
+        );
+        Ok(())
+    }
+}
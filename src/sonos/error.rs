@@ -8,6 +8,38 @@ pub enum ControllerError {
     TransportError(#[from] rupnp::Error),
     #[error("volume should be an integer between 0 and 100")]
     VolumeError,
+    #[error("{0} is not a valid speaker address")]
+    InvalidAddress(String),
+    #[error("{0} is not supported by this device")]
+    Unsupported(String),
+    #[error("{0} is not possible right now")]
+    TransitionNotAvailable(String),
+    #[error("there's no favorite called {0:?}")]
+    NoFavorite(String),
+    #[error("can't seek to that position")]
+    IllegalSeekTarget,
+    #[error("{0} failed with Sonos error {1}")]
+    SonosError(String, u16),
+    #[error("{0} failed with UPnP error {1}: {2}")]
+    Fault(String, u16, String),
+    #[error("the speaker didn't answer in time")]
+    Timeout,
     #[error("response malformed")]
     MalformedResponse,
 }
+
+impl ControllerError {
+    /// Maps the UPnP fault codes we know about to their own variants.
+    pub fn from_fault(action: &str, code: u16, description: &str) -> Self {
+        match code {
+            // no action by that name, optional action not implemented
+            401 | 602 => ControllerError::Unsupported(action.to_string()),
+            // e.g. skipping tracks on a radio stream
+            701 => ControllerError::TransitionNotAvailable(action.to_string()),
+            // UPnP uses 711 for illegal seek targets, Sonos uses 714
+            711 | 714 if action == "Seek" => ControllerError::IllegalSeekTarget,
+            code @ 800..=899 => ControllerError::SonosError(action.to_string(), code),
+            code => ControllerError::Fault(action.to_string(), code, description.to_string()),
+        }
+    }
+}
@@ -0,0 +1,898 @@
+//! A fake Sonos speaker for tests. It serves a device description, SCPD documents and SOAP
+//! control endpoints over HTTP on loopback, answers SSDP searches, and keeps its transport,
+//! volume and queue in memory so tests can check what the real code did to it.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket as StdUdpSocket};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+
+use super::AVTransportState;
+
+const SSDP_ADDRESS: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+
+/// A service, with the actions the fake understands as (name, inputs, outputs), and the
+/// state variables it sends events for.
+struct FakeService {
+    name: &'static str,
+    actions: &'static [(
+        &'static str,
+        &'static [&'static str],
+        &'static [&'static str],
+    )],
+    evented: &'static [&'static str],
+}
+
+const AV_TRANSPORT: FakeService = FakeService {
+    name: "AVTransport",
+    actions: &[
+        ("Play", &["InstanceID", "Speed"], &[]),
+        ("Pause", &["InstanceID"], &[]),
+        ("Stop", &["InstanceID"], &[]),
+        ("Next", &["InstanceID"], &[]),
+        ("Previous", &["InstanceID"], &[]),
+        ("Seek", &["InstanceID", "Unit", "Target"], &[]),
+        (
+            "SetAVTransportURI",
+            &["InstanceID", "CurrentURI", "CurrentURIMetaData"],
+            &[],
+        ),
+        (
+            "AddURIToQueue",
+            &[
+                "InstanceID",
+                "EnqueuedURI",
+                "EnqueuedURIMetaData",
+                "DesiredFirstTrackNumberEnqueued",
+                "EnqueueAsNext",
+            ],
+            &[
+                "FirstTrackNumberEnqueued",
+                "NumTracksAdded",
+                "NewQueueLength",
+            ],
+        ),
+        ("RemoveAllTracksFromQueue", &["InstanceID"], &[]),
+        (
+            "GetRemainingSleepTimerDuration",
+            &["InstanceID"],
+            &["RemainingSleepTimerDuration", "CurrentSleepTimerGeneration"],
+        ),
+        (
+            "GetTransportInfo",
+            &["InstanceID"],
+            &[
+                "CurrentTransportState",
+                "CurrentTransportStatus",
+                "CurrentSpeed",
+            ],
+        ),
+        (
+            "GetPositionInfo",
+            &["InstanceID"],
+            &[
+                "Track",
+                "TrackDuration",
+                "TrackMetaData",
+                "TrackURI",
+                "RelTime",
+                "AbsTime",
+            ],
+        ),
+    ],
+    evented: &["LastChange"],
+};
+
+const RENDERING_CONTROL: FakeService = FakeService {
+    name: "RenderingControl",
+    actions: &[
+        ("GetVolume", &["InstanceID", "Channel"], &["CurrentVolume"]),
+        (
+            "SetVolume",
+            &["InstanceID", "Channel", "DesiredVolume"],
+            &[],
+        ),
+        (
+            "RampToVolume",
+            &[
+                "InstanceID",
+                "Channel",
+                "RampType",
+                "DesiredVolume",
+                "ResetVolumeAfter",
+                "ProgramURI",
+            ],
+            &["RampTime"],
+        ),
+    ],
+    evented: &["LastChange"],
+};
+
+const DEVICE_PROPERTIES: FakeService = FakeService {
+    name: "DeviceProperties",
+    actions: &[(
+        "GetZoneAttributes",
+        &[],
+        &["CurrentZoneName", "CurrentIcon", "CurrentConfiguration"],
+    )],
+    evented: &[],
+};
+
+const CONTENT_DIRECTORY: FakeService = FakeService {
+    name: "ContentDirectory",
+    actions: &[(
+        "Browse",
+        &[
+            "ObjectID",
+            "BrowseFlag",
+            "Filter",
+            "StartingIndex",
+            "RequestedCount",
+            "SortCriteria",
+        ],
+        &["Result", "NumberReturned", "TotalMatches", "UpdateID"],
+    )],
+    evented: &[],
+};
+
+const ZONE_GROUP_TOPOLOGY: FakeService = FakeService {
+    name: "ZoneGroupTopology",
+    actions: &[(
+        "GetZoneGroupAttributes",
+        &[],
+        &["CurrentZoneGroupName", "CurrentZoneGroupID"],
+    )],
+    evented: &[],
+};
+
+/// A bare-bones renderer's transport: no skipping, no seeking, no events.
+const BASIC_AV_TRANSPORT: FakeService = FakeService {
+    name: "AVTransport",
+    actions: &[
+        ("Play", &["InstanceID", "Speed"], &[]),
+        ("Pause", &["InstanceID"], &[]),
+        ("Stop", &["InstanceID"], &[]),
+        (
+            "GetTransportInfo",
+            &["InstanceID"],
+            &[
+                "CurrentTransportState",
+                "CurrentTransportStatus",
+                "CurrentSpeed",
+            ],
+        ),
+    ],
+    evented: &[],
+};
+
+/// A bare-bones renderer's volume control, without Sonos ramps.
+const BASIC_RENDERING_CONTROL: FakeService = FakeService {
+    name: "RenderingControl",
+    actions: &[
+        ("GetVolume", &["InstanceID", "Channel"], &["CurrentVolume"]),
+        (
+            "SetVolume",
+            &["InstanceID", "Channel", "DesiredVolume"],
+            &[],
+        ),
+    ],
+    evented: &[],
+};
+
+const SONOS_DEVICE: &str = "urn:schemas-upnp-org:device:ZonePlayer:1";
+const RENDERER_DEVICE: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+
+/// What the fake pretends to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FakeKind {
+    /// A Sonos speaker, with a media renderer inside.
+    Sonos,
+    /// Some other renderer that does little more than play and pause.
+    Renderer,
+}
+
+impl FakeKind {
+    fn services(self) -> &'static [&'static FakeService] {
+        match self {
+            FakeKind::Sonos => &[
+                &AV_TRANSPORT,
+                &RENDERING_CONTROL,
+                &DEVICE_PROPERTIES,
+                &ZONE_GROUP_TOPOLOGY,
+                &CONTENT_DIRECTORY,
+            ],
+            FakeKind::Renderer => &[&BASIC_AV_TRANSPORT, &BASIC_RENDERING_CONTROL],
+        }
+    }
+
+    /// The search targets the fake answers SSDP searches for, besides `ssdp:all`.
+    fn search_targets(self) -> Vec<String> {
+        match self {
+            FakeKind::Sonos => vec![
+                service_type("ZoneGroupTopology"),
+                RENDERER_DEVICE.to_string(),
+            ],
+            FakeKind::Renderer => vec![RENDERER_DEVICE.to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeTrack {
+    pub title: String,
+    pub duration: Duration,
+}
+
+impl FakeTrack {
+    pub fn new(title: &str, seconds: u64) -> Self {
+        Self {
+            title: title.to_string(),
+            duration: Duration::from_secs(seconds),
+        }
+    }
+}
+
+/// A favorite saved in the Sonos app. Containers, like playlists, go through the queue.
+#[derive(Debug, Clone)]
+pub struct FakeFavorite {
+    pub title: String,
+    pub uri: String,
+    pub container: bool,
+}
+
+impl FakeFavorite {
+    pub fn new(title: &str, uri: &str, container: bool) -> Self {
+        Self {
+            title: title.to_string(),
+            uri: uri.to_string(),
+            container,
+        }
+    }
+}
+
+/// Everything the fake speaker knows about itself. Tests can change it directly.
+#[derive(Debug, Clone)]
+pub struct FakeState {
+    pub room: String,
+    pub transport: AVTransportState,
+    pub volume: u8,
+    /// What's playing: the queue, if it's `x-rincon-queue:...`, or else a stream.
+    pub uri: String,
+    /// An empty queue plays like a radio stream, which can't skip or seek.
+    pub queue: Vec<FakeTrack>,
+    /// Index into `queue`.
+    pub track: usize,
+    pub elapsed: Duration,
+    pub favorites: Vec<FakeFavorite>,
+    pub sleep_timer: Option<Duration>,
+    /// Every action called so far, in order.
+    pub calls: Vec<String>,
+}
+
+pub struct FakeSpeaker {
+    address: SocketAddr,
+    uuid: String,
+    kind: FakeKind,
+    state: Arc<Mutex<FakeState>>,
+    server: JoinHandle<()>,
+}
+
+impl FakeSpeaker {
+    /// Starts a Sonos speaker with a queue of three tracks, paused at the start of the first
+    /// one.
+    pub async fn start(room: &str) -> Self {
+        Self::start_as(FakeKind::Sonos, room).await
+    }
+
+    /// Starts a speaker of the given kind. Plain renderers are named `room` too.
+    pub async fn start_as(kind: FakeKind, room: &str) -> Self {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .expect("can't bind fake speaker");
+        let address = listener.local_addr().unwrap();
+        let uuid = match kind {
+            FakeKind::Sonos => format!("RINCON_FAKE{:05}", address.port()),
+            FakeKind::Renderer => format!("fake-renderer-{}", address.port()),
+        };
+        let state = Arc::new(Mutex::new(FakeState {
+            room: room.to_string(),
+            transport: AVTransportState::Paused,
+            volume: 20,
+            uri: format!("x-rincon-queue:{uuid}#0"),
+            queue: vec![
+                FakeTrack::new("One", 180),
+                FakeTrack::new("Two", 200),
+                FakeTrack::new("Three", 220),
+            ],
+            track: 0,
+            elapsed: Duration::ZERO,
+            favorites: vec![
+                FakeFavorite::new("Jazz", "x-sonosapi-stream:s1234?sid=254", false),
+                FakeFavorite::new("Road Trip", "x-rincon-cpcontainer:1006206cplaylist", true),
+            ],
+            sleep_timer: None,
+            calls: vec![],
+        }));
+
+        let server = tokio::spawn(serve(listener, uuid.clone(), kind, state.clone()));
+        Self {
+            address,
+            uuid,
+            kind,
+            state,
+            server,
+        }
+    }
+
+    pub fn location(&self) -> String {
+        format!("http://{}/xml/device_description.xml", self.address)
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Makes the speaker answer SSDP searches, until it's dropped.
+    pub fn answer_searches(&self) -> io::Result<()> {
+        let responder = ssdp_responder()?;
+        responder
+            .lock()
+            .unwrap()
+            .insert(self.uuid.clone(), (self.location(), self.kind));
+        Ok(())
+    }
+}
+
+impl Drop for FakeSpeaker {
+    fn drop(&mut self) {
+        self.server.abort();
+        if let Some(responder) = SSDP_RESPONDER.get() {
+            responder.lock().unwrap().remove(&self.uuid);
+        }
+    }
+}
+
+async fn serve(listener: TcpListener, uuid: String, kind: FakeKind, state: Arc<Mutex<FakeState>>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let uuid = uuid.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &uuid, kind, &state).await {
+                eprintln!("fake speaker: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    uuid: &str,
+    kind: FakeKind,
+    state: &Mutex<FakeState>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    let body = String::from_utf8_lossy(&body);
+
+    let (status, response) = match (method.as_str(), path.as_str()) {
+        ("GET", "/xml/device_description.xml") => (
+            "200 OK",
+            device_description(kind, uuid, &state.lock().unwrap().room),
+        ),
+        ("GET", path) => match kind
+            .services()
+            .iter()
+            .find(|service| path == format!("/xml/{}1.xml", service.name))
+        {
+            Some(service) => ("200 OK", scpd(service)),
+            None => ("404 Not Found", String::new()),
+        },
+        ("POST", _) => {
+            let soap_action = headers.get("soapaction").cloned().unwrap_or_default();
+            let action = soap_action
+                .trim_matches('"')
+                .rsplit('#')
+                .next()
+                .unwrap_or_default();
+            let service = path.trim_start_matches('/').trim_end_matches("/Control");
+            match control(kind, state, service, action, &body) {
+                Ok(outputs) => ("200 OK", soap_response(service, action, &outputs)),
+                Err(code) => ("500 Internal Server Error", soap_fault(code)),
+            }
+        }
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+
+    let mut stream = reader.into_inner();
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Runs a SOAP action against the speaker's state. Returns the output arguments, or the UPnP
+/// error code a real speaker would answer with.
+fn control(
+    kind: FakeKind,
+    state: &Mutex<FakeState>,
+    service: &str,
+    action: &str,
+    body: &str,
+) -> Result<Vec<(&'static str, String)>, u16> {
+    let known = kind
+        .services()
+        .iter()
+        .find(|known| known.name == service)
+        .and_then(|known| known.actions.iter().find(|(name, _, _)| *name == action));
+    let Some((_, inputs, _)) = known else {
+        return Err(401);
+    };
+
+    let args = soap_arguments(body, action).ok_or(402u16)?;
+    if inputs.iter().any(|input| !args.contains_key(*input)) {
+        return Err(402);
+    }
+
+    let mut state = state.lock().unwrap();
+    state.calls.push(action.to_string());
+    let radio = state.queue.is_empty() || !state.uri.starts_with("x-rincon-queue:");
+
+    match action {
+        "Play" => state.transport = AVTransportState::Playing,
+        "Pause" if state.transport == AVTransportState::Stopped => return Err(701),
+        "Pause" => state.transport = AVTransportState::Paused,
+        "Stop" => state.transport = AVTransportState::Stopped,
+        "Next" if radio || state.track + 1 >= state.queue.len() => return Err(701),
+        "Next" => {
+            state.track += 1;
+            state.elapsed = Duration::ZERO;
+        }
+        "Previous" if radio || state.track == 0 => return Err(701),
+        "Previous" => {
+            state.track -= 1;
+            state.elapsed = Duration::ZERO;
+        }
+        "Seek" => {
+            let target = parse_time(&args["Target"]).ok_or(402u16)?;
+            match state.queue.get(state.track).filter(|_| !radio) {
+                Some(track) if args["Unit"] == "REL_TIME" && target <= track.duration => {
+                    state.elapsed = target
+                }
+                _ => return Err(714),
+            }
+        }
+        "GetTransportInfo" => {
+            return Ok(vec![
+                ("CurrentTransportState", transport_name(state.transport)),
+                ("CurrentTransportStatus", "OK".to_string()),
+                ("CurrentSpeed", "1".to_string()),
+            ])
+        }
+        "GetPositionInfo" => {
+            let (track, duration, uri, title) =
+                match state.queue.get(state.track).filter(|_| !radio) {
+                    Some(track) => (
+                        state.track + 1,
+                        format_time(track.duration),
+                        format!("x-file-cifs://fake/{}.mp3", track.title),
+                        track.title.as_str(),
+                    ),
+                    None => (
+                        0,
+                        "NOT_IMPLEMENTED".to_string(),
+                        "x-rincon-mp3radio://fake".to_string(),
+                        "Fake FM",
+                    ),
+                };
+            let metadata = didl(&format!(
+                r#"<item id="-1" parentID="-1"><dc:title>{}</dc:title><dc:creator>Fake Band</dc:creator></item>"#,
+                escape(title)
+            ));
+            let elapsed = match radio {
+                true => "NOT_IMPLEMENTED".to_string(),
+                false => format_time(state.elapsed),
+            };
+            return Ok(vec![
+                ("Track", track.to_string()),
+                ("TrackDuration", duration),
+                ("TrackMetaData", metadata),
+                ("TrackURI", uri),
+                ("RelTime", elapsed.clone()),
+                ("AbsTime", elapsed),
+            ]);
+        }
+        "SetAVTransportURI" => {
+            state.uri = args["CurrentURI"].clone();
+            state.track = 0;
+            state.elapsed = Duration::ZERO;
+            state.transport = AVTransportState::Stopped;
+        }
+        "AddURIToQueue" => {
+            // every container holds a single track, named after where it came from
+            let uri = &args["EnqueuedURI"];
+            state.queue.push(FakeTrack::new(uri, 240));
+            return Ok(vec![
+                ("FirstTrackNumberEnqueued", state.queue.len().to_string()),
+                ("NumTracksAdded", "1".to_string()),
+                ("NewQueueLength", state.queue.len().to_string()),
+            ]);
+        }
+        "RemoveAllTracksFromQueue" => {
+            state.queue.clear();
+            state.track = 0;
+        }
+        "GetRemainingSleepTimerDuration" => {
+            return Ok(vec![
+                (
+                    "RemainingSleepTimerDuration",
+                    state.sleep_timer.map(format_time).unwrap_or_default(),
+                ),
+                ("CurrentSleepTimerGeneration", "1".to_string()),
+            ])
+        }
+        "Browse" if args["ObjectID"] != "FV:2" => return Err(701),
+        "Browse" => {
+            let items: String = state.favorites.iter().map(favorite_didl).collect();
+            let count = state.favorites.len().to_string();
+            return Ok(vec![
+                ("Result", didl(&items)),
+                ("NumberReturned", count.clone()),
+                ("TotalMatches", count),
+                ("UpdateID", "1".to_string()),
+            ]);
+        }
+        "GetVolume" => return Ok(vec![("CurrentVolume", state.volume.to_string())]),
+        "SetVolume" | "RampToVolume" => {
+            let volume: u8 = args["DesiredVolume"].parse().map_err(|_| 402u16)?;
+            if volume > 100 {
+                return Err(601);
+            }
+            state.volume = volume;
+            if action == "RampToVolume" {
+                // ramps finish immediately, so tests don't have to wait
+                return Ok(vec![("RampTime", "0".to_string())]);
+            }
+        }
+        "GetZoneAttributes" => {
+            return Ok(vec![
+                ("CurrentZoneName", state.room.clone()),
+                ("CurrentIcon", "x-rincon-roomicon:living".to_string()),
+                ("CurrentConfiguration", "1".to_string()),
+            ])
+        }
+        "GetZoneGroupAttributes" => {
+            return Ok(vec![
+                ("CurrentZoneGroupName", state.room.clone()),
+                ("CurrentZoneGroupID", "RINCON_FAKE:1".to_string()),
+            ])
+        }
+        _ => return Err(401),
+    }
+    Ok(vec![])
+}
+
+fn didl(items: &str) -> String {
+    format!(
+        r#"<DIDL-Lite xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/" xmlns:r="urn:schemas-rinconnetworks-com:metadata-1-0/" xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/">{items}</DIDL-Lite>"#
+    )
+}
+
+/// A favorite as Sonos lists it, with the metadata to play it with escaped inside.
+fn favorite_didl(favorite: &FakeFavorite) -> String {
+    let class = match favorite.container {
+        true => "object.container.playlistContainer",
+        false => "object.item.audioItem.audioBroadcast",
+    };
+    let metadata = didl(&format!(
+        r#"<item id="-1" parentID="-1"><dc:title>{}</dc:title><upnp:class>{class}</upnp:class></item>"#,
+        escape(&favorite.title)
+    ));
+    format!(
+        r#"<item id="FV:2/{0}" parentID="FV:2"><dc:title>{0}</dc:title><upnp:class>object.itemobject.item.sonos-favorite</upnp:class><res>{1}</res><r:resMD>{2}</r:resMD></item>"#,
+        escape(&favorite.title),
+        escape(&favorite.uri),
+        escape(&metadata)
+    )
+}
+
+/// The input arguments of the action in a SOAP envelope, unescaped.
+fn soap_arguments(body: &str, action: &str) -> Option<HashMap<String, String>> {
+    let document = roxmltree::Document::parse(body).ok()?;
+    let action = document
+        .descendants()
+        .find(|node| node.is_element() && node.tag_name().name() == action)?;
+    Some(
+        action
+            .children()
+            .filter(|node| node.is_element())
+            .map(|node| {
+                let value = node.text().unwrap_or_default().to_string();
+                (node.tag_name().name().to_string(), value)
+            })
+            .collect(),
+    )
+}
+
+fn service_type(service: &str) -> String {
+    format!("urn:schemas-upnp-org:service:{service}:1")
+}
+
+fn device_description(kind: FakeKind, uuid: &str, room: &str) -> String {
+    let service = |service: &FakeService| {
+        format!(
+            "<service><serviceType>{}</serviceType>\
+            <serviceId>urn:upnp-org:serviceId:{name}</serviceId>\
+            <controlURL>/{name}/Control</controlURL>\
+            <eventSubURL>/{name}/Event</eventSubURL>\
+            <SCPDURL>/xml/{name}1.xml</SCPDURL></service>",
+            service_type(service.name),
+            name = service.name
+        )
+    };
+    let room = escape(room);
+
+    let device = match kind {
+        FakeKind::Sonos => format!(
+            r#"<deviceType>{SONOS_DEVICE}</deviceType>
+<friendlyName>127.0.0.1 - Fake Speaker - {room}</friendlyName>
+<manufacturer>Sonos, Inc.</manufacturer>
+<modelName>Fake Speaker</modelName>
+<UDN>uuid:{uuid}</UDN>
+<roomName>{room}</roomName>
+<serviceList>{}{}</serviceList>
+<deviceList>
+<device>
+<deviceType>{RENDERER_DEVICE}</deviceType>
+<friendlyName>{room} - Fake Speaker Media Renderer</friendlyName>
+<manufacturer>Sonos, Inc.</manufacturer>
+<modelName>Fake Speaker</modelName>
+<UDN>uuid:{uuid}_MR</UDN>
+<serviceList>{}{}</serviceList>
+</device>
+<device>
+<deviceType>urn:schemas-upnp-org:device:MediaServer:1</deviceType>
+<friendlyName>{room} - Fake Speaker Media Server</friendlyName>
+<manufacturer>Sonos, Inc.</manufacturer>
+<modelName>Fake Speaker</modelName>
+<UDN>uuid:{uuid}_MS</UDN>
+<serviceList>{}</serviceList>
+</device>
+</deviceList>"#,
+            service(&DEVICE_PROPERTIES),
+            service(&ZONE_GROUP_TOPOLOGY),
+            service(&AV_TRANSPORT),
+            service(&RENDERING_CONTROL),
+            service(&CONTENT_DIRECTORY),
+        ),
+        FakeKind::Renderer => format!(
+            r#"<deviceType>{RENDERER_DEVICE}</deviceType>
+<friendlyName>{room}</friendlyName>
+<manufacturer>Fake Audio</manufacturer>
+<modelName>Fake Renderer</modelName>
+<UDN>uuid:{uuid}</UDN>
+<serviceList>{}{}</serviceList>"#,
+            service(&BASIC_AV_TRANSPORT),
+            service(&BASIC_RENDERING_CONTROL),
+        ),
+    };
+
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<device>
+{device}
+</device>
+</root>"#
+    )
+}
+
+fn scpd(service: &FakeService) -> String {
+    let mut variables: Vec<&str> = service
+        .actions
+        .iter()
+        .flat_map(|(_, inputs, outputs)| inputs.iter().chain(outputs.iter()))
+        .copied()
+        .collect();
+    variables.extend(service.evented);
+    variables.sort_unstable();
+    variables.dedup();
+
+    let arguments = |names: &[&str], direction: &str| -> String {
+        names
+            .iter()
+            .map(|name| {
+                format!(
+                    "<argument><name>{name}</name><direction>{direction}</direction>\
+                    <relatedStateVariable>{name}</relatedStateVariable></argument>"
+                )
+            })
+            .collect()
+    };
+    let actions: String = service
+        .actions
+        .iter()
+        .map(|(name, inputs, outputs)| {
+            format!(
+                "<action><name>{name}</name><argumentList>{}{}</argumentList></action>",
+                arguments(inputs, "in"),
+                arguments(outputs, "out")
+            )
+        })
+        .collect();
+    let variables: String = variables
+        .iter()
+        .map(|name| {
+            let events = match service.evented.contains(name) {
+                true => "yes",
+                false => "no",
+            };
+            format!(
+                "<stateVariable sendEvents=\"{events}\"><name>{name}</name>\
+                <dataType>string</dataType></stateVariable>"
+            )
+        })
+        .collect();
+
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<serviceStateTable>{variables}</serviceStateTable>
+<actionList>{actions}</actionList>
+</scpd>"#
+    )
+}
+
+fn soap_response(service: &str, action: &str, outputs: &[(&str, String)]) -> String {
+    let outputs: String = outputs
+        .iter()
+        .map(|(name, value)| format!("<{name}>{}</{name}>", escape(value)))
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:{action}Response xmlns:u="{}">{outputs}</u:{action}Response></s:Body></s:Envelope>"#,
+        service_type(service)
+    )
+}
+
+fn soap_fault(code: u16) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>{code}</errorCode></UPnPError></detail></s:Fault></s:Body></s:Envelope>"#
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn transport_name(state: AVTransportState) -> String {
+    match state {
+        AVTransportState::Stopped => "STOPPED",
+        AVTransportState::Playing => "PLAYING",
+        AVTransportState::Paused => "PAUSED_PLAYBACK",
+        AVTransportState::Transitioning => "TRANSITIONING",
+    }
+    .to_string()
+}
+
+fn parse_time(value: &str) -> Option<Duration> {
+    let mut parts = value.split(':').map(|part| part.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (parts.next()??, parts.next()??, parts.next()??);
+    Some(Duration::from_secs(hours * 3600 + minutes * 60 + seconds))
+}
+
+fn format_time(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+/// Speaker UUIDs, with their locations and what they pretend to be.
+type Responder = Arc<Mutex<HashMap<String, (String, FakeKind)>>>;
+
+/// Port 1900 can only be bound once, so all the fake speakers in a test run share one
+/// responder. It only answers searches from this machine, so it doesn't show up for anyone
+/// else on the network. Searches are multicast out of the network interface rather than
+/// loopback, so it can't simply listen on 127.0.0.1.
+static SSDP_RESPONDER: OnceLock<Responder> = OnceLock::new();
+
+fn ssdp_responder() -> io::Result<Responder> {
+    if let Some(responder) = SSDP_RESPONDER.get() {
+        return Ok(responder.clone());
+    }
+
+    // the group's address rather than any, so only SSDP multicast arrives here
+    let socket = StdUdpSocket::bind((SSDP_ADDRESS, SSDP_PORT))?;
+    socket.join_multicast_v4(&SSDP_ADDRESS, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+
+    let speakers = SSDP_RESPONDER.get_or_init(Default::default).clone();
+    let responder = speakers.clone();
+    // the responder outlives any one test's runtime, so it gets a thread of its own
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let socket = UdpSocket::from_std(socket).unwrap();
+            let mut buf = [0; 2048];
+            while let Ok((read, peer)) = socket.recv_from(&mut buf).await {
+                let request = String::from_utf8_lossy(&buf[..read]);
+                if !request.starts_with("M-SEARCH") || !is_local(peer) {
+                    continue;
+                }
+                let target = request
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("ST")
+                            .then(|| value.trim().to_string())
+                    })
+                    .unwrap_or_default();
+
+                let speakers = responder.lock().unwrap().clone();
+                let answering = speakers.into_iter().filter(|(_, (_, kind))| {
+                    target == "ssdp:all" || kind.search_targets().contains(&target)
+                });
+                for (uuid, (location, _)) in answering {
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age = 1800\r\nEXT:\r\n\
+                        LOCATION: {location}\r\nSERVER: Linux UPnP/1.0 Sonos/70.3 (ZPS1)\r\n\
+                        ST: {target}\r\nUSN: uuid:{uuid}::{target}\r\n\r\n"
+                    );
+                    let _ = socket.send_to(response.as_bytes(), peer).await;
+                }
+            }
+        });
+    });
+    Ok(speakers)
+}
+
+/// Whether the address is one of this machine's, which only those can be bound to.
+fn is_local(peer: SocketAddr) -> bool {
+    peer.ip().is_loopback() || StdUdpSocket::bind((peer.ip(), 0)).is_ok()
+}
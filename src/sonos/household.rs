@@ -0,0 +1,161 @@
+use futures::{future, pin_mut, StreamExt};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::player::Player;
+
+/// SSDP is lossy, so a zone has to stay silent for a few rounds of discovery before we drop it.
+const MAX_MISSED_ROUNDS: u32 = 3;
+
+/// All the zones we know about.
+///
+/// Discovery swaps in a new set of zones whenever something changes, while anyone still
+/// holding on to the previous set (like an event that's being handled) keeps using it.
+#[derive(Debug)]
+pub struct Household<P> {
+    zones: RwLock<Arc<Vec<P>>>,
+    /// Zones that didn't answer the last rounds of discovery, by UUID.
+    missed: Mutex<HashMap<String, u32>>,
+}
+
+impl<P> Default for Household<P> {
+    fn default() -> Self {
+        Self {
+            zones: Default::default(),
+            missed: Default::default(),
+        }
+    }
+}
+
+impl<P: Player> Household<P> {
+    pub fn zones(&self) -> Arc<Vec<P>> {
+        self.zones.read().unwrap().clone()
+    }
+
+    pub fn find(&self, name: &str) -> Option<P> {
+        self.zones()
+            .iter()
+            .find(|zone| zone.name() == name)
+            .cloned()
+    }
+
+    pub fn first(&self) -> Option<P> {
+        self.zones().first().cloned()
+    }
+
+    /// Adds a zone, replacing the one for the same device if we already know about it, e.g.
+    /// because it moved to another address. Returns whether anything changed.
+    pub fn insert(&self, zone: P) -> bool {
+        let mut zones = self.zones.write().unwrap();
+        let mut updated = zones.as_ref().clone();
+        match updated.iter_mut().find(|known| known.uuid() == zone.uuid()) {
+            Some(known) if known.name() == zone.name() && known.location() == zone.location() => {
+                return false
+            }
+            Some(known) => *known = zone,
+            None => {
+                info!("found zone: {}", zone.name());
+                updated.push(zone);
+            }
+        }
+        *zones = Arc::new(updated);
+        true
+    }
+
+    /// Runs one round of discovery, adding zones as soon as they answer. Besides searching
+    /// the network, this asks the speakers at `known_locations` directly, so zones we've seen
+    /// before or that were entered by hand keep working without multicast. Zones that haven't
+    /// answered for a few rounds are dropped. Returns whether anything changed.
+    pub async fn discover(&self, timeout: Duration, known_locations: &[String]) -> bool {
+        let (searched, probed) = futures::join!(self.search(timeout), self.probe(known_locations));
+
+        let (mut seen, searched_changed) = searched;
+        let (probed_seen, probed_changed) = probed;
+        seen.extend(probed_seen);
+
+        self.forget_missing(&seen) || searched_changed || probed_changed
+    }
+
+    async fn search(&self, timeout: Duration) -> (HashSet<String>, bool) {
+        let mut seen = HashSet::new();
+        let mut changed = false;
+
+        let found = match P::discover(timeout).await {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("could not search for zones: {e}");
+                return (seen, changed);
+            }
+        };
+        pin_mut!(found);
+
+        while let Some(zone) = found.next().await {
+            match zone {
+                Ok(zone) => {
+                    seen.insert(zone.uuid().to_string());
+                    changed |= self.insert(zone);
+                }
+                Err(e) => warn!("skipping device: {e}"),
+            }
+        }
+        (seen, changed)
+    }
+
+    async fn probe(&self, locations: &[String]) -> (HashSet<String>, bool) {
+        let mut seen = HashSet::new();
+        let mut changed = false;
+
+        let probes = locations.iter().map(|location| async move {
+            match P::from_location(location).await {
+                Ok(zone) => zone.map(|zone| (zone.uuid().to_string(), self.insert(zone))),
+                Err(e) => {
+                    warn!("could not reach speaker at {location}: {e}");
+                    None
+                }
+            }
+        });
+        for (uuid, inserted) in future::join_all(probes).await.into_iter().flatten() {
+            seen.insert(uuid);
+            changed |= inserted;
+        }
+        (seen, changed)
+    }
+
+    fn forget_missing(&self, seen: &HashSet<String>) -> bool {
+        let mut missed = self.missed.lock().unwrap();
+        missed.retain(|uuid, _| !seen.contains(uuid));
+
+        let mut zones = self.zones.write().unwrap();
+        let (kept, dropped): (Vec<P>, Vec<P>) = zones.iter().cloned().partition(|zone| {
+            if seen.contains(zone.uuid()) {
+                return true;
+            }
+            let count = missed.entry(zone.uuid().to_string()).or_default();
+            *count += 1;
+            *count < MAX_MISSED_ROUNDS
+        });
+
+        for zone in &dropped {
+            info!("lost zone: {}", zone.name());
+            missed.remove(zone.uuid());
+        }
+        if dropped.is_empty() {
+            return false;
+        }
+        *zones = Arc::new(kept);
+        true
+    }
+}
+
+/// The local address we'd use to reach the speakers, which changes when we switch networks.
+/// Connecting a UDP socket doesn't send anything, it only picks the route.
+pub fn local_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket
+        .connect((Ipv4Addr::new(239, 255, 255, 250), 1900))
+        .ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
@@ -1,110 +1,360 @@
-use futures::{pin_mut, prelude::*};
+use futures::{pin_mut, prelude::*, stream};
+use log::warn;
 use rupnp::{
     ssdp::{SearchTarget, URN},
     Device,
 };
+use std::net::Ipv6Addr;
 use std::time::Duration;
+use tokio::sync::mpsc;
 
-use self::{
-    error::ControllerError,
-    services::{AVTransport, AVTransportState, RenderingControl, Volume},
+use crate::player::{self, Capabilities, Player};
+
+pub use self::cache::{CachedSpeaker, DeviceCache};
+pub use self::error::ControllerError;
+pub use self::household::{local_address, Household};
+use self::services::{
+    AVTransport, ContentDirectory, DeviceProperties, RampType, RenderingControl, ZoneGroupTopology,
 };
+pub use self::services::{AVTransportState, PositionInfo, Volume};
 
+mod cache;
 mod error;
+#[cfg(test)]
+mod fake;
+mod household;
 mod services;
+mod soap;
+#[cfg(test)]
+mod tests;
+
+/// Sonos speakers serve their device description on this port.
+const SONOS_PORT: u16 = 1400;
+
+/// Any UPnP renderer we can play on, including the one inside every Sonos speaker.
+const MEDIA_RENDERER_URN: URN = URN::device("schemas-upnp-org", "MediaRenderer", 1);
 
-const ZONE_GROUP_TOPOLOGY: URN = URN::service("schemas-upnp-org", "ZoneGroupTopology", 1);
+/// How long event subscriptions last before they have to be renewed.
+const SUBSCRIPTION_TIMEOUT_SECS: u32 = 300;
 
+/// A room to play music in: a group of Sonos speakers, or any other UPnP renderer.
 #[derive(Clone, Debug)]
 pub struct Zone {
+    name: String,
     primary_device: Device,
     av_transport: AVTransport,
-    rendering_control: RenderingControl,
+    /// Some renderers have no volume control of their own.
+    rendering_control: Option<RenderingControl>,
+    /// Only Sonos speakers have favorites.
+    content_directory: Option<ContentDirectory>,
+    capabilities: Capabilities,
 }
 
 impl Zone {
+    /// All the zones that answer within `timeout`. Devices that can't be used are logged and
+    /// skipped, so one odd device doesn't hide the rest.
     pub async fn get_zones(timeout: Duration) -> Result<Vec<Zone>, ControllerError> {
-        let search_target = SearchTarget::URN(ZONE_GROUP_TOPOLOGY);
-        let devices = rupnp::discover(&search_target, timeout).await?;
-
-        let mut zones: Vec<Zone> = vec![];
-
-        pin_mut!(devices);
-        while let Some(device) = devices.try_next().await? {
-            let service = device
-                .find_service(&ZONE_GROUP_TOPOLOGY)
-                .expect("searched for ZoneGroupTopology, got something else");
-
-            let args = "";
-            let response = service
-                .action(device.url(), "GetZoneGroupAttributes", args)
-                .await?;
+        Ok(Self::discover(timeout)
+            .await?
+            .filter_map(|zone| async move { zone.map_err(|e| warn!("skipping device: {e}")).ok() })
+            .collect()
+            .await)
+    }
 
-            if response.get("CurrentZoneGroupID").is_none() {
-                continue;
-            }
+    /// Builds a zone from any device we found: Sonos speakers become the room they're in,
+    /// other renderers go by their friendly name. Returns `None` for devices we can't play on
+    /// and for Sonos speakers that aren't part of a zone.
+    async fn from_any_device(device: Device) -> Result<Option<Zone>, ControllerError> {
+        if device
+            .find_service(&ZoneGroupTopology::SERVICE_URN)
+            .is_some()
+        {
+            return Self::from_zone_player(device).await;
+        }
+        if AVTransport::from_device(&device).is_err() {
+            return Ok(None);
+        }
+        Self::from_device(device).await.map(Some)
+    }
 
-            let zone = Zone::from_device(device);
-            zones.push(zone);
+    /// Builds a zone from a Sonos speaker, or returns `None` if it isn't part of a zone.
+    async fn from_zone_player(device: Device) -> Result<Option<Zone>, ControllerError> {
+        let topology = ZoneGroupTopology::from_device(&device)?;
+        if topology.get_zone_group_id(&device).await?.is_none() {
+            return Ok(None);
         }
 
-        Ok(zones)
+        let mut zone = Zone::from_device(device).await?;
+        zone.content_directory = ContentDirectory::from_device(&zone.primary_device).ok();
+        match zone.get_room_name().await {
+            Ok(name) => zone.name = name,
+            Err(e) => warn!("no room name for {}: {e}", zone.name),
+        }
+        Ok(Some(zone))
     }
 
-    pub fn from_device(primary_device: Device) -> Self {
-        let av_transport =
-            AVTransport::from_device(&primary_device).expect("expected AVTransport on device");
-        let rendering_control = RenderingControl::from_device(&primary_device)
-            .expect("expected RenderingControl on device");
-        Zone {
+    /// Builds a zone from a renderer, and works out what it can do from its SCPDs.
+    pub async fn from_device(primary_device: Device) -> Result<Self, ControllerError> {
+        let av_transport = AVTransport::from_device(&primary_device)?;
+        let rendering_control = RenderingControl::from_device(&primary_device).ok();
+        let mut zone = Zone {
+            name: primary_device.friendly_name().to_string(),
             primary_device,
             av_transport,
             rendering_control,
+            content_directory: None,
+            capabilities: Capabilities::ALL,
+        };
+        zone.capabilities = zone.read_capabilities().await;
+        Ok(zone)
+    }
+
+    async fn read_capabilities(&self) -> Capabilities {
+        let device = &self.primary_device;
+        let transport = &self.av_transport;
+        let volume = match &self.rendering_control {
+            Some(rendering) => {
+                rendering.supports(device, "GetVolume").await
+                    && rendering.supports(device, "SetVolume").await
+            }
+            None => false,
+        };
+        Capabilities {
+            skip: transport.supports(device, "Next").await
+                && transport.supports(device, "Previous").await,
+            seek: transport.supports(device, "Seek").await,
+            volume,
+            events: transport.sends_events(device).await,
         }
     }
 
-    pub async fn pause(&self) -> Result<(), ControllerError> {
-        self.av_transport.pause(&self.primary_device).await
+    fn rendering_control(&self, action: &str) -> Result<&RenderingControl, ControllerError> {
+        self.rendering_control
+            .as_ref()
+            .ok_or_else(|| ControllerError::Unsupported(action.to_string()))
+    }
+
+    async fn get_room_name(&self) -> Result<String, ControllerError> {
+        DeviceProperties::from_device(&self.primary_device)?
+            .get_zone_name(&self.primary_device)
+            .await
+    }
+}
+
+impl Player for Zone {
+    /// Sonos speakers answer both searches, and end up as the same zone.
+    async fn discover(
+        timeout: Duration,
+    ) -> Result<impl Stream<Item = Result<Zone, ControllerError>> + Send, ControllerError> {
+        let zone_player_target = SearchTarget::URN(ZoneGroupTopology::SERVICE_URN);
+        let renderer_target = SearchTarget::URN(MEDIA_RENDERER_URN);
+        let (zone_players, renderers) = futures::try_join!(
+            rupnp::discover(&zone_player_target, timeout),
+            rupnp::discover(&renderer_target, timeout),
+        )?;
+
+        Ok(stream::select(zone_players, renderers)
+            .map_err(ControllerError::TransportError)
+            .try_filter_map(Self::from_any_device))
+    }
+
+    /// For speakers we already know about or that were entered by hand.
+    async fn from_location(location: &str) -> Result<Option<Zone>, ControllerError> {
+        let url = location
+            .parse()
+            .map_err(|_| ControllerError::InvalidAddress(location.to_string()))?;
+        let device = Device::from_url(url).await?;
+        Self::from_any_device(device).await
+    }
+
+    /// The address can be a host name or IP address with an optional port, or a full URL.
+    /// IPv6 addresses go in brackets, with or without a port.
+    fn location_for_address(address: &str) -> String {
+        if address.contains("://") {
+            return address.to_string();
+        }
+        let host = if address.parse::<Ipv6Addr>().is_ok() {
+            format!("[{address}]:{SONOS_PORT}")
+        } else if address.ends_with(']') || !address.contains(':') {
+            format!("{address}:{SONOS_PORT}")
+        } else {
+            address.to_string()
+        };
+        format!("http://{host}/xml/device_description.xml")
+    }
+
+    /// The room name for Sonos speakers, or the device's friendly name for other renderers.
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn uuid(&self) -> &str {
+        let udn = self.primary_device.udn();
+        udn.strip_prefix("uuid:").unwrap_or(udn)
+    }
+
+    fn location(&self) -> String {
+        self.primary_device.url().to_string()
     }
 
-    pub async fn play(&self) -> Result<(), ControllerError> {
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    async fn play(&self) -> Result<(), ControllerError> {
         self.av_transport.play(&self.primary_device).await
     }
 
-    pub async fn next(&self) -> Result<(), ControllerError> {
+    async fn pause(&self) -> Result<(), ControllerError> {
+        self.av_transport.pause(&self.primary_device).await
+    }
+
+    async fn next(&self) -> Result<(), ControllerError> {
         self.av_transport.next(&self.primary_device).await
     }
 
-    pub async fn previous(&self) -> Result<(), ControllerError> {
+    async fn previous(&self) -> Result<(), ControllerError> {
         self.av_transport.previous(&self.primary_device).await
     }
 
-    pub async fn play_pause(&self) -> Result<(), ControllerError> {
-        match self.get_state().await? {
-            AVTransportState::Paused | AVTransportState::Stopped => self.play().await,
-            AVTransportState::Playing | AVTransportState::Transitioning => self.pause().await,
-        }
+    async fn seek(&self, position: Duration) -> Result<(), ControllerError> {
+        self.av_transport.seek(&self.primary_device, position).await
     }
 
-    pub async fn get_state(&self) -> Result<AVTransportState, ControllerError> {
+    async fn get_state(&self) -> Result<AVTransportState, ControllerError> {
         self.av_transport
             .get_transport_info(&self.primary_device)
             .await
     }
 
-    pub async fn get_volume(&self) -> Result<Volume, ControllerError> {
-        self.rendering_control
+    async fn get_position(&self) -> Result<PositionInfo, ControllerError> {
+        self.av_transport
+            .get_position_info(&self.primary_device)
+            .await
+    }
+
+    async fn get_volume(&self) -> Result<Volume, ControllerError> {
+        self.rendering_control("GetVolume")?
             .get_volume(&self.primary_device)
             .await
     }
 
-    pub async fn set_volume(&self, volume: &Volume) -> Result<(), ControllerError> {
-        self.rendering_control
-            .set_volume(&self.primary_device, &volume)
+    async fn get_sleep_timer(&self) -> Result<Option<Duration>, ControllerError> {
+        self.av_transport
+            .get_remaining_sleep_timer_duration(&self.primary_device)
             .await
     }
 
-    pub fn name(&self) -> &str {
-        self.primary_device.friendly_name()
+    async fn set_volume(&self, volume: &Volume) -> Result<(), ControllerError> {
+        self.rendering_control("SetVolume")?
+            .set_volume(&self.primary_device, volume)
+            .await
+    }
+
+    /// Albums and playlists replace the queue, like playing them from the Sonos app does.
+    async fn play_favorite(&self, name: &str) -> Result<(), ControllerError> {
+        let device = &self.primary_device;
+        let favorites = self
+            .content_directory
+            .as_ref()
+            .ok_or_else(|| ControllerError::Unsupported("Favorites".to_string()))?
+            .favorites(device)
+            .await?;
+        let favorite = favorites
+            .into_iter()
+            .find(|favorite| favorite.title.eq_ignore_ascii_case(name.trim()))
+            .ok_or_else(|| ControllerError::NoFavorite(name.to_string()))?;
+
+        let transport = &self.av_transport;
+        if favorite.is_container() {
+            transport.remove_all_tracks_from_queue(device).await?;
+            transport
+                .add_uri_to_queue(device, &favorite.uri, &favorite.metadata)
+                .await?;
+            let queue = format!("x-rincon-queue:{}#0", self.uuid());
+            transport.set_av_transport_uri(device, &queue, "").await?;
+        } else {
+            transport
+                .set_av_transport_uri(device, &favorite.uri, &favorite.metadata)
+                .await?;
+        }
+        self.play().await
+    }
+
+    /// Subscribes to transport events, renewing the subscription until we're done.
+    async fn watch_transport_state(
+        &self,
+        states: mpsc::Sender<AVTransportState>,
+    ) -> Result<(), ControllerError> {
+        let (sid, events) = self
+            .av_transport
+            .subscribe(&self.primary_device, SUBSCRIPTION_TIMEOUT_SECS)
+            .await?;
+        pin_mut!(events);
+
+        let mut renewal =
+            tokio::time::interval(Duration::from_secs(SUBSCRIPTION_TIMEOUT_SECS as u64 / 2));
+        renewal.tick().await; // the first tick completes immediately
+
+        let result = loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(Ok(state)) => {
+                        if states.send(state).await.is_err() {
+                            break Ok(());
+                        }
+                    }
+                    Some(Err(e)) => warn!("skipping malformed transport event: {e}"),
+                    None => break Ok(()),
+                },
+                _ = renewal.tick() => {
+                    if let Err(e) = self
+                        .av_transport
+                        .renew_subscription(&self.primary_device, &sid, SUBSCRIPTION_TIMEOUT_SECS)
+                        .await
+                    {
+                        break Err(e);
+                    }
+                }
+                _ = states.closed() => break Ok(()),
+            }
+        };
+
+        if let Err(e) = self
+            .av_transport
+            .unsubscribe(&self.primary_device, &sid)
+            .await
+        {
+            warn!("could not unsubscribe from transport events: {e}");
+        }
+        result
+    }
+
+    /// Sonos speakers ramp by themselves when one of their ramp types comes close to the
+    /// requested duration, otherwise (or on other renderers) we step the volume ourselves.
+    async fn ramp_volume(
+        &self,
+        target: &Volume,
+        duration: Duration,
+    ) -> Result<(), ControllerError> {
+        let current = self.get_volume().await?;
+        let Some(ramp_type) = RampType::for_ramp(&current, target, duration) else {
+            return player::step_volume(self, &current, target, duration).await;
+        };
+
+        match self
+            .rendering_control("RampToVolume")?
+            .ramp_to_volume(&self.primary_device, ramp_type, target)
+            .await
+        {
+            Ok(ramp_time) => {
+                tokio::time::sleep(ramp_time).await;
+                Ok(())
+            }
+            Err(ControllerError::Unsupported(_)) => {
+                player::step_volume(self, &current, target, duration).await
+            }
+            Err(e) => Err(e),
+        }
     }
 }
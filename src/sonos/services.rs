@@ -1,12 +1,16 @@
 use super::error::ControllerError;
-use rupnp::{ssdp::URN, Device, Service};
-use std::fmt::Display;
+use super::soap::{Arguments, FromResponse, Response, SoapService};
+use futures::{Stream, TryStreamExt};
+use rupnp::{ssdp::URN, Device};
+use serde::Deserialize;
+use std::{fmt::Display, time::Duration};
 
 #[derive(Debug, Clone)]
 pub struct AVTransport {
-    service: Service,
+    service: SoapService,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AVTransportState {
     Stopped,
     Playing,
@@ -18,99 +22,453 @@ impl AVTransport {
     const SERVICE_URN: URN = URN::service("schemas-upnp-org", "AVTransport", 1);
 
     pub fn from_device(device: &Device) -> Result<Self, ControllerError> {
-        let service =
-            device
-                .find_service(&Self::SERVICE_URN)
-                .ok_or(ControllerError::ServiceUnavailable(
-                    "AVTransport".to_string(),
-                    device.friendly_name().to_string(),
-                ))?;
-
         Ok(Self {
-            service: service.clone(),
+            service: SoapService::from_device(device, &Self::SERVICE_URN, "AVTransport")?,
         })
     }
 
+    pub async fn supports(&self, device: &Device, action: &str) -> bool {
+        self.service.supports(device, action).await
+    }
+
+    /// Whether the renderer tells subscribers about transport state changes, which it does
+    /// through `LastChange`.
+    pub async fn sends_events(&self, device: &Device) -> bool {
+        self.service.sends_events(device, "LastChange").await
+    }
+
     pub async fn pause(&self, device: &Device) -> Result<(), ControllerError> {
-        let payload = "<InstanceID>0</InstanceID>";
-        self.service.action(device.url(), "Pause", payload).await?;
-        Ok(())
+        self.service
+            .call(device, "Pause", Arguments::instance())
+            .await
     }
 
     pub async fn play(&self, device: &Device) -> Result<(), ControllerError> {
-        let payload = "<InstanceID>0</InstanceID><Speed>1</Speed>";
-        self.service.action(device.url(), "Play", payload).await?;
-        Ok(())
+        let args = Arguments::instance().arg("Speed", 1);
+        self.service.call(device, "Play", args).await
     }
 
     pub async fn previous(&self, device: &Device) -> Result<(), ControllerError> {
-        let payload = "<InstanceID>0</InstanceID>";
         self.service
-            .action(device.url(), "Previous", payload)
-            .await?;
-        Ok(())
+            .call(device, "Previous", Arguments::instance())
+            .await
     }
 
     pub async fn next(&self, device: &Device) -> Result<(), ControllerError> {
-        let payload = "<InstanceID>0</InstanceID>";
-        self.service.action(device.url(), "Next", payload).await?;
+        self.service
+            .call(device, "Next", Arguments::instance())
+            .await
+    }
+
+    pub async fn seek(&self, device: &Device, position: Duration) -> Result<(), ControllerError> {
+        let args = Arguments::instance()
+            .arg("Unit", "REL_TIME")
+            .arg("Target", format_duration(position));
+        self.service.call(device, "Seek", args).await
+    }
+
+    /// How long until the Sonos sleep timer pauses the zone, or `None` if it isn't set.
+    pub async fn get_remaining_sleep_timer_duration(
+        &self,
+        device: &Device,
+    ) -> Result<Option<Duration>, ControllerError> {
+        let response: Response = self
+            .service
+            .call(
+                device,
+                "GetRemainingSleepTimerDuration",
+                Arguments::instance(),
+            )
+            .await?;
+        Ok(parse_duration(response.get("RemainingSleepTimerDuration")?))
+    }
+
+    /// Makes the renderer play `uri` from now on, described by its DIDL-Lite `metadata`.
+    pub async fn set_av_transport_uri(
+        &self,
+        device: &Device,
+        uri: &str,
+        metadata: &str,
+    ) -> Result<(), ControllerError> {
+        let args = Arguments::instance()
+            .arg("CurrentURI", uri)
+            .arg("CurrentURIMetaData", metadata);
+        self.service.call(device, "SetAVTransportURI", args).await
+    }
+
+    /// Adds what `uri` points to, like an album or a playlist, to the end of the Sonos queue.
+    pub async fn add_uri_to_queue(
+        &self,
+        device: &Device,
+        uri: &str,
+        metadata: &str,
+    ) -> Result<(), ControllerError> {
+        let args = Arguments::instance()
+            .arg("EnqueuedURI", uri)
+            .arg("EnqueuedURIMetaData", metadata)
+            .arg("DesiredFirstTrackNumberEnqueued", 0)
+            .arg("EnqueueAsNext", 0);
+        let _: Response = self.service.call(device, "AddURIToQueue", args).await?;
         Ok(())
     }
 
+    pub async fn remove_all_tracks_from_queue(
+        &self,
+        device: &Device,
+    ) -> Result<(), ControllerError> {
+        self.service
+            .call(device, "RemoveAllTracksFromQueue", Arguments::instance())
+            .await
+    }
+
+    pub async fn get_position_info(
+        &self,
+        device: &Device,
+    ) -> Result<PositionInfo, ControllerError> {
+        self.service
+            .call(device, "GetPositionInfo", Arguments::instance())
+            .await
+    }
+
     pub async fn get_transport_info(
         &self,
         device: &Device,
     ) -> Result<AVTransportState, ControllerError> {
-        let payload = "<InstanceID>0</InstanceID>";
-        let resp = self
+        self.service
+            .call(device, "GetTransportInfo", Arguments::instance())
+            .await
+    }
+
+    /// Subscribes to the service's events. Returns the subscription ID, needed to renew the
+    /// subscription before `timeout_secs` runs out, and a stream of the transport states
+    /// reported by the device. Events that don't include a transport state are skipped.
+    pub async fn subscribe(
+        &self,
+        device: &Device,
+        timeout_secs: u32,
+    ) -> Result<
+        (
+            String,
+            impl Stream<Item = Result<AVTransportState, ControllerError>>,
+        ),
+        ControllerError,
+    > {
+        let (sid, events) = self
             .service
-            .action(device.url(), "GetTransportInfo", payload)
+            .service()
+            .subscribe(device.url(), timeout_secs)
+            .await?;
+        let states = events
+            .map_err(ControllerError::TransportError)
+            .try_filter_map(|vars| async move {
+                match vars.get("LastChange") {
+                    Some(last_change) => LastChange::transport_state(last_change),
+                    None => Ok(None),
+                }
+            });
+        Ok((sid, states))
+    }
+
+    pub async fn renew_subscription(
+        &self,
+        device: &Device,
+        sid: &str,
+        timeout_secs: u32,
+    ) -> Result<(), ControllerError> {
+        self.service
+            .service()
+            .renew_subscription(device.url(), sid, timeout_secs)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, device: &Device, sid: &str) -> Result<(), ControllerError> {
+        self.service
+            .service()
+            .unsubscribe(device.url(), sid)
             .await?;
-        match resp
-            .get("CurrentTransportState")
-            .ok_or(ControllerError::MalformedResponse)?
-            .to_uppercase()
-            .as_ref()
-        {
+        Ok(())
+    }
+}
+
+impl FromResponse for AVTransportState {
+    fn from_response(response: &Response) -> Result<Self, ControllerError> {
+        response.parse("CurrentTransportState")
+    }
+}
+
+impl std::str::FromStr for AVTransportState {
+    type Err = ControllerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_ref() {
             "STOPPED" => Ok(AVTransportState::Stopped),
             "PLAYING" => Ok(AVTransportState::Playing),
-            "PAUSED_PLAYBACK" => Ok(AVTransportState::Paused),
+            // nothing to play, e.g. after the queue was cleared
+            "NO_MEDIA_PRESENT" => Ok(AVTransportState::Stopped),
+            "PAUSED_PLAYBACK" | "PAUSED_RECORDING" => Ok(AVTransportState::Paused),
             "TRANSITIONING" => Ok(AVTransportState::Transitioning),
             _ => Err(ControllerError::MalformedResponse),
         }
     }
 }
 
+/// AVTransport events carry their state variables as an XML document in `LastChange`:
+/// `<Event><InstanceID val="0"><TransportState val="PLAYING"/>...</InstanceID></Event>`
+#[derive(Debug, Deserialize)]
+struct LastChange {
+    #[serde(rename = "InstanceID")]
+    instance: LastChangeInstance,
+}
+
+#[derive(Debug, Deserialize)]
+struct LastChangeInstance {
+    #[serde(rename = "TransportState")]
+    transport_state: Option<LastChangeValue>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LastChangeValue {
+    val: String,
+}
+
+impl LastChange {
+    fn transport_state(xml: &str) -> Result<Option<AVTransportState>, ControllerError> {
+        let last_change: LastChange =
+            serde_xml_rs::from_str(xml).map_err(|_| ControllerError::MalformedResponse)?;
+        last_change
+            .instance
+            .transport_state
+            .map(|state| state.val.parse())
+            .transpose()
+    }
+}
+
+/// The Sonos media server, which knows the household's favorites.
+#[derive(Debug, Clone)]
+pub struct ContentDirectory {
+    service: SoapService,
+}
+
+/// One of the favorites saved in the Sonos app: a station, an album, a playlist...
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Favorite {
+    pub title: String,
+    pub uri: String,
+    /// The DIDL-Lite metadata Sonos wants along with the URI.
+    pub metadata: String,
+}
+
+impl Favorite {
+    /// Whether the favorite holds tracks, which go through the queue, rather than being
+    /// something that plays by itself, like a station.
+    pub fn is_container(&self) -> bool {
+        self.uri.starts_with("x-rincon-cpcontainer:")
+            || didl_text(&self.metadata, "class")
+                .is_some_and(|class| class.starts_with("object.container"))
+    }
+}
+
+impl ContentDirectory {
+    const SERVICE_URN: URN = URN::service("schemas-upnp-org", "ContentDirectory", 1);
+    /// Where Sonos keeps the favorites.
+    const FAVORITES: &'static str = "FV:2";
+    /// More favorites than anyone keeps, so one browse gets them all.
+    const MAX_FAVORITES: u32 = 500;
+
+    pub fn from_device(device: &Device) -> Result<Self, ControllerError> {
+        Ok(Self {
+            service: SoapService::from_device(device, &Self::SERVICE_URN, "ContentDirectory")?,
+        })
+    }
+
+    pub async fn favorites(&self, device: &Device) -> Result<Vec<Favorite>, ControllerError> {
+        let args = Arguments::new()
+            .arg("ObjectID", Self::FAVORITES)
+            .arg("BrowseFlag", "BrowseDirectChildren")
+            .arg("Filter", "*")
+            .arg("StartingIndex", 0)
+            .arg("RequestedCount", Self::MAX_FAVORITES)
+            .arg("SortCriteria", "");
+        let response: Response = self.service.call(device, "Browse", args).await?;
+        parse_favorites(response.get("Result")?)
+    }
+}
+
+/// Reads favorites from the DIDL-Lite a browse returns. Favorites without a URI, like
+/// shortcuts to a whole music service, can't be played and are left out.
+fn parse_favorites(didl: &str) -> Result<Vec<Favorite>, ControllerError> {
+    let document =
+        roxmltree::Document::parse(didl).map_err(|_| ControllerError::MalformedResponse)?;
+    let favorites = document
+        .root_element()
+        .children()
+        .filter(|node| node.tag_name().name() == "item")
+        .filter_map(|item| {
+            let text = |name: &str| {
+                item.children()
+                    .find(|node| node.tag_name().name() == name)
+                    .and_then(|node| node.text())
+                    .map(str::trim)
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let favorite = Favorite {
+                title: text("title"),
+                uri: text("res"),
+                metadata: text("resMD"),
+            };
+            (!favorite.uri.is_empty()).then_some(favorite)
+        })
+        .collect();
+    Ok(favorites)
+}
+
+/// Sonos-specific device settings, such as the name of the room the speaker is in.
+#[derive(Debug, Clone)]
+pub struct DeviceProperties {
+    service: SoapService,
+}
+
+impl DeviceProperties {
+    const SERVICE_URN: URN = URN::service("schemas-upnp-org", "DeviceProperties", 1);
+
+    pub fn from_device(device: &Device) -> Result<Self, ControllerError> {
+        Ok(Self {
+            service: SoapService::from_device(device, &Self::SERVICE_URN, "DeviceProperties")?,
+        })
+    }
+
+    pub async fn get_zone_name(&self, device: &Device) -> Result<String, ControllerError> {
+        let response: Response = self
+            .service
+            .call(device, "GetZoneAttributes", Arguments::new())
+            .await?;
+        response.parse("CurrentZoneName")
+    }
+}
+
+/// Sonos-specific, tells us how speakers are grouped into zones.
+#[derive(Debug, Clone)]
+pub struct ZoneGroupTopology {
+    service: SoapService,
+}
+
+impl ZoneGroupTopology {
+    pub const SERVICE_URN: URN = URN::service("schemas-upnp-org", "ZoneGroupTopology", 1);
+
+    pub fn from_device(device: &Device) -> Result<Self, ControllerError> {
+        Ok(Self {
+            service: SoapService::from_device(device, &Self::SERVICE_URN, "ZoneGroupTopology")?,
+        })
+    }
+
+    /// The ID of the group the speaker belongs to, or `None` if it isn't part of a zone.
+    pub async fn get_zone_group_id(
+        &self,
+        device: &Device,
+    ) -> Result<Option<String>, ControllerError> {
+        let response: Response = self
+            .service
+            .call(device, "GetZoneGroupAttributes", Arguments::new())
+            .await?;
+        Ok(response.get("CurrentZoneGroupID").ok().map(str::to_string))
+    }
+}
+
+/// Where playback is in the current track, and what it is. Streams such as radio report no
+/// duration, and sometimes no elapsed time either.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PositionInfo {
+    pub track: u32,
+    pub duration: Option<Duration>,
+    pub elapsed: Option<Duration>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+impl FromResponse for PositionInfo {
+    fn from_response(response: &Response) -> Result<Self, ControllerError> {
+        Ok(PositionInfo {
+            track: response.parse("Track")?,
+            duration: response.get("TrackDuration").ok().and_then(parse_duration),
+            elapsed: response.get("RelTime").ok().and_then(parse_duration),
+            ..parse_metadata(response.get("TrackMetaData").unwrap_or_default())
+        })
+    }
+}
+
+/// Reads the title and artist from a track's DIDL-Lite metadata. Radio stations put what's
+/// on in `r:streamContent`, which is more interesting than the station's name.
+fn parse_metadata(metadata: &str) -> PositionInfo {
+    let mut info = PositionInfo {
+        track: 0,
+        duration: None,
+        elapsed: None,
+        title: None,
+        artist: None,
+    };
+    let text = |name: &str| didl_text(metadata, name);
+    info.title = text("streamContent").or_else(|| text("title"));
+    info.artist = text("creator");
+    info
+}
+
+/// The text of the first element called `name` in a DIDL-Lite document, if it has any.
+fn didl_text(didl: &str, name: &str) -> Option<String> {
+    let document = roxmltree::Document::parse(didl).ok()?;
+    document
+        .descendants()
+        .find(|node| node.tag_name().name() == name)
+        .and_then(|node| node.text())
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(str::to_string)
+}
+
+/// Parses UPnP `H+:MM:SS[.F+]` durations, ignoring values such as `NOT_IMPLEMENTED`.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let mut parts = value.split(':');
+    let hours: u64 = parts.next()?.parse().ok()?;
+    let minutes: u64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Duration::from_secs(hours * 3600 + minutes * 60) + Duration::from_secs_f64(seconds))
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
 #[derive(Debug, Clone)]
 pub struct RenderingControl {
-    service: Service,
+    service: SoapService,
 }
 
 impl RenderingControl {
     const SERVICE_URN: URN = URN::service("schemas-upnp-org", "RenderingControl", 1);
 
     pub fn from_device(device: &Device) -> Result<Self, ControllerError> {
-        let service =
-            device
-                .find_service(&Self::SERVICE_URN)
-                .ok_or(ControllerError::ServiceUnavailable(
-                    "RenderingControl".to_string(),
-                    device.friendly_name().to_string(),
-                ))?;
-
         Ok(Self {
-            service: service.clone(),
+            service: SoapService::from_device(device, &Self::SERVICE_URN, "RenderingControl")?,
         })
     }
 
+    pub async fn supports(&self, device: &Device, action: &str) -> bool {
+        self.service.supports(device, action).await
+    }
+
     pub async fn get_volume(&self, device: &Device) -> Result<Volume, ControllerError> {
-        let payload = "<InstanceID>0</InstanceID><Channel>Master</Channel>";
-        let resp = self
-            .service
-            .action(device.url(), "GetVolume", payload)
-            .await?;
-        let volume = resp.get("CurrentVolume");
-        Ok(Volume::try_from(volume)?)
+        let args = Arguments::instance().arg("Channel", "Master");
+        self.service.call(device, "GetVolume", args).await
     }
 
     pub async fn set_volume(
@@ -118,18 +476,99 @@ impl RenderingControl {
         device: &Device,
         volume: &Volume,
     ) -> Result<(), ControllerError> {
-        let payload = format!(
-            "<InstanceID>0</InstanceID><Channel>Master</Channel><DesiredVolume>{}</DesiredVolume>",
-            &volume.value()
-        );
+        let args = Arguments::instance()
+            .arg("Channel", "Master")
+            .arg("DesiredVolume", volume);
+        self.service.call(device, "SetVolume", args).await
+    }
 
-        self.service
-            .action(device.url(), "GetVolume", &payload)
-            .await?;
-        Ok(())
+    /// Asks the speaker to ramp its volume by itself. Returns how long the ramp will take.
+    ///
+    /// `RampToVolume` is a Sonos extension, so other renderers will answer with
+    /// [`ControllerError::Unsupported`].
+    pub async fn ramp_to_volume(
+        &self,
+        device: &Device,
+        ramp_type: RampType,
+        volume: &Volume,
+    ) -> Result<Duration, ControllerError> {
+        let args = Arguments::instance()
+            .arg("Channel", "Master")
+            .arg("RampType", ramp_type)
+            .arg("DesiredVolume", volume)
+            .arg("ResetVolumeAfter", 0)
+            .arg("ProgramURI", "");
+        let response: Response = self.service.call(device, "RampToVolume", args).await?;
+        Ok(Duration::from_secs(response.parse("RampTime")?))
     }
 }
 
+/// The ramp types understood by the Sonos `RampToVolume` action. The speaker decides the ramp
+/// speed by itself, we can only pick the type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RampType {
+    /// Ramps from the current volume at about 1.25 steps per second.
+    SleepTimer,
+    /// Resets the volume to zero and ramps up at about 2.5 steps per second.
+    Alarm,
+    /// Resets the volume to zero and ramps up almost immediately.
+    Autoplay,
+}
+
+impl RampType {
+    fn steps_per_second(&self) -> f32 {
+        match self {
+            RampType::SleepTimer => 1.25,
+            RampType::Alarm => 2.5,
+            RampType::Autoplay => 50.0,
+        }
+    }
+
+    fn starts_from_zero(&self) -> bool {
+        !matches!(self, RampType::SleepTimer)
+    }
+
+    /// Roughly how long the speaker takes to ramp between the two volumes.
+    pub fn duration(&self, from: &Volume, to: &Volume) -> Duration {
+        let from = if self.starts_from_zero() {
+            0
+        } else {
+            from.value()
+        };
+        let steps = from.abs_diff(to.value());
+        Duration::from_secs_f32(steps as f32 / self.steps_per_second())
+    }
+
+    /// Picks the ramp type that gets from `from` to `to` in about `duration`, or `None` if none
+    /// of them comes close enough and the ramp should be stepped by hand instead.
+    pub fn for_ramp(from: &Volume, to: &Volume, duration: Duration) -> Option<Self> {
+        [RampType::SleepTimer, RampType::Alarm, RampType::Autoplay]
+            .into_iter()
+            // jumping down to zero first would defeat the purpose of ramping
+            .filter(|ramp_type| !ramp_type.starts_from_zero() || from.value() == 0)
+            .filter(|ramp_type| {
+                let expected = ramp_type.duration(from, to).as_secs_f32();
+                (expected - duration.as_secs_f32()).abs() <= duration.as_secs_f32() / 2.0
+            })
+            .min_by(|a, b| {
+                let a = (a.duration(from, to).as_secs_f32() - duration.as_secs_f32()).abs();
+                let b = (b.duration(from, to).as_secs_f32() - duration.as_secs_f32()).abs();
+                a.total_cmp(&b)
+            })
+    }
+}
+
+impl Display for RampType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RampType::SleepTimer => "SLEEP_TIMER_RAMP_TYPE",
+            RampType::Alarm => "ALARM_RAMP_TYPE",
+            RampType::Autoplay => "AUTOPLAY_RAMP_TYPE",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Volume(u8);
 
 impl Volume {
@@ -148,16 +587,17 @@ impl Volume {
     }
 }
 
-impl TryFrom<Option<&String>> for Volume {
-    type Error = ControllerError;
-
-    fn try_from(value: Option<&String>) -> Result<Self, ControllerError> {
-        let value = value.ok_or(ControllerError::VolumeError)?;
+impl FromResponse for Volume {
+    fn from_response(response: &Response) -> Result<Self, ControllerError> {
+        let value = response
+            .get("CurrentVolume")
+            .map_err(|_| ControllerError::VolumeError)?;
         Ok(Self::new(
             value.parse().map_err(|_| ControllerError::VolumeError)?,
         ))
     }
 }
+
 impl Display for Volume {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("{}", self.value()))
@@ -0,0 +1,300 @@
+use hyper::{header, Body, Client, Request, StatusCode};
+use log::warn;
+use roxmltree::{Document, Node};
+use rupnp::{http::Uri, ssdp::URN, Device, Service};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+
+use super::error::ControllerError;
+
+/// The arguments of a SOAP action, in the order the service declares them.
+#[derive(Debug, Clone, Default)]
+pub struct Arguments(Vec<(&'static str, String)>);
+
+impl Arguments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts with `InstanceID` 0, the only instance Sonos (and most renderers) have.
+    pub fn instance() -> Self {
+        Self::new().arg("InstanceID", 0)
+    }
+
+    pub fn arg(mut self, name: &'static str, value: impl Display) -> Self {
+        self.0.push((name, value.to_string()));
+        self
+    }
+
+    fn to_xml(&self) -> String {
+        self.0
+            .iter()
+            .map(|(name, value)| format!("<{name}>{}</{name}>", escape(value)))
+            .collect()
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The output arguments of a SOAP action, by name.
+#[derive(Debug, Clone)]
+pub struct Response(HashMap<String, String>);
+
+impl Response {
+    pub fn get(&self, name: &str) -> Result<&str, ControllerError> {
+        self.0
+            .get(name)
+            .map(String::as_str)
+            .ok_or(ControllerError::MalformedResponse)
+    }
+
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T, ControllerError> {
+        self.get(name)?
+            .parse()
+            .map_err(|_| ControllerError::MalformedResponse)
+    }
+}
+
+/// Types that can be read from the response to a SOAP action.
+pub trait FromResponse: Sized {
+    fn from_response(response: &Response) -> Result<Self, ControllerError>;
+}
+
+impl FromResponse for () {
+    fn from_response(_: &Response) -> Result<Self, ControllerError> {
+        Ok(())
+    }
+}
+
+impl FromResponse for Response {
+    fn from_response(response: &Response) -> Result<Self, ControllerError> {
+        Ok(response.clone())
+    }
+}
+
+/// A UPnP service we call typed actions on. Actions are checked against the service's SCPD
+/// before they're sent, so unsupported actions fail early with [`ControllerError::Unsupported`].
+#[derive(Debug, Clone)]
+pub struct SoapService {
+    service: Service,
+    /// What the SCPD says, fetched on first use. `None` if the SCPD couldn't be read, in which
+    /// case we send actions without checking them.
+    description: Arc<OnceCell<Option<Description>>>,
+    /// Where actions are sent, read from the device description on first use. rupnp knows
+    /// this too but doesn't tell.
+    control_url: Arc<OnceCell<Uri>>,
+}
+
+/// The parts of an SCPD we care about.
+#[derive(Debug)]
+struct Description {
+    actions: HashSet<String>,
+    /// State variables the service sends events for.
+    evented: HashSet<String>,
+}
+
+impl SoapService {
+    pub fn from_device(device: &Device, urn: &URN, name: &str) -> Result<Self, ControllerError> {
+        let service = device
+            .find_service(urn)
+            .ok_or(ControllerError::ServiceUnavailable(
+                name.to_string(),
+                device.friendly_name().to_string(),
+            ))?;
+
+        Ok(Self {
+            service: service.clone(),
+            description: Default::default(),
+            control_url: Default::default(),
+        })
+    }
+
+    pub fn service(&self) -> &Service {
+        &self.service
+    }
+
+    async fn description(&self, device: &Device) -> Option<&Description> {
+        self.description
+            .get_or_init(|| async {
+                match self.service.scpd(device.url()).await {
+                    Ok(scpd) => Some(Description {
+                        actions: scpd.actions().iter().map(|a| a.name().clone()).collect(),
+                        evented: scpd
+                            .state_variables()
+                            .iter()
+                            .filter(|variable| variable.sends_events())
+                            .map(|variable| variable.name().to_string())
+                            .collect(),
+                    }),
+                    Err(e) => {
+                        warn!(
+                            "could not read SCPD of {}: {e}",
+                            self.service.service_type()
+                        );
+                        None
+                    }
+                }
+            })
+            .await
+            .as_ref()
+    }
+
+    /// Whether the service lists the action in its SCPD. Assumes it does if the SCPD can't
+    /// be read.
+    pub async fn supports(&self, device: &Device, action: &str) -> bool {
+        self.description(device)
+            .await
+            .is_none_or(|description| description.actions.contains(action))
+    }
+
+    /// Whether the service sends events when the state variable changes. Assumes it does if
+    /// the SCPD can't be read.
+    pub async fn sends_events(&self, device: &Device, variable: &str) -> bool {
+        self.description(device)
+            .await
+            .is_none_or(|description| description.evented.contains(variable))
+    }
+
+    /// Sends an action and reads its output arguments.
+    ///
+    /// This doesn't go through [`Service::action`], because speakers answer faults with an
+    /// HTTP 500 and rupnp gives up on those before reading the fault, which is where the
+    /// useful error code is.
+    pub async fn call<T: FromResponse>(
+        &self,
+        device: &Device,
+        action: &str,
+        args: Arguments,
+    ) -> Result<T, ControllerError> {
+        if !self.supports(device, action).await {
+            return Err(ControllerError::Unsupported(action.to_string()));
+        }
+
+        let control_url = self
+            .control_url
+            .get_or_try_init(|| self.find_control_url(device))
+            .await?;
+        let service_type = self.service.service_type();
+        let envelope = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:{action} xmlns:u="{service_type}">{}</u:{action}></s:Body></s:Envelope>"#,
+            args.to_xml()
+        );
+        let request = Request::post(control_url.clone())
+            .header(header::CONTENT_TYPE, "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{service_type}#{action}\""))
+            .body(Body::from(envelope))
+            .map_err(|_| ControllerError::MalformedResponse)?;
+
+        let (status, body) = send(request).await?;
+        let document = match Document::parse(&body) {
+            Ok(document) => document,
+            Err(_) if !status.is_success() => {
+                return Err(rupnp::Error::HttpErrorCode(status).into())
+            }
+            Err(e) => return Err(rupnp::Error::from(e).into()),
+        };
+
+        let response = document
+            .descendants()
+            .find(|node| node.tag_name().name() == "Body")
+            .and_then(|body| body.first_element_child());
+        match response {
+            Some(fault) if fault.tag_name().name() == "Fault" => Err(read_fault(action, fault)),
+            Some(response) if status.is_success() => {
+                let values = response
+                    .children()
+                    .filter(Node::is_element)
+                    .map(|node| {
+                        let value = node.text().unwrap_or_default().to_string();
+                        (node.tag_name().name().to_string(), value)
+                    })
+                    .collect();
+                T::from_response(&Response(values))
+            }
+            _ if !status.is_success() => Err(rupnp::Error::HttpErrorCode(status).into()),
+            _ => Err(ControllerError::MalformedResponse),
+        }
+    }
+
+    async fn find_control_url(&self, device: &Device) -> Result<Uri, ControllerError> {
+        let request = Request::get(device.url().clone())
+            .body(Body::empty())
+            .map_err(|_| ControllerError::MalformedResponse)?;
+        let (status, body) = send(request).await?;
+        if !status.is_success() {
+            return Err(rupnp::Error::HttpErrorCode(status).into());
+        }
+
+        let document = Document::parse(&body).map_err(rupnp::Error::from)?;
+        let service_type = self.service.service_type().to_string();
+        let path = document
+            .descendants()
+            .filter(|node| node.tag_name().name() == "service")
+            .find(|service| child_text(*service, "serviceType") == Some(&service_type))
+            .and_then(|service| child_text(service, "controlURL"))
+            .ok_or(ControllerError::MalformedResponse)?;
+
+        // control URLs are relative to the device, like rupnp treats them
+        let mut parts = device.url().clone().into_parts();
+        parts.path_and_query = Some(
+            path.parse()
+                .map_err(|_| ControllerError::MalformedResponse)?,
+        );
+        Uri::from_parts(parts).map_err(|_| ControllerError::MalformedResponse)
+    }
+}
+
+async fn send(request: Request<Body>) -> Result<(StatusCode, String), ControllerError> {
+    let response = Client::new()
+        .request(request)
+        .await
+        .map_err(rupnp::Error::from)?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(rupnp::Error::from)?;
+    let body = String::from_utf8(body.to_vec()).map_err(|e| rupnp::Error::from(e.utf8_error()))?;
+    Ok((status, body))
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|child| child.tag_name().name() == name)
+        .and_then(|child| child.text())
+        .map(str::trim)
+}
+
+/// Reads the UPnP error code out of a SOAP fault.
+fn read_fault(action: &str, fault: Node) -> ControllerError {
+    let detail = |name: &str| {
+        fault
+            .descendants()
+            .find(|node| node.tag_name().name() == name)
+            .and_then(|node| node.text())
+            .map(str::trim)
+    };
+    let Some(code) = detail("errorCode").and_then(|code| code.parse().ok()) else {
+        return ControllerError::MalformedResponse;
+    };
+    let description = detail("errorDescription")
+        .or_else(|| detail("faultstring"))
+        .unwrap_or_default();
+    ControllerError::from_fault(action, code, description)
+}
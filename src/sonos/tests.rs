@@ -0,0 +1,345 @@
+//! Runs zones against a [`FakeSpeaker`] over real HTTP, so the SOAP plumbing is covered end
+//! to end without a Sonos on the network.
+
+use std::future;
+use std::time::Duration;
+
+use super::fake::{FakeKind, FakeSpeaker};
+use super::{AVTransportState, ControllerError, Household, Volume, Zone};
+use crate::player::{Capabilities, Player};
+
+async fn zone(speaker: &FakeSpeaker) -> Zone {
+    Zone::from_location(&speaker.location())
+        .await
+        .expect("fake speaker should answer")
+        .expect("fake speaker should be part of a zone")
+}
+
+#[tokio::test]
+async fn reads_room_name() {
+    let speaker = FakeSpeaker::start("Living Room").await;
+    let zone = zone(&speaker).await;
+
+    assert_eq!(zone.name(), "Living Room");
+    assert_eq!(zone.location(), speaker.location());
+    assert!(zone.uuid().starts_with("RINCON_FAKE"));
+}
+
+#[tokio::test]
+async fn sonos_can_do_everything() {
+    let speaker = FakeSpeaker::start("Living Room").await;
+    assert_eq!(zone(&speaker).await.capabilities(), Capabilities::ALL);
+}
+
+#[tokio::test]
+async fn reads_renderer_capabilities() {
+    let renderer = FakeSpeaker::start_as(FakeKind::Renderer, "Hifi").await;
+    let zone = zone(&renderer).await;
+
+    assert_eq!(zone.name(), "Hifi");
+    assert_eq!(
+        zone.capabilities(),
+        Capabilities {
+            skip: false,
+            seek: false,
+            volume: true,
+            events: false,
+        }
+    );
+}
+
+#[tokio::test]
+async fn renderer_refuses_what_it_cannot_do() {
+    let renderer = FakeSpeaker::start_as(FakeKind::Renderer, "Hifi").await;
+    let zone = zone(&renderer).await;
+
+    assert!(matches!(
+        zone.restart_or_previous(Duration::from_secs(3)).await,
+        Err(ControllerError::Unsupported(action)) if action == "Previous"
+    ));
+    // unsupported actions are caught before they're sent
+    assert!(renderer.state().calls.is_empty());
+}
+
+#[tokio::test]
+async fn renderer_fades_by_stepping() {
+    let renderer = FakeSpeaker::start_as(FakeKind::Renderer, "Hifi").await;
+    renderer.state().transport = AVTransportState::Playing;
+    let zone = zone(&renderer).await;
+
+    zone.fade_out_and_pause(Duration::from_millis(300), future::pending())
+        .await
+        .unwrap();
+
+    let state = renderer.state();
+    assert_eq!(state.transport, AVTransportState::Paused);
+    assert_eq!(state.volume, 20);
+    assert!(
+        state
+            .calls
+            .iter()
+            .filter(|call| *call == "SetVolume")
+            .count()
+            > 1
+    );
+}
+
+#[tokio::test]
+async fn play_pause_toggles() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    let zone = zone(&speaker).await;
+
+    zone.play_pause().await.unwrap();
+    assert_eq!(zone.get_state().await.unwrap(), AVTransportState::Playing);
+    zone.play_pause().await.unwrap();
+    assert_eq!(speaker.state().transport, AVTransportState::Paused);
+}
+
+#[tokio::test]
+async fn sets_volume() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    let zone = zone(&speaker).await;
+
+    zone.set_volume(&Volume::new(42)).await.unwrap();
+    assert_eq!(speaker.state().volume, 42);
+    assert_eq!(zone.get_volume().await.unwrap().value(), 42);
+}
+
+#[tokio::test]
+async fn reads_sleep_timer() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    let zone = zone(&speaker).await;
+
+    assert_eq!(zone.get_sleep_timer().await.unwrap(), None);
+    speaker.state().sleep_timer = Some(Duration::from_secs(1500));
+    assert_eq!(
+        zone.get_sleep_timer().await.unwrap(),
+        Some(Duration::from_secs(1500))
+    );
+}
+
+#[tokio::test]
+async fn skips_tracks() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    let zone = zone(&speaker).await;
+
+    zone.next().await.unwrap();
+    zone.next().await.unwrap();
+    let position = zone.get_position().await.unwrap();
+    assert_eq!(position.track, 3);
+    assert_eq!(position.title, Some(speaker.state().queue[2].title.clone()));
+    assert_eq!(position.artist.as_deref(), Some("Fake Band"));
+    assert!(matches!(
+        zone.next().await,
+        Err(ControllerError::TransitionNotAvailable(_))
+    ));
+
+    zone.previous().await.unwrap();
+    assert_eq!(speaker.state().track, 1);
+}
+
+#[tokio::test]
+async fn restarts_or_goes_back() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    let zone = zone(&speaker).await;
+    let threshold = Duration::from_secs(3);
+
+    {
+        let mut state = speaker.state();
+        state.track = 1;
+        state.elapsed = Duration::from_secs(60);
+    }
+    zone.restart_or_previous(threshold).await.unwrap();
+    assert_eq!(speaker.state().track, 1);
+    assert_eq!(speaker.state().elapsed, Duration::ZERO);
+
+    zone.restart_or_previous(threshold).await.unwrap();
+    assert_eq!(speaker.state().track, 0);
+}
+
+#[tokio::test]
+async fn cannot_skip_radio() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    speaker.state().queue.clear();
+    let zone = zone(&speaker).await;
+
+    assert!(matches!(
+        zone.next().await,
+        Err(ControllerError::TransitionNotAvailable(_))
+    ));
+    assert_eq!(zone.get_position().await.unwrap().elapsed, None);
+}
+
+#[tokio::test]
+async fn cannot_seek_past_track() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    let zone = zone(&speaker).await;
+
+    assert!(matches!(
+        zone.seek(Duration::from_secs(3600)).await,
+        Err(ControllerError::IllegalSeekTarget)
+    ));
+    zone.seek(Duration::from_secs(90)).await.unwrap();
+    assert_eq!(speaker.state().elapsed, Duration::from_secs(90));
+}
+
+#[tokio::test]
+async fn plays_favorites() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    let zone = zone(&speaker).await;
+
+    zone.play_favorite("jazz").await.unwrap();
+    {
+        let state = speaker.state();
+        assert_eq!(state.uri, "x-sonosapi-stream:s1234?sid=254");
+        assert_eq!(state.transport, AVTransportState::Playing);
+        assert_eq!(state.queue.len(), 3, "streams leave the queue alone");
+    }
+
+    zone.play_favorite("Road Trip").await.unwrap();
+    let state = speaker.state();
+    assert!(state.uri.starts_with("x-rincon-queue:RINCON_FAKE"));
+    assert_eq!(state.queue.len(), 1);
+    assert_eq!(
+        state.queue[0].title,
+        "x-rincon-cpcontainer:1006206cplaylist"
+    );
+    assert_eq!(state.transport, AVTransportState::Playing);
+}
+
+#[tokio::test]
+async fn refuses_unknown_favorites() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    assert!(matches!(
+        zone(&speaker).await.play_favorite("Polka").await,
+        Err(ControllerError::NoFavorite(name)) if name == "Polka"
+    ));
+    assert!(!speaker.state().calls.iter().any(|call| call == "Play"));
+
+    let renderer = FakeSpeaker::start_as(FakeKind::Renderer, "Hifi").await;
+    assert!(matches!(
+        zone(&renderer).await.play_favorite("Jazz").await,
+        Err(ControllerError::Unsupported(_))
+    ));
+}
+
+#[tokio::test]
+async fn fades_out_and_restores_volume() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    speaker.state().transport = AVTransportState::Playing;
+    let zone = zone(&speaker).await;
+
+    zone.fade_out_and_pause(Duration::from_millis(300), future::pending())
+        .await
+        .unwrap();
+
+    let state = speaker.state();
+    assert_eq!(state.transport, AVTransportState::Paused);
+    assert_eq!(state.volume, 20);
+    assert!(state.calls.iter().any(|call| call == "SetVolume"));
+}
+
+#[tokio::test]
+async fn cuts_fades_short_when_asked() {
+    let renderer = FakeSpeaker::start_as(FakeKind::Renderer, "Hifi").await;
+    renderer.state().transport = AVTransportState::Playing;
+    let zone = zone(&renderer).await;
+
+    let stop = tokio::time::sleep(Duration::from_millis(100));
+    let fade = zone.fade_out_and_pause(Duration::from_secs(60), stop);
+    tokio::time::timeout(Duration::from_secs(5), fade)
+        .await
+        .expect("the fade should stop early")
+        .unwrap();
+
+    let state = renderer.state();
+    assert_eq!(state.transport, AVTransportState::Paused);
+    assert_eq!(state.volume, 20);
+}
+
+#[tokio::test]
+async fn fade_out_leaves_paused_speaker_alone() {
+    let speaker = FakeSpeaker::start("Kitchen").await;
+    let zone = zone(&speaker).await;
+
+    zone.fade_out_and_pause(Duration::from_millis(300), future::pending())
+        .await
+        .unwrap();
+    assert!(!speaker.state().calls.iter().any(|call| call == "Pause"));
+}
+
+#[tokio::test]
+async fn finds_known_locations() {
+    let speaker = FakeSpeaker::start("Office").await;
+    let household = Household::<Zone>::default();
+
+    assert!(
+        household
+            .discover(Duration::from_millis(200), &[speaker.location()])
+            .await
+    );
+    assert_eq!(
+        household.find("Office").unwrap().location(),
+        speaker.location()
+    );
+}
+
+#[test]
+fn reads_addresses() {
+    let location = |address| Zone::location_for_address(address);
+    let path = "/xml/device_description.xml";
+    assert_eq!(location("10.0.0.2"), format!("http://10.0.0.2:1400{path}"));
+    assert_eq!(
+        location("hifi.local:8080"),
+        format!("http://hifi.local:8080{path}")
+    );
+    assert_eq!(location("fd00::2"), format!("http://[fd00::2]:1400{path}"));
+    assert_eq!(
+        location("[fd00::2]"),
+        format!("http://[fd00::2]:1400{path}")
+    );
+    assert_eq!(
+        location("[fd00::2]:8080"),
+        format!("http://[fd00::2]:8080{path}")
+    );
+    assert_eq!(location("http://hifi/desc.xml"), "http://hifi/desc.xml");
+}
+
+#[test]
+fn reads_transport_states() {
+    let state = |s: &str| s.parse::<AVTransportState>().ok();
+    assert_eq!(state("PLAYING"), Some(AVTransportState::Playing));
+    assert_eq!(state("PAUSED_PLAYBACK"), Some(AVTransportState::Paused));
+    assert_eq!(state("PAUSED_RECORDING"), Some(AVTransportState::Paused));
+    assert_eq!(state("STOPPED"), Some(AVTransportState::Stopped));
+    assert_eq!(state("NO_MEDIA_PRESENT"), Some(AVTransportState::Stopped));
+    assert_eq!(
+        state("TRANSITIONING"),
+        Some(AVTransportState::Transitioning)
+    );
+    assert_eq!(state("RECORDING"), None);
+}
+
+#[tokio::test]
+async fn discovers_over_ssdp() {
+    let speaker = FakeSpeaker::start("Bedroom").await;
+    let renderer = FakeSpeaker::start_as(FakeKind::Renderer, "Hifi").await;
+    speaker
+        .answer_searches()
+        .and_then(|_| renderer.answer_searches())
+        .expect("port 1900 should be free, stop any SSDP service on this machine");
+    let household = Household::<Zone>::default();
+
+    household.discover(Duration::from_secs(1), &[]).await;
+    let zones = household.zones();
+    for location in [speaker.location(), renderer.location()] {
+        // the speaker answers both searches, but is only one zone
+        assert_eq!(
+            zones
+                .iter()
+                .filter(|zone| zone.location() == location)
+                .count(),
+            1
+        );
+    }
+}
@@ -18,5 +18,11 @@ pub enum StreamDeckError {
     #[error("handler failed: {}", .0)]
     HandlerFailed(String),
     #[error("error reading from websockets")]
-    ReadError(#[from] tokio_tungstenite::tungstenite::Error),
+    ReadError(#[from] Box<tokio_tungstenite::tungstenite::Error>),
+    #[error("can't connect to the Stream Deck")]
+    ConnectionFailed(#[source] Box<tokio_tungstenite::tungstenite::Error>),
+    #[error("invalid arguments: {}", .0)]
+    InvalidArguments(String),
+    #[error("can't draw key image: {}", .0)]
+    ImageFailed(String),
 }
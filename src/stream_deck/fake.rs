@@ -0,0 +1,116 @@
+//! A fake Stream Deck app for tests. It listens for the plugin's WebSocket connection on
+//! loopback like the real app does, checks the registration, and lets tests send events and
+//! read back what the plugin sent.
+
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+use super::launch::{Info, LaunchArgs};
+
+/// How long we wait for the plugin to send something before failing the test.
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct FakeStreamDeck {
+    listener: TcpListener,
+}
+
+impl FakeStreamDeck {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self { listener }
+    }
+
+    /// The arguments the app would start the plugin with.
+    pub fn launch_args(&self, plugin_uuid: &str) -> LaunchArgs {
+        LaunchArgs {
+            port: self.listener.local_addr().unwrap().port(),
+            plugin_uuid: plugin_uuid.to_string(),
+            register_event: "registerPlugin".to_string(),
+            info: Info::default(),
+        }
+    }
+
+    /// Waits for the plugin to connect and register with the given uuid.
+    pub async fn accept(&self, plugin_uuid: &str) -> FakeSession {
+        let (stream, _) = tokio::time::timeout(EXPECT_TIMEOUT, self.listener.accept())
+            .await
+            .expect("the plugin didn't connect")
+            .unwrap();
+        let socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let mut session = FakeSession { socket };
+
+        let registration = session.next().await;
+        assert_eq!(registration["event"], "registerPlugin");
+        assert_eq!(registration["uuid"], plugin_uuid);
+        session
+    }
+}
+
+/// The app's side of one plugin connection.
+pub struct FakeSession {
+    socket: WebSocketStream<TcpStream>,
+}
+
+impl FakeSession {
+    pub async fn send(&mut self, event: Value) {
+        self.socket
+            .send(Message::Text(event.to_string()))
+            .await
+            .unwrap();
+    }
+
+    /// The next message from the plugin, skipping pings.
+    pub async fn next(&mut self) -> Value {
+        loop {
+            let message = tokio::time::timeout(EXPECT_TIMEOUT, self.socket.next())
+                .await
+                .expect("the plugin didn't send anything")
+                .expect("the plugin disconnected")
+                .unwrap();
+            match message {
+                Message::Text(json) => return serde_json::from_str(&json).unwrap(),
+                // registration goes out as binary, see `SendEvent::is_binary`
+                Message::Binary(json) => return serde_json::from_slice(&json).unwrap(),
+                Message::Close(_) => panic!("the plugin closed the connection"),
+                _ => {}
+            }
+        }
+    }
+
+    /// The next message with the given event name, skipping anything else like log messages.
+    pub async fn expect(&mut self, event: &str) -> Value {
+        loop {
+            let message = self.next().await;
+            if message["event"] == event {
+                return message;
+            }
+        }
+    }
+
+    /// Like the app quitting.
+    pub async fn close(mut self) {
+        self.socket.close(None).await.unwrap();
+        // the plugin answers the close, and then it's gone
+        while let Some(Ok(_)) = self.socket.next().await {}
+    }
+}
+
+/// An event for a key, the way the app sends it.
+pub fn key_event(event: &str, action: &str, context: &str, settings: Value) -> Value {
+    json!({
+        "event": event,
+        "action": action,
+        "context": context,
+        "device": "DEVICE",
+        "payload": {
+            "settings": settings,
+            "coordinates": {"column": 0, "row": 0},
+            "controller": "Keypad",
+            "isInMultiAction": false,
+        },
+    })
+}
@@ -1,12 +1,36 @@
-use log::{error, info};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use log::{debug, error, info, warn};
 use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
-use tokio::sync::mpsc;
+use std::future::Future;
+use std::mem::discriminant;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinSet;
 
-use super::{error::StreamDeckError, payload, ReceiveEvent, SendEvent};
+use super::{
+    error::StreamDeckError,
+    launch::{Device, Info},
+    middleware::{Layered, Middleware},
+    payload, ReceiveEvent, SendEvent,
+};
 
+/// How long handling one event may take before we give up on it, e.g. when a speaker went away
+/// in the middle of a request.
+pub const HANDLER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
 pub struct Connection {
     chan: mpsc::Sender<SendEvent>,
+    uuid: String,
+    info: Arc<RwLock<Info>>,
+    /// Flips to `true` once when the Stream Deck goes away or we're asked to quit.
+    closed: Arc<watch::Sender<bool>>,
+    tasks: Arc<Mutex<JoinSet<()>>>,
 }
 
 pub trait Handler<Actions> {
@@ -15,9 +39,111 @@ pub trait Handler<Actions> {
         connection: &Connection,
         event: &ReceiveEvent<Actions>,
     ) -> Result<(), StreamDeckError>;
+
+    /// Puts a middleware in front of the handler.
+    fn with<M: Middleware<Actions>>(self, middleware: M) -> Layered<M, Self>
+    where
+        Self: Sized,
+    {
+        Layered::new(middleware, self)
+    }
 }
 
 impl Connection {
+    pub fn new(chan: mpsc::Sender<SendEvent>, uuid: &str, info: Info) -> Self {
+        Self {
+            chan,
+            uuid: uuid.to_string(),
+            info: Arc::new(RwLock::new(info)),
+            closed: Arc::new(watch::channel(false).0),
+            tasks: Default::default(),
+        }
+    }
+
+    /// Tells everything holding the connection to wrap up.
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    /// Resolves once the connection is closed.
+    pub async fn closed(&self) {
+        let mut closed = self.closed.subscribe();
+        // the sender lives as long as we do, so this can't fail
+        let _ = closed.wait_for(|closed| *closed).await;
+    }
+
+    /// Runs a background task, like a subscription, that should end when [`Self::closed`]
+    /// resolves. Shutdown waits a little for it to clean up.
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.tasks.lock().unwrap_or_else(PoisonError::into_inner);
+        while let Some(Some(_)) = tasks.join_next().now_or_never() {} // forget finished ones
+        tasks.spawn(task);
+    }
+
+    /// Runs a task in the background like [`Self::spawn`] and waits for what it returns. The
+    /// task carries on if we stop waiting, e.g. when the handler times out, and shutdown waits
+    /// for it like for any other background task.
+    pub async fn run_tracked<T: Send + 'static>(
+        &self,
+        task: impl Future<Output = T> + Send + 'static,
+    ) -> Result<T, StreamDeckError> {
+        let (tx, rx) = oneshot::channel();
+        self.spawn(async move {
+            let _ = tx.send(task.await);
+        });
+        rx.await
+            .map_err(|_| StreamDeckError::HandlerFailed("stopped before it was done".to_string()))
+    }
+
+    /// Waits for background tasks to end after closing, and aborts those that take too long.
+    pub async fn finish_tasks(&self, grace: Duration) {
+        let mut tasks =
+            std::mem::take(&mut *self.tasks.lock().unwrap_or_else(PoisonError::into_inner));
+        let finished =
+            tokio::time::timeout(grace, async { while tasks.join_next().await.is_some() {} }).await;
+        if finished.is_err() {
+            warn!(
+                "aborting {} background tasks that didn't stop in time",
+                tasks.len()
+            );
+            tasks.shutdown().await;
+        }
+    }
+
+    /// The device a key is on, as told at launch or when it was plugged in since.
+    pub fn device(&self, id: &str) -> Option<Device> {
+        let info = self.info.read().unwrap_or_else(|e| e.into_inner());
+        info.device(id).cloned()
+    }
+
+    /// How big key images on a device should be, in actual pixels.
+    pub fn key_size(&self, device: &str) -> Option<u32> {
+        let info = self.info.read().unwrap_or_else(|e| e.into_inner());
+        let ratio = info.device_pixel_ratio.max(1) as u32;
+        info.device(device)
+            .map(|device| device.kind.key_size() * ratio)
+    }
+
+    fn track_devices<Actions>(&self, event: &ReceiveEvent<Actions>) {
+        let mut info = self.info.write().unwrap_or_else(|e| e.into_inner());
+        match event {
+            ReceiveEvent::DeviceDidConnect {
+                device,
+                device_info: Some(device_info),
+            } => {
+                info.devices.retain(|known| known.id != *device);
+                info.devices.push(Device {
+                    id: device.clone(),
+                    ..device_info.clone()
+                });
+            }
+            ReceiveEvent::DeviceDidDisconnect { device } => {
+                info.devices.retain(|known| known.id != *device)
+            }
+            _ => {}
+        }
+    }
+
     pub async fn send(&self, event: SendEvent) -> Result<(), StreamDeckError> {
         info!("sending event: {event:?}");
         self.chan
@@ -26,18 +152,76 @@ impl Connection {
             .map_err(StreamDeckError::SendError)
     }
 
-    // Panics on purpose (if we can't log something has gone horribly wrong.)
-    pub async fn log(&self, msg: &str) {
+    pub async fn show_alert(&self, context: &str) -> Result<(), StreamDeckError> {
+        self.send(SendEvent::ShowAlert {
+            context: context.to_string(),
+        })
+        .await
+    }
+
+    /// Flashes a check mark on the key.
+    pub async fn show_ok(&self, context: &str) -> Result<(), StreamDeckError> {
+        self.send(SendEvent::ShowOk {
+            context: context.to_string(),
+        })
+        .await
+    }
+
+    pub async fn set_state(&self, context: &str, state: u8) -> Result<(), StreamDeckError> {
+        self.send(SendEvent::SetState {
+            context: context.to_string(),
+            payload: payload::State { state },
+        })
+        .await
+    }
+
+    pub async fn set_title(&self, context: &str, title: &str) -> Result<(), StreamDeckError> {
+        self.send(SendEvent::SetTitle {
+            context: context.to_string(),
+            payload: payload::Title {
+                title: title.to_string(),
+            },
+        })
+        .await
+    }
+
+    pub async fn set_image(&self, context: &str, image: &str) -> Result<(), StreamDeckError> {
+        self.send(SendEvent::SetImage {
+            context: context.to_string(),
+            payload: payload::Image {
+                image: image.to_string(),
+            },
+        })
+        .await
+    }
+
+    /// Asks the Stream Deck for the plugin-wide settings, which arrive as a
+    /// `DidReceiveGlobalSettings` event.
+    pub async fn get_global_settings(&self) -> Result<(), StreamDeckError> {
+        self.send(SendEvent::GetGlobalSettings {
+            context: self.uuid.clone(),
+        })
+        .await
+    }
+
+    pub async fn set_global_settings(&self, settings: Value) -> Result<(), StreamDeckError> {
+        self.send(SendEvent::SetGlobalSettings {
+            context: self.uuid.clone(),
+            payload: settings,
+        })
+        .await
+    }
+
+    pub async fn log(&self, msg: &str) -> Result<(), StreamDeckError> {
         self.send(SendEvent::Log {
             payload: payload::Log {
                 message: msg.to_string(),
             },
         })
         .await
-        .unwrap();
     }
 
-    pub async fn handle<'a, Actions>(
+    pub async fn handle<Actions>(
         &self,
         event: &ReceiveEvent<Actions>,
         handler: &impl Handler<Actions>,
@@ -46,31 +230,188 @@ impl Connection {
         Actions: DeserializeOwned + Debug,
     {
         info!("handling {event:?}");
+        self.track_devices(event);
         handler.handle(self, event).await
     }
 
-    pub async fn ingest<'a, Actions: DeserializeOwned + Debug>(
+    /// Handles events concurrently, except that events for the same key are handled in order.
+    /// While a key is busy its events queue up, and a newer one replaces a queued one of the same
+    /// kind, so mashing a key behind a slow speaker doesn't replay every press.
+    pub async fn ingest<Actions: DeserializeOwned + Debug>(
         &self,
         incoming: &mut mpsc::Receiver<ReceiveEvent<Actions>>,
         handler: impl Handler<Actions>,
     ) {
-        while let Some(event) = incoming.recv().await {
-            let res = self.handle(&event, &handler).await;
-            if let Err(e) = res {
-                error!("error handling event: {:?}", e)
+        // keyed by context, plugin-wide events share the "" lane
+        let mut lanes: HashMap<String, VecDeque<ReceiveEvent<Actions>>> = HashMap::new();
+        let mut running = FuturesUnordered::new();
+        let mut open = true;
+
+        // once the Stream Deck is gone, still finish what's running or queued
+        while open || !running.is_empty() {
+            tokio::select! {
+                event = incoming.recv(), if open => {
+                    let Some(event) = event else {
+                        open = false;
+                        continue;
+                    };
+                    let lane = event.context().unwrap_or_default().to_string();
+                    match lanes.get_mut(&lane) {
+                        Some(queue) => {
+                            if event.coalesces() {
+                                let before = queue.len();
+                                queue.retain(|queued| discriminant(queued) != discriminant(&event));
+                                if queue.len() < before {
+                                    debug!("dropped a stale event for {lane:?}");
+                                }
+                            }
+                            queue.push_back(event);
+                        }
+                        None => {
+                            lanes.insert(lane.clone(), VecDeque::new());
+                            running.push(self.run(lane, event, &handler));
+                        }
+                    }
+                }
+                Some(lane) = running.next() => {
+                    match lanes.get_mut(&lane).and_then(VecDeque::pop_front) {
+                        Some(event) => running.push(self.run(lane, event, &handler)),
+                        None => {
+                            lanes.remove(&lane);
+                        }
+                    }
+                }
             }
         }
     }
+
+    /// Handles one event, giving up after [`HANDLER_TIMEOUT`]. Returns the lane for `ingest`.
+    async fn run<Actions: DeserializeOwned + Debug>(
+        &self,
+        lane: String,
+        event: ReceiveEvent<Actions>,
+        handler: &impl Handler<Actions>,
+    ) -> String {
+        match tokio::time::timeout(HANDLER_TIMEOUT, self.handle(&event, handler)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("error handling event: {:?}", e),
+            Err(_) => error!("gave up handling {event:?} after {HANDLER_TIMEOUT:?}"),
+        }
+        lane
+    }
 }
 
-pub async fn initialize(chan: mpsc::Sender<SendEvent>, uuid: &str) -> Connection {
-    let connection = Connection { chan };
+pub async fn initialize(
+    chan: mpsc::Sender<SendEvent>,
+    uuid: &str,
+    info: Info,
+) -> Result<Connection, StreamDeckError> {
+    super::logger::forward_to(chan.clone());
+    let connection = Connection::new(chan, uuid, info);
     connection
         .send(SendEvent::RegisterPlugin {
             uuid: uuid.to_string(),
         })
-        .await
-        .unwrap();
-    connection.log("(ﾉ>ω<)ﾉ :｡･:*:･ﾟ’★,｡･:*:･ﾟ’☆").await;
-    connection
+        .await?;
+    connection.log("(ﾉ>ω<)ﾉ :｡･:*:･ﾟ’★,｡･:*:･ﾟ’☆").await?;
+    connection.get_global_settings().await?;
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde_json::json;
+    use tokio::time::{sleep, Instant};
+
+    use super::*;
+
+    /// Sleeps for the number of seconds in the key's settings, then notes the key and when.
+    struct Slow {
+        start: Instant,
+        done: Mutex<Vec<(String, u64)>>,
+    }
+
+    impl Handler<String> for Arc<Slow> {
+        async fn handle(
+            &self,
+            _connection: &Connection,
+            event: &ReceiveEvent<String>,
+        ) -> Result<(), StreamDeckError> {
+            if let ReceiveEvent::KeyUp {
+                context, payload, ..
+            } = event
+            {
+                let secs = payload.settings["secs"].as_u64().unwrap_or(0);
+                sleep(Duration::from_secs(secs)).await;
+                let at = self.start.elapsed().as_secs();
+                self.done.lock().unwrap().push((context.clone(), at));
+            }
+            Ok(())
+        }
+    }
+
+    fn key(event: &str, context: &str, secs: u64) -> ReceiveEvent<String> {
+        serde_json::from_value(json!({
+            "event": event,
+            "action": "sh.viora.controller-for-sonos.test",
+            "context": context,
+            "payload": {
+                "settings": {"secs": secs},
+                "coordinates": {"column": 0, "row": 0},
+                "isInMultiAction": false,
+            },
+        }))
+        .unwrap()
+    }
+
+    async fn ingest(events: Vec<ReceiveEvent<String>>) -> Vec<(String, u64)> {
+        let (tx, _rx) = mpsc::channel(32);
+        let connection = Connection::new(tx, "PLUGIN", Info::default());
+        let (events_tx, mut events_rx) = mpsc::channel(32);
+        for event in events {
+            events_tx.send(event).await.unwrap();
+        }
+        drop(events_tx);
+
+        let handler = Arc::new(Slow {
+            start: Instant::now(),
+            done: Mutex::default(),
+        });
+        connection.ingest(&mut events_rx, handler.clone()).await;
+        let done = handler.done.lock().unwrap().clone();
+        done
+    }
+
+    fn done(keys: &[(&str, u64)]) -> Vec<(String, u64)> {
+        keys.iter()
+            .map(|(key, at)| (key.to_string(), *at))
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_keys_dont_hold_up_others() {
+        let done_at = ingest(vec![key("keyUp", "A", 5), key("keyUp", "B", 1)]).await;
+        assert_eq!(done_at, done(&[("B", 1), ("A", 5)]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_order_per_key_and_drops_stale_presses() {
+        let done_at = ingest(vec![
+            key("keyUp", "A", 2),
+            key("keyUp", "A", 1),
+            key("keyUp", "A", 3),
+            key("keyUp", "B", 1),
+        ])
+        .await;
+        // the second press of A was replaced by the third while the first was running
+        assert_eq!(done_at, done(&[("B", 1), ("A", 2), ("A", 5)]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_hung_handlers() {
+        let done_at = ingest(vec![key("keyUp", "A", 60), key("keyUp", "A", 1)]).await;
+        assert_eq!(done_at, done(&[("A", HANDLER_TIMEOUT.as_secs() + 1)]));
+    }
 }
@@ -0,0 +1,280 @@
+//! What the Stream Deck tells us when it starts the plugin: where to connect, and `-info`
+//! about the application and the devices plugged in.
+
+use log::{debug, warn};
+use serde::Deserialize;
+
+use super::error::StreamDeckError;
+
+/// The Stream Deck starts us with `-port <port> -pluginUUID <uuid> -registerEvent <event>
+/// -info <json>`, in no particular order.
+#[derive(Debug, Clone)]
+pub struct LaunchArgs {
+    pub port: u16,
+    pub plugin_uuid: String,
+    pub register_event: String,
+    pub info: Info,
+}
+
+impl LaunchArgs {
+    /// Parses the arguments after the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, StreamDeckError> {
+        let mut port = None;
+        let mut plugin_uuid = None;
+        let mut register_event = None;
+        let mut info = None;
+
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| StreamDeckError::InvalidArguments(format!("no value for {flag}")))?;
+            match flag.as_str() {
+                "-port" => {
+                    let parsed = value.parse().map_err(|_| {
+                        StreamDeckError::InvalidArguments(format!("bad port {value}"))
+                    })?;
+                    port = Some(parsed);
+                }
+                "-pluginUUID" => plugin_uuid = Some(value),
+                "-registerEvent" => register_event = Some(value),
+                "-info" => info = Some(value),
+                _ => debug!("ignoring unknown argument {flag}"),
+            }
+        }
+
+        let missing = |flag: &str| StreamDeckError::InvalidArguments(format!("missing {flag}"));
+        Ok(Self {
+            port: port.ok_or_else(|| missing("-port"))?,
+            plugin_uuid: plugin_uuid.ok_or_else(|| missing("-pluginUUID"))?,
+            register_event: register_event.ok_or_else(|| missing("-registerEvent"))?,
+            // the info is nice to have, not worth refusing to start over
+            info: match info.as_deref().map(serde_json::from_str) {
+                Some(Ok(info)) => info,
+                Some(Err(e)) => {
+                    warn!("ignoring malformed -info: {e}");
+                    Info::default()
+                }
+                None => {
+                    warn!("no -info given");
+                    Info::default()
+                }
+            },
+        })
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Info {
+    #[serde(default)]
+    pub application: Application,
+    #[serde(default)]
+    pub plugin: Plugin,
+    /// 2 on high-DPI screens, where key images should be twice the size.
+    #[serde(default)]
+    pub device_pixel_ratio: u8,
+    #[serde(default)]
+    pub colors: Colors,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+}
+
+impl Info {
+    pub fn device(&self, id: &str) -> Option<&Device> {
+        self.devices.iter().find(|device| device.id == id)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Application {
+    #[serde(default)]
+    pub language: String,
+    #[serde(default)]
+    pub platform: Platform,
+    #[serde(default)]
+    pub platform_version: String,
+    #[serde(default)]
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    Mac,
+    Windows,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Plugin {
+    #[serde(default)]
+    pub uuid: String,
+    #[serde(default)]
+    pub version: String,
+}
+
+/// The Stream Deck app's theme colors, as `#RRGGBBAA`.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Colors {
+    pub button_pressed_background_color: Option<String>,
+    pub button_pressed_border_color: Option<String>,
+    pub button_pressed_text_color: Option<String>,
+    pub disabled_color: Option<String>,
+    pub highlight_color: Option<String>,
+    pub mouse_down_color: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Device {
+    /// Missing from the `deviceInfo` of a `deviceDidConnect` event, which has it alongside.
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub size: Size,
+    #[serde(rename = "type")]
+    pub kind: DeviceType,
+}
+
+/// A device's keys, in columns and rows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct Size {
+    pub columns: u8,
+    pub rows: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(from = "u8")]
+pub enum DeviceType {
+    StreamDeck,
+    Mini,
+    Xl,
+    Mobile,
+    CorsairGKeys,
+    Pedal,
+    CorsairVoyager,
+    Plus,
+    ScufController,
+    Neo,
+    /// Something newer than us.
+    Other(u8),
+}
+
+impl From<u8> for DeviceType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => DeviceType::StreamDeck,
+            1 => DeviceType::Mini,
+            2 => DeviceType::Xl,
+            3 => DeviceType::Mobile,
+            4 => DeviceType::CorsairGKeys,
+            5 => DeviceType::Pedal,
+            6 => DeviceType::CorsairVoyager,
+            7 => DeviceType::Plus,
+            8 => DeviceType::ScufController,
+            9 => DeviceType::Neo,
+            other => DeviceType::Other(other),
+        }
+    }
+}
+
+impl DeviceType {
+    /// The width and height of a key image in pixels, at a device pixel ratio of 1.
+    pub fn key_size(&self) -> u32 {
+        match self {
+            DeviceType::Mini => 80,
+            DeviceType::Xl => 96,
+            DeviceType::Plus => 120,
+            _ => 72,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_flags_in_any_order() {
+        let info = r##"{
+            "application": {"language": "en", "platform": "mac", "platformVersion": "14.1", "version": "6.4.0"},
+            "plugin": {"uuid": "sh.viora.controller-for-sonos", "version": "0.1.0"},
+            "devicePixelRatio": 2,
+            "colors": {"highlightColor": "#007AFFFF"},
+            "devices": [
+                {"id": "XL1", "name": "Desk", "size": {"columns": 8, "rows": 4}, "type": 2},
+                {"id": "NEW", "name": "Future", "size": {"columns": 1, "rows": 1}, "type": 42}
+            ]
+        }"##;
+        let launch = LaunchArgs::parse(args(&[
+            "-info",
+            info,
+            "-registerEvent",
+            "registerPlugin",
+            "-port",
+            "28196",
+            "-pluginUUID",
+            "ABC",
+        ]))
+        .unwrap();
+
+        assert_eq!(launch.port, 28196);
+        assert_eq!(launch.plugin_uuid, "ABC");
+        assert_eq!(launch.register_event, "registerPlugin");
+        assert_eq!(launch.info.application.platform, Platform::Mac);
+        assert_eq!(launch.info.device_pixel_ratio, 2);
+        let desk = launch.info.device("XL1").unwrap();
+        assert_eq!(desk.kind, DeviceType::Xl);
+        assert_eq!(desk.kind.key_size(), 96);
+        assert_eq!(
+            desk.size,
+            Size {
+                columns: 8,
+                rows: 4
+            }
+        );
+        assert_eq!(
+            launch.info.colors.highlight_color.as_deref(),
+            Some("#007AFFFF")
+        );
+        assert_eq!(
+            launch.info.device("NEW").unwrap().kind,
+            DeviceType::Other(42)
+        );
+    }
+
+    #[test]
+    fn starts_without_info() {
+        let launch = LaunchArgs::parse(args(&[
+            "-port",
+            "1",
+            "-pluginUUID",
+            "A",
+            "-registerEvent",
+            "r",
+        ]))
+        .unwrap();
+        assert!(launch.info.devices.is_empty());
+    }
+
+    #[test]
+    fn rejects_missing_flags() {
+        assert!(LaunchArgs::parse(args(&["-port", "1", "-pluginUUID", "A"])).is_err());
+        assert!(LaunchArgs::parse(args(&["-port", "x", "-pluginUUID", "A"])).is_err());
+        assert!(LaunchArgs::parse(args(&["-port"])).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Tees the `log` facade: everything goes to log4rs as before, and the important bits also go to
+//! the Stream Deck's own log, so they end up next to the app's when someone sends us their logs.
+
+use std::sync::{Mutex, OnceLock, PoisonError, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use tokio::sync::mpsc;
+
+use super::{payload, SendEvent};
+
+/// At most this many records a second go to the Stream Deck, the rest are counted and dropped.
+const MAX_PER_SECOND: u32 = 10;
+
+/// Records about sending events to the Stream Deck stay out of the Stream Deck log, otherwise
+/// sending a log message would log that it was sent, which would be sent, which... Everything
+/// else from the plumbing, like handler errors, is forwarded as usual.
+pub const OWN_TARGET: &str = concat!(env!("CARGO_CRATE_NAME"), "::stream_deck::sending");
+
+static LOGGER: OnceLock<StreamDeckLogger> = OnceLock::new();
+
+/// Installs the tee as the global logger. Records only reach the Stream Deck once there's a
+/// connection, see [`forward_to`].
+pub fn init(config: log4rs::Config, level: LevelFilter) -> Result<(), SetLoggerError> {
+    let file = log4rs::Logger::new(config);
+    let max_level = file.max_log_level().max(level);
+    log::set_logger(LOGGER.get_or_init(|| StreamDeckLogger::new(file, level)))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// Swaps in a new log4rs configuration, e.g. after log4rs.yml changed. Forwarding to the Stream
+/// Deck carries on as before.
+pub fn reload(config: log4rs::Config) {
+    if let Some(logger) = LOGGER.get() {
+        log::set_max_level(logger.replace_file(config));
+    }
+}
+
+/// Starts sending log records over the connection's channel.
+pub fn forward_to(chan: mpsc::Sender<SendEvent>) {
+    if let Some(logger) = LOGGER.get() {
+        *logger.chan.lock().unwrap_or_else(PoisonError::into_inner) = Some(chan);
+    }
+}
+
+pub struct StreamDeckLogger {
+    /// Replaced whenever the configuration is reloaded.
+    file: RwLock<log4rs::Logger>,
+    level: LevelFilter,
+    chan: Mutex<Option<mpsc::Sender<SendEvent>>>,
+    limit: Mutex<RateLimit>,
+}
+
+impl StreamDeckLogger {
+    fn new(file: log4rs::Logger, level: LevelFilter) -> Self {
+        Self {
+            file: RwLock::new(file),
+            level,
+            chan: Mutex::new(None),
+            limit: Mutex::new(RateLimit::new(Instant::now())),
+        }
+    }
+
+    /// Returns the level the `log` facade should let through now.
+    fn replace_file(&self, config: log4rs::Config) -> LevelFilter {
+        let file = log4rs::Logger::new(config);
+        let max_level = file.max_log_level().max(self.level);
+        *self.file.write().unwrap_or_else(PoisonError::into_inner) = file;
+        max_level
+    }
+
+    fn file(&self) -> RwLockReadGuard<'_, log4rs::Logger> {
+        self.file.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn forwards(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && metadata.target() != OWN_TARGET
+    }
+
+    fn forward(&self, record: &Record) {
+        let Some(chan) = self
+            .chan
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+        else {
+            return;
+        };
+        let mut limit = self.limit.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(dropped) = limit.take(Instant::now()) else {
+            return;
+        };
+
+        let mut message = format!("{} {}", record.level(), record.args());
+        if dropped > 0 {
+            message.push_str(&format!(" ({dropped} earlier messages dropped)"));
+        }
+        // never wait for room in the channel: the sender task logs too, and would wait on itself
+        if chan
+            .try_send(SendEvent::Log {
+                payload: payload::Log { message },
+            })
+            .is_err()
+        {
+            limit.dropped += dropped + 1;
+        }
+    }
+}
+
+impl Log for StreamDeckLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.file().enabled(metadata) || self.forwards(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        {
+            let file = self.file();
+            if file.enabled(record.metadata()) {
+                file.log(record);
+            }
+        }
+        if self.forwards(record.metadata()) {
+            self.forward(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.file().flush();
+    }
+}
+
+/// Lets [`MAX_PER_SECOND`] records through per one second window.
+struct RateLimit {
+    window: Instant,
+    sent: u32,
+    dropped: u32,
+}
+
+impl RateLimit {
+    fn new(now: Instant) -> Self {
+        Self {
+            window: now,
+            sent: 0,
+            dropped: 0,
+        }
+    }
+
+    /// How many records were dropped since the last one let through, or `None` to drop this one.
+    fn take(&mut self, now: Instant) -> Option<u32> {
+        if now.duration_since(self.window) >= Duration::from_secs(1) {
+            self.window = now;
+            self.sent = 0;
+        }
+        if self.sent >= MAX_PER_SECOND {
+            self.dropped += 1;
+            return None;
+        }
+        self.sent += 1;
+        Some(std::mem::take(&mut self.dropped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use log4rs::config::{Config, Root};
+
+    use super::*;
+
+    fn config(level: LevelFilter) -> Config {
+        Config::builder()
+            .build(Root::builder().build(level))
+            .unwrap()
+    }
+
+    fn logger(level: LevelFilter) -> (StreamDeckLogger, mpsc::Receiver<SendEvent>) {
+        let logger = StreamDeckLogger::new(log4rs::Logger::new(config(LevelFilter::Off)), level);
+        let (tx, rx) = mpsc::channel(2);
+        *logger.chan.lock().unwrap() = Some(tx);
+        (logger, rx)
+    }
+
+    fn log(logger: &StreamDeckLogger, level: Level, target: &str, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target(target)
+                .args(format_args!("{message}"))
+                .build(),
+        );
+    }
+
+    fn messages(rx: &mut mpsc::Receiver<SendEvent>) -> Vec<String> {
+        let mut messages = vec![];
+        while let Ok(SendEvent::Log { payload }) = rx.try_recv() {
+            messages.push(payload.message);
+        }
+        messages
+    }
+
+    #[test]
+    fn forwards_by_level_and_target() {
+        let (logger, mut rx) = logger(LevelFilter::Warn);
+        log(
+            &logger,
+            Level::Info,
+            "sonos_controller::controller",
+            "chatty",
+        );
+        log(
+            &logger,
+            Level::Error,
+            "sonos_controller::controller",
+            "broken",
+        );
+        log(&logger, Level::Error, OWN_TARGET, "can't send");
+        log(
+            &logger,
+            Level::Error,
+            "sonos_controller::stream_deck::handler",
+            "error handling event",
+        );
+        assert_eq!(
+            messages(&mut rx),
+            ["ERROR broken", "ERROR error handling event"]
+        );
+    }
+
+    #[test]
+    fn reloads_the_file_config() {
+        let (logger, mut rx) = logger(LevelFilter::Warn);
+        let info = Metadata::builder()
+            .level(Level::Info)
+            .target("sonos_controller")
+            .build();
+        assert!(!logger.enabled(&info));
+
+        assert_eq!(
+            logger.replace_file(config(LevelFilter::Info)),
+            LevelFilter::Info
+        );
+        assert!(logger.enabled(&info));
+        assert_eq!(
+            logger.replace_file(config(LevelFilter::Off)),
+            LevelFilter::Warn
+        );
+        assert!(!logger.enabled(&info));
+
+        // still forwarding
+        log(&logger, Level::Warn, "sonos_controller", "still here");
+        assert_eq!(messages(&mut rx), ["WARN still here"]);
+    }
+
+    #[test]
+    fn drops_instead_of_blocking_when_the_channel_is_full() {
+        let (logger, mut rx) = logger(LevelFilter::Warn);
+        for n in 0..4 {
+            log(&logger, Level::Warn, "sonos_controller", &n.to_string());
+        }
+        assert_eq!(messages(&mut rx), ["WARN 0", "WARN 1"]);
+        log(&logger, Level::Warn, "sonos_controller", "4");
+        assert_eq!(messages(&mut rx), ["WARN 4 (2 earlier messages dropped)"]);
+    }
+
+    #[test]
+    fn rate_limits_per_second() {
+        let start = Instant::now();
+        let mut limit = RateLimit::new(start);
+        for _ in 0..MAX_PER_SECOND {
+            assert_eq!(limit.take(start), Some(0));
+        }
+        assert_eq!(limit.take(start + Duration::from_millis(500)), None);
+        assert_eq!(limit.take(start + Duration::from_millis(900)), None);
+        assert_eq!(limit.take(start + Duration::from_secs(1)), Some(2));
+    }
+}
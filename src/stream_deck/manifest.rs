@@ -0,0 +1,103 @@
+//! The plugin's `manifest.json`, which tells the Stream Deck app about the plugin and its
+//! actions. Written from the action registry rather than by hand, see `cargo make manifest`.
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Manifest {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub author: &'static str,
+    pub actions: Vec<ActionManifest>,
+    pub category: &'static str,
+    pub category_icon: &'static str,
+    pub code_path: &'static str,
+    pub description: &'static str,
+    pub icon: &'static str,
+    #[serde(rename = "SDKVersion")]
+    pub sdk_version: u8,
+    pub software: Software,
+    #[serde(rename = "OS")]
+    pub os: &'static [Os],
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Software {
+    pub minimum_version: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Os {
+    pub platform: &'static str,
+    pub minimum_version: &'static str,
+}
+
+/// One action, as it shows up in the app's action list. Image paths are relative to the plugin
+/// directory and without extension, the app picks `.png` or `@2x.png` by itself.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ActionManifest {
+    pub name: &'static str,
+    #[serde(rename = "UUID")]
+    pub uuid: &'static str,
+    pub icon: &'static str,
+    pub tooltip: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub property_inspector_path: Option<&'static str>,
+    pub controllers: &'static [Controller],
+    /// Keeps the app from flipping states on every press, for keys whose state we set.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub disable_automatic_states: bool,
+    pub states: &'static [State],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<Encoder>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Controller {
+    Keypad,
+    /// The dials and touch strip of a Stream Deck +.
+    #[allow(dead_code)] // no dial actions yet
+    Encoder,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct State {
+    pub image: &'static str,
+    pub title_alignment: TitleAlignment,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TitleAlignment {
+    #[allow(dead_code)]
+    Top,
+    Middle,
+    Bottom,
+}
+
+/// How an action looks and is described on a Stream Deck + touch strip.
+#[allow(dead_code)] // no dial actions yet
+#[derive(Debug, Clone, Serialize)]
+pub struct Encoder {
+    /// One of the built-in layouts like `$B1`, or a path to a layout file.
+    #[serde(rename = "layout")]
+    pub layout: &'static str,
+    #[serde(rename = "TriggerDescription")]
+    pub trigger_description: TriggerDescription,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TriggerDescription {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rotate: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub push: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub touch: Option<&'static str>,
+}
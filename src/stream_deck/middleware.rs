@@ -0,0 +1,201 @@
+//! Wrappers around a [`Handler`] for what every action needs, like telling the user when a key
+//! press failed. Stack them with [`Handler::with`], the last one added sees events first.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use super::error::StreamDeckError;
+use super::handler::{Connection, Handler};
+use super::ReceiveEvent;
+
+/// Key presses that take longer than this get a warning, a speaker is probably struggling.
+const SLOW_ACTION: Duration = Duration::from_secs(2);
+
+pub trait Middleware<Actions> {
+    /// Handles the event, usually by passing it on to `next` and looking at the result.
+    async fn handle(
+        &self,
+        connection: &Connection,
+        event: &ReceiveEvent<Actions>,
+        next: &impl Handler<Actions>,
+    ) -> Result<(), StreamDeckError>;
+}
+
+/// A handler with a middleware in front of it, see [`Handler::with`].
+pub struct Layered<M, H> {
+    middleware: M,
+    inner: H,
+}
+
+impl<M, H> Layered<M, H> {
+    pub fn new(middleware: M, inner: H) -> Self {
+        Self { middleware, inner }
+    }
+}
+
+impl<Actions, M: Middleware<Actions>, H: Handler<Actions>> Handler<Actions> for Layered<M, H> {
+    async fn handle(
+        &self,
+        connection: &Connection,
+        event: &ReceiveEvent<Actions>,
+    ) -> Result<(), StreamDeckError> {
+        self.middleware.handle(connection, event, &self.inner).await
+    }
+}
+
+/// Shows an alert on the key when pressing or turning it failed, and a check mark once it
+/// worked as part of a multi-action, where the user can't see otherwise which step failed.
+/// The check mark waits for the key up, which is when keys do their thing.
+pub struct Feedback;
+
+impl<Actions> Middleware<Actions> for Feedback {
+    async fn handle(
+        &self,
+        connection: &Connection,
+        event: &ReceiveEvent<Actions>,
+        next: &impl Handler<Actions>,
+    ) -> Result<(), StreamDeckError> {
+        let result = next.handle(connection, event).await;
+        let Some(context) = event.context().filter(|_| event.is_input()) else {
+            return result;
+        };
+
+        match &result {
+            Err(_) => connection.show_alert(context).await?,
+            Ok(()) if event.is_in_multi_action() && matches!(event, ReceiveEvent::KeyUp { .. }) => {
+                connection.show_ok(context).await?
+            }
+            Ok(()) => {}
+        }
+        result
+    }
+}
+
+/// Keeps track of how long each action takes to handle, and warns about slow ones.
+#[derive(Default)]
+pub struct Latency {
+    stats: Mutex<HashMap<String, Stats>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Stats {
+    count: u32,
+    total: Duration,
+    max: Duration,
+}
+
+impl<Actions: Display> Middleware<Actions> for Latency {
+    async fn handle(
+        &self,
+        connection: &Connection,
+        event: &ReceiveEvent<Actions>,
+        next: &impl Handler<Actions>,
+    ) -> Result<(), StreamDeckError> {
+        let Some(action) = event.action() else {
+            return next.handle(connection, event).await;
+        };
+
+        let start = Instant::now();
+        let result = next.handle(connection, event).await;
+        let elapsed = start.elapsed();
+
+        debug!("{} for {action} took {elapsed:?}", event.name());
+        if event.is_input() && elapsed > SLOW_ACTION {
+            warn!("{} for {action} took {elapsed:?}", event.name());
+        }
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(action.to_string()).or_default();
+        stats.count += 1;
+        stats.total += elapsed;
+        stats.max = stats.max.max(elapsed);
+        result
+    }
+}
+
+impl Drop for Latency {
+    fn drop(&mut self) {
+        let stats = self.stats.get_mut().unwrap_or_else(|e| e.into_inner());
+        for (action, stats) in stats.iter() {
+            info!(
+                "{action}: {} events, {:?} on average, {:?} at most",
+                stats.count,
+                stats.total / stats.count,
+                stats.max
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::stream_deck::SendEvent;
+
+    /// Fails key ups for the "broken" key.
+    struct Flaky;
+
+    impl Handler<String> for Flaky {
+        async fn handle(
+            &self,
+            _connection: &Connection,
+            event: &ReceiveEvent<String>,
+        ) -> Result<(), StreamDeckError> {
+            match event {
+                ReceiveEvent::KeyUp { context, .. } if context == "broken" => Err(
+                    StreamDeckError::HandlerFailed("speaker went away".to_string()),
+                ),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn key(event: &str, context: &str, multi_action: bool) -> ReceiveEvent<String> {
+        serde_json::from_value(json!({
+            "event": event,
+            "action": "sh.viora.controller-for-sonos.next-track",
+            "context": context,
+            "payload": {
+                "settings": {"room": "Kitchen"},
+                "coordinates": {"column": 0, "row": 0},
+                "isInMultiAction": multi_action,
+            },
+        }))
+        .unwrap()
+    }
+
+    async fn feedback(event: ReceiveEvent<String>) -> Vec<SendEvent> {
+        let (tx, mut rx) = mpsc::channel(8);
+        let connection = Connection::new(tx, "plugin", Default::default());
+        let handler = Flaky.with(Feedback);
+        let _ = handler.handle(&connection, &event).await;
+        drop(connection);
+        let mut sent = vec![];
+        while let Some(event) = rx.recv().await {
+            sent.push(event);
+        }
+        sent
+    }
+
+    #[tokio::test]
+    async fn feedback_shows_alert_or_ok() {
+        let failed = feedback(key("keyUp", "broken", false)).await;
+        assert!(matches!(&failed[..], [SendEvent::ShowAlert { context }] if context == "broken"));
+
+        let multi = feedback(key("keyUp", "fine", true)).await;
+        assert!(matches!(&multi[..], [SendEvent::ShowOk { context }] if context == "fine"));
+
+        assert!(feedback(key("keyUp", "fine", false)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn feedback_waits_for_the_key_up() {
+        assert!(feedback(key("keyDown", "fine", true)).await.is_empty());
+    }
+}
@@ -1,25 +1,61 @@
 pub mod error;
 pub mod handler;
+pub mod launch;
+pub mod logger;
+pub mod manifest;
+pub mod middleware;
 pub mod plumbing;
 
+#[cfg(test)]
+mod fake;
+#[cfg(test)]
+mod tests;
+
 use log::debug;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio_tungstenite::tungstenite::Message;
 
 use self::error::StreamDeckError;
+use self::logger::OWN_TARGET;
 
 #[non_exhaustive]
 #[derive(Debug, Clone, Serialize)]
 #[serde(tag = "event", rename_all = "camelCase")]
 pub enum SendEvent {
-    #[serde(alias = "register")]
-    RegisterPlugin { uuid: String },
-    #[serde(alias = "logMessage")]
+    #[serde(rename = "registerPlugin")]
+    RegisterPlugin {
+        uuid: String,
+    },
+    #[serde(rename = "logMessage")]
     Log {
-        #[serde(flatten)]
         payload: payload::Log,
     },
+    ShowAlert {
+        context: String,
+    },
+    ShowOk {
+        context: String,
+    },
+    SetState {
+        context: String,
+        payload: payload::State,
+    },
+    SetTitle {
+        context: String,
+        payload: payload::Title,
+    },
+    SetImage {
+        context: String,
+        payload: payload::Image,
+    },
+    GetGlobalSettings {
+        context: String,
+    },
+    SetGlobalSettings {
+        context: String,
+        payload: Value,
+    },
 }
 
 #[non_exhaustive]
@@ -49,6 +85,11 @@ pub enum ReceiveEvent<Action> {
         context: String,
         payload: payload::KeyPress,
     },
+    DialRotate {
+        action: Action,
+        context: String,
+        payload: payload::DialRotation,
+    },
     WillAppear {
         action: Action,
         context: String,
@@ -61,8 +102,16 @@ pub enum ReceiveEvent<Action> {
         device: String,
         payload: payload::Presence,
     },
+    TitleParametersDidChange {
+        action: Action,
+        context: String,
+        device: String,
+        payload: payload::TitleChange,
+    },
     DeviceDidConnect {
         device: String,
+        #[serde(rename = "deviceInfo")]
+        device_info: Option<launch::Device>,
     },
     DeviceDidDisconnect {
         device: String,
@@ -77,17 +126,102 @@ pub enum ReceiveEvent<Action> {
     },
 }
 
+impl<Action> ReceiveEvent<Action> {
+    /// The action of the key the event is about, if it's about one.
+    pub fn action(&self) -> Option<&Action> {
+        match self {
+            ReceiveEvent::DidReceiveSettings { action, .. }
+            | ReceiveEvent::KeyDown { action, .. }
+            | ReceiveEvent::KeyUp { action, .. }
+            | ReceiveEvent::DialRotate { action, .. }
+            | ReceiveEvent::WillAppear { action, .. }
+            | ReceiveEvent::WillDisappear { action, .. }
+            | ReceiveEvent::TitleParametersDidChange { action, .. }
+            | ReceiveEvent::SendToPlugin { action, .. } => Some(action),
+            _ => None,
+        }
+    }
+
+    /// The key the event is about, if it's about one.
+    pub fn context(&self) -> Option<&str> {
+        match self {
+            ReceiveEvent::DidReceiveSettings { context, .. }
+            | ReceiveEvent::KeyDown { context, .. }
+            | ReceiveEvent::KeyUp { context, .. }
+            | ReceiveEvent::DialRotate { context, .. }
+            | ReceiveEvent::WillAppear { context, .. }
+            | ReceiveEvent::WillDisappear { context, .. }
+            | ReceiveEvent::TitleParametersDidChange { context, .. }
+            | ReceiveEvent::SendToPlugin { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// The event's name in the Stream Deck's protocol.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReceiveEvent::DidReceiveSettings { .. } => "didReceiveSettings",
+            ReceiveEvent::DidReceiveGlobalSettings { .. } => "didReceiveGlobalSettings",
+            ReceiveEvent::DidReceiveDeepLink { .. } => "didReceiveDeepLink",
+            ReceiveEvent::KeyDown { .. } => "keyDown",
+            ReceiveEvent::KeyUp { .. } => "keyUp",
+            ReceiveEvent::DialRotate { .. } => "dialRotate",
+            ReceiveEvent::WillAppear { .. } => "willAppear",
+            ReceiveEvent::WillDisappear { .. } => "willDisappear",
+            ReceiveEvent::TitleParametersDidChange { .. } => "titleParametersDidChange",
+            ReceiveEvent::DeviceDidConnect { .. } => "deviceDidConnect",
+            ReceiveEvent::DeviceDidDisconnect { .. } => "deviceDidDisconnect",
+            ReceiveEvent::PropertyInspectorDidAppear => "propertyInspectorDidAppear",
+            ReceiveEvent::PropertyInspectorDidDisappear => "propertyInspectorDidDisappear",
+            ReceiveEvent::SystemDidWakeUp => "systemDidWakeUp",
+            ReceiveEvent::SendToPlugin { .. } => "sendToPlugin",
+        }
+    }
+
+    /// Whether the user pressed or turned something, as opposed to the app telling us things.
+    pub fn is_input(&self) -> bool {
+        matches!(
+            self,
+            ReceiveEvent::KeyDown { .. }
+                | ReceiveEvent::KeyUp { .. }
+                | ReceiveEvent::DialRotate { .. }
+        )
+    }
+
+    /// Whether the key was pressed as a step of a multi-action rather than by itself.
+    pub fn is_in_multi_action(&self) -> bool {
+        match self {
+            ReceiveEvent::KeyDown { payload, .. } | ReceiveEvent::KeyUp { payload, .. } => {
+                payload.is_in_multi_action
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether a newer event of the same kind for the same key makes this one pointless, like
+    /// presses queued up behind a slow speaker or settings that were changed again since.
+    pub fn coalesces(&self) -> bool {
+        matches!(
+            self,
+            ReceiveEvent::KeyDown { .. }
+                | ReceiveEvent::KeyUp { .. }
+                | ReceiveEvent::DidReceiveSettings { .. }
+                | ReceiveEvent::TitleParametersDidChange { .. }
+        )
+    }
+}
+
 impl TryFrom<SendEvent> for Message {
     type Error = StreamDeckError;
 
     fn try_from(value: SendEvent) -> Result<Self, Self::Error> {
-        let msg = serde_json::to_string(&value).unwrap();
+        let msg = serde_json::to_string(&value)?;
 
         if value.is_binary() {
-            debug!("sending bytes: {msg}");
+            debug!(target: OWN_TARGET, "sending bytes: {msg}");
             Ok(Message::Binary(msg.into_bytes()))
         } else {
-            debug!("sending json: {msg}");
+            debug!(target: OWN_TARGET, "sending json: {msg}");
             Ok(Message::Text(msg))
         }
     }
@@ -99,7 +233,7 @@ impl SendEvent {
     }
 }
 
-mod payload {
+pub mod payload {
     use serde::{Deserialize, Serialize};
     use serde_json::Value;
 
@@ -110,6 +244,22 @@ mod payload {
         pub row: i32,
     }
 
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct State {
+        pub state: u8,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Title {
+        pub title: String,
+    }
+
+    /// A data URL or SVG, or an empty string for the image from the manifest.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Image {
+        pub image: String,
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Log {
         pub message: String,
@@ -125,6 +275,16 @@ mod payload {
         pub is_in_multi_action: bool,
     }
 
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct DialRotation {
+        pub settings: Value,
+        pub coordinates: Coordinates,
+        /// Positive clockwise, negative counter-clockwise.
+        pub ticks: i32,
+        pub pressed: bool,
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize)]
     #[serde(rename_all = "camelCase")]
     pub struct Presence {
@@ -134,27 +294,37 @@ mod payload {
         pub state: Option<i32>,
         pub is_in_multi_action: bool,
     }
-}
 
-macro_rules! action_names {
-    ($actions:ident => { $($name:expr => $variant:expr) , + }) => {
-        use serde::{de, Deserialize};
-
-        impl<'de> Deserialize<'de> for $actions {
-            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-            where
-                D: serde::Deserializer<'de>,
-            {
-                match String::deserialize(deserializer)?.as_str() {
-                    $(
-                        $name => Ok($variant),
-                    )+
-                    val => Err(de::Error::invalid_value(
-                        de::Unexpected::Str(val),
-                        &"a valid action",
-                    )),
-                }
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TitleChange {
+        pub settings: Value,
+        pub coordinates: Coordinates,
+        pub state: Option<i32>,
+        /// The title the user typed in, if any.
+        pub title: String,
+        pub title_parameters: TitleParameters,
+    }
+
+    /// How the user wants the key's title to look. The app draws titles itself, so we only
+    /// need what tells us how much fits.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct TitleParameters {
+        pub font_size: f32,
+        pub show_title: bool,
+        #[serde(default)]
+        pub title_alignment: String,
+    }
+
+    impl Default for TitleParameters {
+        /// The app's defaults, until it tells us otherwise.
+        fn default() -> Self {
+            Self {
+                font_size: 12.0,
+                show_title: true,
+                title_alignment: "bottom".to_string(),
             }
         }
-    };
+    }
 }
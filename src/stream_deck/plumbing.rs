@@ -1,71 +1,181 @@
 use std::fmt::Debug;
+use std::time::Duration;
 
 use futures::{SinkExt, StreamExt};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde::de::DeserializeOwned;
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tokio_tungstenite::connect_async;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
 use crate::stream_deck::{error::StreamDeckError, handler};
 
-use super::{handler::Handler, ReceiveEvent, SendEvent};
+use super::logger::OWN_TARGET;
+use super::{handler::Handler, launch::LaunchArgs, ReceiveEvent, SendEvent};
+
+/// The Stream Deck starts us right after opening its socket, but give it a moment anyway.
+const CONNECT_ATTEMPTS: u32 = 6;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+/// We ping the Stream Deck this often, and give up on it if nothing came back for a while.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long background tasks get to unsubscribe and such on shutdown.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
 pub async fn run<H: Handler<Actions>, Actions: DeserializeOwned + Debug>(
-    port: u16,
-    uuid: &String,
+    args: LaunchArgs,
     hndlr: H,
-) {
-    // set up websockets client
-    let (conn, _) = connect_async(format!("ws://localhost:{}", port))
-        .await
-        .expect("connection failed");
+) -> Result<(), StreamDeckError> {
+    let conn = connect(args.port).await?;
     debug!("connection successful");
 
     // this lets us have separate tasks to send and receive events
-    let (mut write, read) = conn.split();
+    let (mut write, mut read) = conn.split();
 
     // set up channels to send and receive events
     let (send_tx, mut send_rx) = mpsc::channel::<SendEvent>(32);
     let (recv_tx, mut recv_rx) = mpsc::channel::<ReceiveEvent<Actions>>(32);
 
+    let connection = handler::initialize(send_tx, &args.plugin_uuid, args.info).await?;
+
     let sender = async {
-        while let Some(event) = send_rx.recv().await {
-            match write.send(event.clone().try_into().unwrap()).await {
-                Ok(_) => debug!("sent event: {:?}", event),
-                Err(e) => error!("error sending event: {:?}", e),
+        let mut pings = tokio::time::interval(PING_INTERVAL);
+        loop {
+            let message = tokio::select! {
+                event = send_rx.recv() => match event {
+                    Some(event) => match event.clone().try_into() {
+                        Ok(message) => message,
+                        Err(e) => {
+                            error!(target: OWN_TARGET, "error serializing event {:?}: {:?}", event, e);
+                            continue;
+                        }
+                    },
+                    None => break,
+                },
+                _ = pings.tick() => Message::Ping(vec![]),
+                _ = connection.closed() => break,
+            };
+            if let Err(e) = write.send(message).await {
+                error!(target: OWN_TARGET, "error sending event: {:?}", e);
+                connection.close();
+                break;
             }
         }
+        // anything the handler still sends now fails right away instead of filling up the channel
+        send_rx.close();
+        if let Err(e) = write.close().await {
+            debug!("could not close the connection cleanly: {e}");
+        }
     };
 
-    let reader = {
-        read.for_each(|message| async {
-            let msg = message
-                .map(|m| m.into_data())
-                .map_err(StreamDeckError::ReadError)
-                .and_then(|e| Ok(String::from_utf8(e).unwrap()));
-
-            if let Ok(json) = msg {
-                if json.parse::<i64>().is_ok() {
-                    // sometimes we just get numbers from the stream deck, we ignore those
-                    return;
+    let reader = async {
+        // moved in, so the handler stops once the reader does
+        let recv_tx = recv_tx;
+        loop {
+            let message = tokio::select! {
+                message = tokio::time::timeout(PING_INTERVAL + PING_TIMEOUT, read.next()) => message,
+                _ = connection.closed() => break,
+            };
+            let json = match message {
+                Ok(Some(Ok(Message::Text(json)))) => json,
+                Ok(Some(Ok(Message::Close(frame)))) => {
+                    info!("the Stream Deck closed the connection: {frame:?}");
+                    break;
+                }
+                Ok(Some(Ok(_))) => continue, // pings and pongs
+                Ok(Some(Err(e))) => {
+                    error!("{}", StreamDeckError::ReadError(Box::new(e)));
+                    break;
+                }
+                Ok(None) => {
+                    info!("the connection to the Stream Deck is gone");
+                    break;
+                }
+                Err(_) => {
+                    error!("the Stream Deck stopped responding");
+                    break;
+                }
+            };
+
+            if json.parse::<i64>().is_ok() {
+                // sometimes we just get numbers from the stream deck, we ignore those
+                continue;
+            }
+            debug!("received json: {:?}", json);
+            let event = serde_json::from_str(&json).map_err(StreamDeckError::MalformedJson);
+            info!("dispatching event: {:?}", event);
+
+            match event {
+                Ok(event) => {
+                    if recv_tx.send(event).await.is_err() {
+                        error!("handler has stopped, dropping event");
+                    }
                 }
-                debug!("received json: {:?}", json);
-                let event = serde_json::from_str(&json).map_err(StreamDeckError::MalformedJson);
-                info!("dispatching event: {:?}", event);
-
-                match event {
-                    Ok(event) => recv_tx.send(event).await.unwrap(),
-                    Err(e) => error!("error processing event: {:?}", e),
-                };
+                Err(e) => error!("error processing event: {:?}", e),
+            };
+        }
+        connection.close();
+    };
+
+    let signals = async {
+        tokio::select! {
+            _ = terminated() => {
+                info!("asked to quit");
+                connection.close();
             }
-        })
+            _ = connection.closed() => {}
+        }
     };
 
-    let connection = handler::initialize(send_tx, uuid).await;
     let handler = connection.ingest(&mut recv_rx, hndlr);
 
-    // start the send and handler tasks and wait until they are done
-    // the handler shuts down when the Stream Deck closes the WebSockets connection
-    futures::pin_mut!(sender, reader, handler);
-    futures::join!(sender, reader, handler);
+    // everything runs until the connection is closed, by the Stream Deck or by a signal; the
+    // handler then finishes what it was doing and background tasks get to clean up
+    futures::pin_mut!(sender, reader, signals, handler);
+    futures::join!(sender, reader, signals, handler);
+    connection.finish_tasks(SHUTDOWN_GRACE).await;
+    Ok(())
+}
+
+/// Connects to the Stream Deck, retrying with exponential backoff.
+async fn connect(port: u16) -> Result<Socket, StreamDeckError> {
+    let url = format!("ws://localhost:{}", port);
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match connect_async(&url).await {
+            Ok((conn, _)) => return Ok(conn),
+            Err(e) if attempt < CONNECT_ATTEMPTS => {
+                warn!("could not connect to the Stream Deck, retrying in {backoff:?}: {e}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(StreamDeckError::ConnectionFailed(Box::new(e))),
+        }
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM where there is such a thing.
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("can't listen for SIGTERM: {e}"),
+        }
+    }
+    if tokio::signal::ctrl_c().await.is_err() {
+        // nothing to listen to, so we only stop when the Stream Deck goes away
+        std::future::pending::<()>().await;
+    }
 }
@@ -0,0 +1,216 @@
+use serde_json::json;
+
+use super::error::StreamDeckError;
+use super::fake::{key_event, FakeSession, FakeStreamDeck};
+use super::handler::{Connection, Handler};
+use super::middleware::Feedback;
+use super::{plumbing, ReceiveEvent};
+use crate::controller::SonosHandler;
+use crate::player::mock::MockPlayer;
+use crate::sonos::AVTransportState;
+
+const PLUGIN: &str = "plugin-uuid";
+const ACTION: &str = "sh.viora.controller-for-sonos.test";
+
+/// Answers events with something the fake app can see.
+struct Echo;
+
+impl Handler<String> for Echo {
+    async fn handle(
+        &self,
+        connection: &Connection,
+        event: &ReceiveEvent<String>,
+    ) -> Result<(), StreamDeckError> {
+        match event {
+            ReceiveEvent::KeyDown { context, .. } => connection.show_alert(context).await,
+            ReceiveEvent::KeyUp { context, .. } => connection.set_state(context, 1).await,
+            ReceiveEvent::WillAppear { context, .. } => {
+                connection.set_title(context, "hello").await
+            }
+            ReceiveEvent::DialRotate {
+                context, payload, ..
+            } => {
+                let image = format!("ticks:{}", payload.ticks);
+                connection.set_image(context, &image).await
+            }
+            ReceiveEvent::SendToPlugin {
+                context, payload, ..
+            } => {
+                let title = payload["title"].as_str().unwrap_or_default();
+                connection.set_title(context, title).await
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Runs the plugin against a fake app until `script` closes the session.
+async fn run_plugin<H, A, F>(handler: H, script: impl FnOnce(FakeSession) -> F)
+where
+    H: Handler<A>,
+    A: serde::de::DeserializeOwned + std::fmt::Debug,
+    F: std::future::Future<Output = ()>,
+{
+    let deck = FakeStreamDeck::start().await;
+    let plugin = plumbing::run(deck.launch_args(PLUGIN), handler);
+    let app = async { script(deck.accept(PLUGIN).await).await };
+    let (result, ()) = tokio::join!(plugin, app);
+    result.unwrap();
+}
+
+#[test]
+fn deserializes_events() {
+    let settings = json!({"room": "Kitchen"});
+    let events = [
+        key_event("keyDown", ACTION, "key", settings.clone()),
+        key_event("keyUp", ACTION, "key", settings.clone()),
+        key_event("willAppear", ACTION, "key", settings.clone()),
+        key_event("willDisappear", ACTION, "key", settings.clone()),
+        json!({
+            "event": "dialRotate", "action": ACTION, "context": "key", "device": "DEVICE",
+            "payload": {
+                "settings": settings, "coordinates": {"column": 0, "row": 0},
+                "controller": "Encoder", "ticks": -2, "pressed": false,
+            },
+        }),
+        json!({
+            "event": "didReceiveSettings", "action": ACTION, "context": "key", "device": "DEVICE",
+            "payload": {"settings": settings, "coordinates": {"column": 0, "row": 0}},
+        }),
+        json!({
+            "event": "sendToPlugin", "action": ACTION, "context": "key",
+            "payload": {"title": "hi"},
+        }),
+    ];
+    for event in events {
+        let parsed: ReceiveEvent<String> = serde_json::from_value(event.clone()).unwrap();
+        assert_eq!(parsed.context(), Some("key"), "{event}");
+    }
+
+    let plugin_wide = [
+        json!({"event": "didReceiveGlobalSettings", "payload": {"settings": {}}}),
+        json!({"event": "systemDidWakeUp"}),
+        json!({
+            "event": "deviceDidConnect", "device": "XL1",
+            "deviceInfo": {"name": "Desk", "type": 2, "size": {"columns": 8, "rows": 4}},
+        }),
+        json!({"event": "deviceDidDisconnect", "device": "XL1"}),
+        json!({"event": "didReceiveDeepLink", "payload": {"url": "/"}}),
+    ];
+    for event in plugin_wide {
+        let parsed: ReceiveEvent<String> = serde_json::from_value(event.clone()).unwrap();
+        assert_eq!(parsed.context(), None, "{event}");
+    }
+
+    let rotation = serde_json::from_value::<ReceiveEvent<String>>(json!({
+        "event": "dialRotate", "action": ACTION, "context": "key",
+        "payload": {
+            "settings": {}, "coordinates": {"column": 1, "row": 0}, "ticks": -2, "pressed": true,
+        },
+    }));
+    assert!(matches!(
+        rotation,
+        Ok(ReceiveEvent::DialRotate { payload, .. }) if payload.ticks == -2 && payload.pressed
+    ));
+    assert!(serde_json::from_value::<ReceiveEvent<String>>(json!({"event": "nope"})).is_err());
+}
+
+#[tokio::test]
+async fn registers_and_asks_for_global_settings() {
+    run_plugin(Echo, |mut app| async move {
+        let log = app.next().await;
+        assert_eq!(log["event"], "logMessage");
+        assert!(log["payload"]["message"].is_string());
+        let request = app.expect("getGlobalSettings").await;
+        assert_eq!(request["context"], PLUGIN);
+        app.close().await;
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn answers_scripted_events() {
+    run_plugin(Echo, |mut app| async move {
+        app.send(key_event("willAppear", ACTION, "a", json!({})))
+            .await;
+        let title = app.expect("setTitle").await;
+        assert_eq!(title["context"], "a");
+        assert_eq!(title["payload"]["title"], "hello");
+
+        app.send(key_event("keyDown", ACTION, "a", json!({}))).await;
+        assert_eq!(app.expect("showAlert").await["context"], "a");
+
+        app.send(key_event("keyUp", ACTION, "a", json!({}))).await;
+        assert_eq!(app.expect("setState").await["payload"]["state"], 1);
+
+        app.send(json!({
+            "event": "dialRotate", "action": ACTION, "context": "d",
+            "payload": {
+                "settings": {}, "coordinates": {"column": 0, "row": 0},
+                "ticks": 3, "pressed": false,
+            },
+        }))
+        .await;
+        let image = app.expect("setImage").await;
+        assert_eq!(image["context"], "d");
+        assert_eq!(image["payload"]["image"], "ticks:3");
+
+        app.send(json!({
+            "event": "sendToPlugin", "action": ACTION, "context": "a",
+            "payload": {"title": "from the inspector"},
+        }))
+        .await;
+        let title = app.expect("setTitle").await;
+        assert_eq!(title["payload"]["title"], "from the inspector");
+
+        // junk doesn't take the plugin down
+        app.send(json!({"event": "somethingNew"})).await;
+        app.send(json!(42)).await;
+        app.send(key_event("keyDown", ACTION, "b", json!({}))).await;
+        assert_eq!(app.expect("showAlert").await["context"], "b");
+
+        app.close().await;
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn controls_players_end_to_end() {
+    let players = vec![MockPlayer::new("Office"), MockPlayer::new("Kitchen")];
+    let handler = SonosHandler::with_players(players.clone()).with(Feedback);
+    let kitchen = players[1].clone();
+    let play_pause = "sh.viora.controller-for-sonos.play-pause";
+    let settings = json!({"room": "Kitchen"});
+
+    run_plugin(handler, |mut app| async move {
+        app.send(key_event("willAppear", play_pause, "key", settings.clone()))
+            .await;
+        assert_eq!(app.expect("setState").await["payload"]["state"], 0);
+
+        app.send(key_event("keyUp", play_pause, "key", settings.clone()))
+            .await;
+        let state = app.expect("setState").await;
+        assert_eq!(state["context"], "key");
+        assert_eq!(state["payload"]["state"], 1);
+        assert_eq!(kitchen.state().transport, AVTransportState::Playing);
+
+        // changes made elsewhere show up on the key
+        kitchen.change_transport(AVTransportState::Paused);
+        assert_eq!(app.expect("setState").await["payload"]["state"], 0);
+
+        kitchen.state().fail = Some(crate::sonos::ControllerError::Unsupported(
+            "Play".to_string(),
+        ));
+        app.send(key_event("keyUp", play_pause, "key", settings.clone()))
+            .await;
+        assert_eq!(app.expect("showAlert").await["context"], "key");
+
+        app.close().await;
+    })
+    .await;
+    assert_eq!(
+        players[0].state().calls,
+        Vec::<&str>::new(),
+        "only the kitchen is touched"
+    );
+}